@@ -27,8 +27,8 @@ fn main() -> Result<(), Error> {
         let tx = db.tx(true)?;
         let r_bucket = tx.get_bucket(path.as_str())?;
         let r_bucket = r_bucket.get_bucket("data")?;
-        let old = r_bucket.get_kv(old_name.to_string() + "-f");
-        let new = r_bucket.get_kv(new_name.to_string() + "-f");
+        let old = r_bucket.get_kv(old_name.to_string() + "-f")?;
+        let new = r_bucket.get_kv(new_name.to_string() + "-f")?;
 
         let _ans = if old.is_some() {
             if new.is_some() {
@@ -42,7 +42,7 @@ fn main() -> Result<(), Error> {
                 println!("create new");
 
                 let old_bucket = tx.get_bucket(old_path.clone())?;
-                let old_data_p = old_bucket.get_kv("data").unwrap();
+                let old_data_p = old_bucket.get_kv("data")?.unwrap();
                 let old_data = old_data_p.value().to_vec();
                 tx.delete_bucket(old_path)?;
                 let new_bucket = tx.create_bucket(new_path)?;
@@ -19,7 +19,7 @@ fn main() -> Result<(), Error> {
     let tx = db.tx(true)?;
     {
         let bucket = tx.get_bucket("root")?;
-        let value = bucket.get_kv("key").unwrap();
+        let value = bucket.get_kv("key")?.unwrap();
         let value = value.value();
         assert_eq!(value, "value".as_bytes());
         tx.delete_bucket("root")?;
@@ -0,0 +1,353 @@
+//! Coalesces writes from concurrent callers into a single transaction, see [`DB::batch`].
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::{db::DB, errors::Result, tx::Tx};
+
+pub(crate) type BatchFn = Box<dyn Fn(&Tx) -> Result<()> + Send>;
+
+// Default maximum number of calls in a batch, same as Bolt
+pub(crate) const DEFAULT_MAX_BATCH_SIZE: usize = 1000;
+// Default time to wait for other calls to join a batch, same as Bolt
+pub(crate) const DEFAULT_MAX_BATCH_DELAY: Duration = Duration::from_millis(10);
+
+// What happened to a call after its batch ran
+enum Outcome {
+    Committed,
+    // the call failed, or the batch couldn't be committed,
+    // so the function is handed back for the caller to run in a transaction of its own
+    Solo(BatchFn),
+}
+
+#[derive(Default)]
+struct Slot {
+    outcome: Mutex<Option<Outcome>>,
+    ready: Condvar,
+}
+
+impl Slot {
+    fn set(&self, outcome: Outcome) {
+        *lock(&self.outcome) = Some(outcome);
+        self.ready.notify_all();
+    }
+
+    fn wait(&self) -> Outcome {
+        let mut outcome = lock(&self.outcome);
+        loop {
+            if let Some(outcome) = outcome.take() {
+                return outcome;
+            }
+            outcome = self.ready.wait(outcome).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+struct Call {
+    f: BatchFn,
+    slot: Arc<Slot>,
+}
+
+// Calls that haven't been given an outcome yet.
+// If running the batch panics, the callers still waiting on them run them alone instead.
+struct Calls(Vec<Call>);
+
+impl Drop for Calls {
+    fn drop(&mut self) {
+        for call in self.0.drain(..) {
+            call.slot.set(Outcome::Solo(call.f));
+        }
+    }
+}
+
+pub(crate) struct Batcher {
+    // calls waiting for the leader of the current batch to run them
+    pending: Mutex<Option<Vec<Call>>>,
+    joined: Condvar,
+    max_size: usize,
+    max_delay: Duration,
+}
+
+impl Batcher {
+    pub(crate) fn new(max_size: usize, max_delay: Duration) -> Batcher {
+        Batcher {
+            pending: Mutex::new(None),
+            joined: Condvar::new(),
+            max_size,
+            max_delay,
+        }
+    }
+
+    // The first caller to find no pending batch leads the next one: it waits for others to join,
+    // then runs every call in one transaction while the other callers wait for their outcome.
+    pub(crate) fn batch(&self, db: &DB, f: BatchFn) -> Result<()> {
+        let slot = Arc::new(Slot::default());
+        let call = Call {
+            f,
+            slot: slot.clone(),
+        };
+        let mut pending = lock(&self.pending);
+        let calls = match pending.as_mut() {
+            Some(calls) => {
+                calls.push(call);
+                if calls.len() >= self.max_size {
+                    self.joined.notify_all();
+                }
+                drop(pending);
+                None
+            }
+            None => {
+                *pending = Some(vec![call]);
+                let deadline = Instant::now() + self.max_delay;
+                loop {
+                    let now = Instant::now();
+                    let full = pending.as_ref().map_or(0, Vec::len) >= self.max_size;
+                    if full || now >= deadline {
+                        break;
+                    }
+                    pending = self
+                        .joined
+                        .wait_timeout(pending, deadline - now)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0;
+                }
+                let calls = pending.take();
+                // let the next batch start forming while this one runs
+                drop(pending);
+                calls
+            }
+        };
+        if let Some(calls) = calls {
+            run(db, Calls(calls));
+        }
+        match slot.wait() {
+            Outcome::Committed => Ok(()),
+            Outcome::Solo(f) => db.update(|tx| f(tx)),
+        }
+    }
+}
+
+// Runs the calls in a single transaction. A call that fails is taken out of the batch and
+// the rest are run again in a new transaction, so only the calls that succeeded are committed.
+fn run(db: &DB, mut calls: Calls) {
+    loop {
+        let tx = match db.tx(true) {
+            Ok(tx) => tx,
+            // leave every call to its caller, who will get the error themselves
+            Err(_) => return,
+        };
+        let failed = calls.0.iter().position(|call| (call.f)(&tx).is_err());
+        match failed {
+            Some(index) => {
+                if tx.rollback().is_err() {
+                    return;
+                }
+                let call = calls.0.remove(index);
+                call.slot.set(Outcome::Solo(call.f));
+            }
+            None => {
+                if tx.commit().is_ok() {
+                    for call in calls.0.drain(..) {
+                        call.slot.set(Outcome::Committed);
+                    }
+                }
+                // on failure, dropping `calls` leaves each one to its caller
+                return;
+            }
+        }
+    }
+}
+
+// The locks are never held while running user code, so poisoning can be ignored
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memfile::{FakeMap, FileOpenOptions};
+    use crate::testutil::RandomFile;
+    use crate::{Error, OpenOptions};
+
+    fn call(f: impl Fn(&Tx) -> Result<()> + Send + 'static) -> (Call, Arc<Slot>) {
+        let slot = Arc::new(Slot::default());
+        let call = Call {
+            f: Box::new(f),
+            slot: slot.clone(),
+        };
+        (call, slot)
+    }
+
+    fn put(key: &'static str) -> impl Fn(&Tx) -> Result<()> + Send + 'static {
+        move |tx| {
+            tx.get_or_create_bucket("abc")?.put(key, "value")?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_run_retries_without_failed_calls() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file)?;
+        let (a, a_slot) = call(put("a"));
+        let (b, b_slot) = call(|tx| {
+            tx.get_or_create_bucket("abc")?.put("b", "value")?;
+            Err(Error::KeyValueMissing)
+        });
+        let (c, c_slot) = call(put("c"));
+        run(&db, Calls(vec![a, b, c]));
+
+        assert!(matches!(a_slot.wait(), Outcome::Committed));
+        assert!(matches!(c_slot.wait(), Outcome::Committed));
+        // the failed call is handed back to be run alone
+        match b_slot.wait() {
+            Outcome::Solo(f) => assert_eq!(db.update(|tx| f(tx)), Err(Error::KeyValueMissing)),
+            Outcome::Committed => panic!("failed call was committed"),
+        }
+        let tx = db.tx(false)?;
+        let bucket = tx.get_bucket("abc")?;
+        assert!(bucket.get_kv("a")?.is_some());
+        assert!(bucket.get_kv("b")?.is_none());
+        assert!(bucket.get_kv("c")?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_panics() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file)?;
+        let (a, a_slot) = call(put("a"));
+        let (b, b_slot) = call(|_| panic!("oops"));
+        let (c, c_slot) = call(put("c"));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            run(&db, Calls(vec![a, b, c]));
+        }));
+        assert!(result.is_err());
+        // every caller gets their call back, so none of them wait forever
+        for slot in [a_slot, b_slot, c_slot] {
+            assert!(matches!(slot.wait(), Outcome::Solo(_)));
+        }
+        let tx = db.tx(false)?;
+        assert_eq!(tx.get_bucket("abc").err(), Some(Error::BucketMissing));
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new()
+            .max_batch_size(1)
+            .open::<_, FileOpenOptions>(Arc::new(FakeMap), &random_file)?;
+        db.batch(put("a"))?;
+        assert_eq!(
+            db.batch(|tx| tx.get_bucket("missing").map(|_| ())),
+            Err(Error::BucketMissing)
+        );
+        let db = OpenOptions::new()
+            .max_batch_delay(Duration::from_millis(1))
+            .open::<_, FileOpenOptions>(Arc::new(FakeMap), &random_file)?;
+        db.batch(put("b"))?;
+        let tx = db.tx(false)?;
+        let bucket = tx.get_bucket("abc")?;
+        assert!(bucket.get_kv("a")?.is_some());
+        assert!(bucket.get_kv("b")?.is_some());
+
+        let result = OpenOptions::new()
+            .max_batch_size(0)
+            .open::<_, FileOpenOptions>(Arc::new(FakeMap), &random_file);
+        assert_eq!(
+            result.err(),
+            Some(Error::InvalidOption("Max batch size must be at least 1"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_batch() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new()
+            .max_batch_delay(Duration::from_millis(50))
+            .open::<_, FileOpenOptions>(Arc::new(FakeMap), &random_file)?;
+        db.update(|tx| tx.create_bucket("abc").map(|_| ()))?;
+        let tx_id = db.tx(false)?.inner.borrow().meta.tx_id();
+        let workers: Vec<_> = (0..8_u64)
+            .map(|i| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    db.batch(move |tx| {
+                        tx.get_bucket("abc")?.put(i.to_be_bytes(), "value")?;
+                        if i == 3 {
+                            return Err(Error::KeyValueMissing);
+                        }
+                        Ok(())
+                    })
+                })
+            })
+            .collect();
+        for (i, worker) in workers.into_iter().enumerate() {
+            let result = worker.join().unwrap();
+            if i == 3 {
+                assert_eq!(result, Err(Error::KeyValueMissing));
+            } else {
+                assert_eq!(result, Ok(()));
+            }
+        }
+        let tx = db.tx(false)?;
+        let bucket = tx.get_bucket("abc")?;
+        for i in 0..8_u64 {
+            assert_eq!(bucket.get_kv(i.to_be_bytes())?.is_some(), i != 3);
+        }
+        // the calls were coalesced into fewer transactions than there were calls
+        assert!(tx.inner.borrow().meta.tx_id() - tx_id < 8);
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_forms_while_running() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new()
+            .max_batch_delay(Duration::from_millis(1))
+            .open::<_, FileOpenOptions>(Arc::new(FakeMap), &random_file)?;
+        let (started_tx, started) = std::sync::mpsc::channel();
+        let (release, release_rx) = std::sync::mpsc::channel::<()>();
+        let release_rx = Mutex::new(release_rx);
+        let first = {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                db.batch(move |tx| {
+                    let _ = started_tx.send(());
+                    // hold the first batch open until the second one has started forming
+                    let _ = lock(&release_rx).recv();
+                    put("a")(tx)
+                })
+            })
+        };
+        started.recv().unwrap();
+        let second = {
+            let db = db.clone();
+            std::thread::spawn(move || db.batch(put("b")))
+        };
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            if let Ok(pending) = db.inner.batcher.pending.try_lock() {
+                if pending.is_some() {
+                    break;
+                }
+            }
+            assert!(Instant::now() < deadline, "the second batch never started");
+            std::thread::yield_now();
+        }
+        release.send(()).unwrap();
+        first.join().unwrap()?;
+        second.join().unwrap()?;
+        let tx = db.tx(false)?;
+        let bucket = tx.get_bucket("abc")?;
+        assert!(bucket.get_kv("a")?.is_some());
+        assert!(bucket.get_kv("b")?.is_some());
+        Ok(())
+    }
+}
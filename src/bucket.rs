@@ -12,6 +12,7 @@ use crate::{
     bytes::{Bytes, ToBytes},
     cursor::{search, Cursor, Range, ToBuckets, ToKVPairs},
     data::{Data, KVPair},
+    endian::U64,
     errors::{Error, Result},
     freelist::TxFreelist,
     node::{Leaf, Node, NodeData, NodeID},
@@ -128,23 +129,32 @@ impl<'b, 'tx> Bucket<'b, 'tx> {
         Ok(b.put(key, value)?.map(|v| v.into()))
     }
 
-    pub fn get<'a, T: AsRef<[u8]>>(&'a self, key: T) -> Option<Data<'b, 'tx>> {
+    /// Gets the data for the given key.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`Corruption`](enum.Error.html#variant.Corruption) error
+    /// if a page on the way to the key fails its checksum.
+    pub fn get<'a, T: AsRef<[u8]>>(&'a self, key: T) -> Result<Option<Data<'b, 'tx>>> {
         let mut b = self.inner.borrow_mut();
         if b.deleted {
             panic!("Cannot get data from a deleted bucket.");
         }
-        b.get(key).map(|data| data.into())
+        Ok(b.get(key)?.map(|data| data.into()))
     }
 
-    pub fn get_kv<'a, T: AsRef<[u8]>>(&'a self, key: T) -> Option<KVPair<'b, 'tx>> {
+    /// Gets the key / value pair for the given key, if the key is not a bucket.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`Corruption`](enum.Error.html#variant.Corruption) error
+    /// if a page on the way to the key fails its checksum.
+    pub fn get_kv<'a, T: AsRef<[u8]>>(&'a self, key: T) -> Result<Option<KVPair<'b, 'tx>>> {
         let mut b = self.inner.borrow_mut();
         if b.deleted {
             panic!("Cannot get data from a deleted bucket.");
         }
-        match b.get(key) {
-            Some(data) => data.into(),
-            None => None,
-        }
+        Ok(b.get(key)?.and_then(|data| data.into()))
     }
 
     /// Deletes a key / value pair from the bucket
@@ -163,11 +173,11 @@ impl<'b, 'tx> Bucket<'b, 'tx> {
     ///
     /// let bucket = tx.get_bucket("my-bucket")?;
     /// // check if data is there
-    /// assert!(bucket.get_kv("some-key").is_some());
+    /// assert!(bucket.get_kv("some-key")?.is_some());
     /// // delete the key / value pair
     /// bucket.delete("some-key")?;
     /// // data should no longer exist
-    /// assert!(bucket.get_kv("some-key").is_none());
+    /// assert!(bucket.get_kv("some-key")?.is_none());
     ///
     /// # Ok(())
     /// # }
@@ -442,7 +452,7 @@ impl<'b, 'tx> Bucket<'b, 'tx> {
         if b.deleted {
             panic!("Cannot get next int from a deleted bucket.");
         }
-        b.meta.next_int
+        b.meta.next_int()
     }
 
     /// Iterator over the sub-buckets in this bucket.
@@ -489,16 +499,30 @@ pub(crate) struct InnerBucket<'b> {
     pages: Pages,
 }
 
+// The state of a bucket when a savepoint was taken.
+// Buckets and nodes keep their Rc so they are restored in place,
+// and any Bucket handles to them still work after rolling back.
+pub(crate) struct BucketSnapshot<'b> {
+    meta: BucketMeta,
+    root: PageNodeID,
+    deleted: bool,
+    dirty: bool,
+    buckets: Vec<(Bytes<'b>, Rc<RefCell<InnerBucket<'b>>>, BucketSnapshot<'b>)>,
+    nodes: Vec<(Rc<RefCell<Node<'b>>>, Node<'b>)>,
+    page_node_ids: HashMap<PageID, NodeID>,
+    page_parents: HashMap<PageID, PageID>,
+}
+
 impl<'b> InnerBucket<'b> {
     pub(crate) fn from_meta(meta: BucketMeta, pages: Pages) -> InnerBucket<'b> {
         debug_assert!(
-            meta.root_page > 1,
+            meta.root_page() > 1,
             "bucket cannot have root page {}, reserved for meta",
-            meta.root_page
+            meta.root_page()
         );
         InnerBucket {
             meta,
-            root: PageNodeID::Page(meta.root_page),
+            root: PageNodeID::Page(meta.root_page()),
             deleted: false,
             dirty: false,
             buckets: HashMap::new(),
@@ -530,35 +554,112 @@ impl<'b> InnerBucket<'b> {
         b.borrow_mut()
     }
 
+    pub(crate) fn snapshot(&self) -> BucketSnapshot<'b> {
+        BucketSnapshot {
+            meta: self.meta,
+            root: self.root,
+            deleted: self.deleted,
+            dirty: self.dirty,
+            buckets: self
+                .buckets
+                .iter()
+                .map(|(name, b)| (name.clone(), b.clone(), b.borrow().snapshot()))
+                .collect(),
+            nodes: self
+                .nodes
+                .iter()
+                .map(|n| (n.clone(), n.borrow().clone()))
+                .collect(),
+            page_node_ids: self.page_node_ids.clone(),
+            page_parents: self.page_parents.clone(),
+        }
+    }
+
+    pub(crate) fn restore(&mut self, snapshot: BucketSnapshot<'b>) {
+        let opened: Vec<_> = self.buckets.drain().collect();
+        self.meta = snapshot.meta;
+        self.root = snapshot.root;
+        self.deleted = snapshot.deleted;
+        self.dirty = snapshot.dirty;
+        for (name, b, saved) in snapshot.buckets {
+            b.borrow_mut().restore(saved);
+            self.buckets.insert(name, b);
+        }
+        self.nodes = snapshot
+            .nodes
+            .into_iter()
+            .map(|(n, saved)| {
+                *n.borrow_mut() = saved;
+                n
+            })
+            .collect();
+        self.page_node_ids = snapshot.page_node_ids;
+        self.page_parents = snapshot.page_parents;
+        // buckets opened or created since the snapshot weren't in it
+        for (name, b) in opened {
+            if !self.buckets.contains_key(&name) {
+                self.reopen(name, b);
+            }
+        }
+    }
+
+    // Resets a bucket that was opened since a snapshot to the state this bucket has for it,
+    // so Bucket handles to it keep working. Buckets that were created since then don't exist
+    // anymore, so they are marked as deleted instead.
+    fn reopen(&mut self, name: Bytes<'b>, b: Rc<RefCell<InnerBucket<'b>>>) {
+        let meta = match self.get(&name) {
+            Ok(Some(Leaf::Bucket(_, meta))) if meta.root_page() != 0 => meta,
+            _ => {
+                b.borrow_mut().mark_deleted();
+                return;
+            }
+        };
+        let mut inner = b.borrow_mut();
+        let children: Vec<_> = inner.buckets.drain().collect();
+        *inner = InnerBucket::from_meta(meta, self.pages.clone());
+        for (name, child) in children {
+            inner.reopen(name, child);
+        }
+        drop(inner);
+        self.buckets.insert(name, b);
+    }
+
+    fn mark_deleted(&mut self) {
+        self.deleted = true;
+        for b in self.buckets.values() {
+            b.borrow_mut().mark_deleted();
+        }
+    }
+
     pub(crate) fn add_page_parent(&mut self, page: PageID, parent: PageID) {
         debug_assert!(
-            self.meta.root_page == parent || self.page_parents.contains_key(&parent),
+            self.meta.root_page() == parent || self.page_parents.contains_key(&parent),
             "cannot find reference to parent page ID \"{parent}\""
         );
         self.page_parents.insert(page, parent);
     }
 
-    pub(crate) fn page_node<'a>(&'a self, id: PageNodeID) -> PageNode<'b> {
-        match id {
+    pub(crate) fn page_node<'a>(&'a self, id: PageNodeID) -> Result<PageNode<'b>> {
+        Ok(match id {
             PageNodeID::Page(page) => {
                 if let Some(node_id) = self.page_node_ids.get(&page) {
                     PageNode::Node(self.nodes[*node_id as usize].clone())
                 } else {
-                    PageNode::Page(self.pages.page(page))
+                    PageNode::Page(self.pages.page(page)?)
                 }
             }
             PageNodeID::Node(node) => PageNode::Node(self.nodes[node as usize].clone()),
-        }
+        })
     }
 
-    pub fn get<'a, T: AsRef<[u8]>>(&'a mut self, key: T) -> Option<Leaf<'b>> {
-        let (exists, stack) = search(key.as_ref(), self.meta.root_page, self);
+    pub fn get<'a, T: AsRef<[u8]>>(&'a mut self, key: T) -> Result<Option<Leaf<'b>>> {
+        let (exists, stack) = search(key.as_ref(), self.meta.root_page(), self)?;
         let last = stack.last().unwrap();
         if exists {
-            let page_node = self.page_node(last.id);
-            page_node.val(last.index)
+            let page_node = self.page_node(last.id)?;
+            Ok(page_node.val(last.index))
         } else {
-            None
+            Ok(None)
         }
     }
 
@@ -580,16 +681,16 @@ impl<'b> InnerBucket<'b> {
     }
 
     fn delete<'a, T: AsRef<[u8]>>(&'a mut self, key: T) -> Result<(Bytes<'b>, Bytes<'b>)> {
-        let (exists, stack) = search(key.as_ref(), self.meta.root_page, self);
+        let (exists, stack) = search(key.as_ref(), self.meta.root_page(), self)?;
         let last = stack.last().unwrap();
         if exists {
-            let page_node = self.page_node(last.id);
+            let page_node = self.page_node(last.id)?;
             let data = page_node.val(last.index).unwrap();
             if data.is_kv() {
                 let current_id = last.id;
                 let index = last.index;
                 self.dirty = true;
-                let node = self.node(current_id, None);
+                let node = self.node(current_id, None)?;
                 let mut node = node.borrow_mut();
                 match node.delete(index) {
                     Leaf::Kv(k, v) => Ok((k, v)),
@@ -604,20 +705,20 @@ impl<'b> InnerBucket<'b> {
     }
 
     fn put_leaf<'a>(&'a mut self, leaf: Leaf<'b>) -> Result<Option<Leaf<'b>>> {
-        let (exists, stack) = search(leaf.key(), self.meta.root_page, self);
+        let (exists, stack) = search(leaf.key(), self.meta.root_page(), self)?;
         let last = stack.last().unwrap();
         let current_data = if exists {
-            let page_node = self.page_node(last.id);
+            let page_node = self.page_node(last.id)?;
             let current = page_node.val(last.index).unwrap();
             if current.is_kv() != leaf.is_kv() {
                 return Err(Error::IncompatibleValue);
             }
             Some(current)
         } else {
-            self.meta.next_int += 1;
+            self.meta.set_next_int(self.meta.next_int() + 1);
             None
         };
-        let node = self.node(last.id, None);
+        let node = self.node(last.id, None)?;
         let mut node = node.borrow_mut();
         node.insert_data(leaf);
         self.dirty = true;
@@ -650,24 +751,24 @@ impl<'b> InnerBucket<'b> {
         must_create: bool,
     ) -> Result<Rc<RefCell<InnerBucket<'b>>>> {
         if !self.buckets.contains_key(&name) {
-            let (exists, stack) = search(name.as_ref(), self.meta.root_page, self);
+            let (exists, stack) = search(name.as_ref(), self.meta.root_page(), self)?;
             let last = stack.last().unwrap();
             if !exists {
                 if should_create {
-                    self.meta.next_int += 1;
+                    self.meta.set_next_int(self.meta.next_int() + 1);
                     let leaf = {
                         let b = self.new_child(name.clone());
                         let meta = b.meta;
                         Leaf::Bucket(name.clone(), meta)
                     };
-                    let node = self.node(last.id, None);
+                    let node = self.node(last.id, None)?;
                     let mut node = node.borrow_mut();
                     node.insert_data(leaf);
                 } else {
                     return Err(Error::BucketMissing);
                 }
             } else {
-                let page_node = self.page_node(last.id);
+                let page_node = self.page_node(last.id)?;
                 match page_node.val(last.index) {
                     Some(leaf) => match leaf {
                         Leaf::Bucket(name, meta) => {
@@ -704,27 +805,27 @@ impl<'b> InnerBucket<'b> {
         b.deleted = true;
         // check that the bucket wasn't just created and never comitted
         let mut remaining_pages = Vec::new();
-        if b.meta.root_page != 0 {
+        if b.meta.root_page() != 0 {
             // create a stack of pages to free and keep going until
             // we've freed every reachable page starting from this bucket's root page
-            remaining_pages.push(b.meta.root_page);
+            remaining_pages.push(b.meta.root_page());
             while !remaining_pages.is_empty() {
                 let page_id = remaining_pages.pop().unwrap();
-                let page = self.pages.page(page_id);
-                let num_pages = page.overflow + 1;
+                let page = self.pages.page(page_id)?;
+                let num_pages = page.overflow() + 1;
                 match page.page_type {
                     // every branch element's page much be freed
                     Page::TYPE_BRANCH => {
                         page.branch_elements()
                             .iter()
-                            .for_each(|b| remaining_pages.push(b.page));
+                            .for_each(|b| remaining_pages.push(b.page()));
                     }
                     Page::TYPE_LEAF => {
                         // every nested bucket's pages must be freed
                         page.leaf_elements().iter().for_each(|leaf| {
                             if leaf.node_type == Node::TYPE_BUCKET {
                                 let meta: BucketMeta = leaf.value().into();
-                                remaining_pages.push(meta.root_page);
+                                remaining_pages.push(meta.root_page());
                             }
                         });
                     }
@@ -734,17 +835,17 @@ impl<'b> InnerBucket<'b> {
             }
         }
         // delete the element from this bucket
-        let (exists, stack) = search(name.as_ref(), self.meta.root_page, self);
+        let (exists, stack) = search(name.as_ref(), self.meta.root_page(), self)?;
         let last = stack.last().unwrap();
         if exists {
-            let page_node = self.page_node(last.id);
+            let page_node = self.page_node(last.id)?;
             let data = page_node.val(last.index).unwrap();
 
             if !data.is_kv() {
                 self.dirty = true;
                 let current_id = last.id;
                 let index = last.index;
-                let node = self.node(current_id, None);
+                let node = self.node(current_id, None)?;
                 let mut node = node.borrow_mut();
                 node.delete(index);
                 Ok(())
@@ -760,23 +861,23 @@ impl<'b> InnerBucket<'b> {
         &'a mut self,
         id: PageNodeID,
         parent: Option<&mut Node>,
-    ) -> Rc<RefCell<Node<'b>>> {
+    ) -> Result<Rc<RefCell<Node<'b>>>> {
         let id: NodeID = match id {
             PageNodeID::Page(page_id) => {
                 if let Some(node_id) = self.page_node_ids.get(&page_id) {
-                    return self.nodes[*node_id as usize].clone();
+                    return Ok(self.nodes[*node_id as usize].clone());
                 }
                 debug_assert!(
-                    self.meta.root_page == page_id || self.page_parents.contains_key(&page_id),
+                    self.meta.root_page() == page_id || self.page_parents.contains_key(&page_id),
                     "cannot find reference to page ID \"{page_id}\""
                 );
+                let page = self.pages.page(page_id)?;
                 let node_id = self.nodes.len() as u64;
                 self.page_node_ids.insert(page_id, node_id);
-                let n: Node =
-                    Node::from_page(node_id, self.pages.page(page_id), self.pages.pagesize);
+                let n: Node = Node::from_page(node_id, page, self.pages.pagesize);
                 self.nodes.push(Rc::new(RefCell::new(n)));
                 // If this node is not for the root page, then recursively create nodes for the parent pages
-                if self.meta.root_page != page_id {
+                if self.meta.root_page() != page_id {
                     let n = self.nodes[node_id as usize].clone();
                     let mut n = n.borrow_mut();
                     let node_key = n.data.first_key();
@@ -784,7 +885,8 @@ impl<'b> InnerBucket<'b> {
                         parent.insert_child(node_id, node_key);
                         n.parent = Some(parent.id);
                     } else {
-                        let parent = self.node(PageNodeID::Page(self.page_parents[&page_id]), None);
+                        let parent =
+                            self.node(PageNodeID::Page(self.page_parents[&page_id]), None)?;
                         let mut parent = parent.borrow_mut();
                         parent.insert_child(node_id, node_key);
                         n.parent = Some(parent.id);
@@ -794,7 +896,7 @@ impl<'b> InnerBucket<'b> {
             }
             PageNodeID::Node(id) => id,
         };
-        self.nodes.get_mut(id as usize).unwrap().clone()
+        Ok(self.nodes.get_mut(id as usize).unwrap().clone())
     }
 
     pub(crate) fn new_node<'a>(&'a mut self, data: NodeData<'b>) -> Rc<RefCell<Node<'b>>> {
@@ -805,7 +907,7 @@ impl<'b> InnerBucket<'b> {
         self.nodes[node_id as usize].clone()
     }
 
-    fn is_dirty(&mut self) -> bool {
+    pub(crate) fn is_dirty(&mut self) -> bool {
         // If it isn't marked as dirty, make sure by checking
         // the sub-buckets to see if they're dirty.
         if !self.dirty {
@@ -831,18 +933,18 @@ impl<'b> InnerBucket<'b> {
         }
 
         // merge emptyish nodes with siblings
-        self.merge_nodes(tx_freelist);
+        self.merge_nodes(tx_freelist)?;
 
         Ok(())
     }
 
-    fn merge_nodes(&mut self, tx_freelist: &mut TxFreelist) {
+    fn merge_nodes(&mut self, tx_freelist: &mut TxFreelist) -> Result<()> {
         // If we haven't initialized any nodes yet, make sure we have the root node.
         // If there is even one node, we are guarunteed to hage loaded the root node too.
         if self.page_node_ids.is_empty() {
-            self.node(PageNodeID::Page(self.meta.root_page), None);
+            self.node(PageNodeID::Page(self.meta.root_page()), None)?;
         }
-        let mut stack: Vec<(bool, u64)> = vec![(false, self.page_node_ids[&self.meta.root_page])];
+        let mut stack: Vec<(bool, u64)> = vec![(false, self.page_node_ids[&self.meta.root_page()])];
 
         while let Some((visited, node_id)) = stack.pop() {
             let node = self.nodes[node_id as usize].clone();
@@ -854,7 +956,7 @@ impl<'b> InnerBucket<'b> {
                     continue;
                 }
                 // Handle root node speially
-                if node.page_id == self.meta.root_page {
+                if node.page_id == self.meta.root_page() {
                     // If the root node has only one branch, promote that page to the root page
                     if !node.leaf() && node.data.len() == 1 {
                         // delete the root node
@@ -872,7 +974,7 @@ impl<'b> InnerBucket<'b> {
                             "cannot have page <= 1, those are reserved for metadata"
                         );
                         // Make that child page the bucket's root page.
-                        self.meta.root_page = page_id;
+                        self.meta.set_root_page(page_id);
                         self.root = PageNodeID::Page(page_id);
                     }
                 } else {
@@ -911,7 +1013,7 @@ impl<'b> InnerBucket<'b> {
 
                             self.page_parents.insert(sibling_page, parent.page_id);
                             let sibling =
-                                self.node(PageNodeID::Page(sibling_page), Some(&mut parent));
+                                self.node(PageNodeID::Page(sibling_page), Some(&mut parent))?;
 
                             let mut sibling = sibling.borrow_mut();
                             // Copy this node's data over to it's sibling
@@ -948,6 +1050,7 @@ impl<'b> InnerBucket<'b> {
                 }
             }
         }
+        Ok(())
     }
 
     // Make sure none of the nodes are too full, creating other nodes as needed.
@@ -970,12 +1073,12 @@ impl<'b> InnerBucket<'b> {
             self.put_leaf(Leaf::Bucket(name, meta))?;
         }
 
-        let root = self.nodes[self.page_node_ids[&self.meta.root_page] as usize].clone();
+        let root = self.nodes[self.page_node_ids[&self.meta.root_page()] as usize].clone();
         let mut root = root.borrow_mut();
         let page_id = root
             .spill(self, tx_freelist, None)?
             .expect("root node did not return a new page_id");
-        self.meta.root_page = page_id;
+        self.meta.set_root_page(page_id);
 
         Ok(self.meta)
     }
@@ -986,8 +1089,37 @@ pub const META_SIZE: usize = core::mem::size_of::<BucketMeta>();
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub(crate) struct BucketMeta {
-    pub(crate) root_page: PageID,
-    pub(crate) next_int: u64,
+    root_page: U64,
+    next_int: U64,
+}
+
+impl BucketMeta {
+    pub(crate) fn new(root_page: PageID, next_int: u64) -> BucketMeta {
+        BucketMeta {
+            root_page: root_page.into(),
+            next_int: next_int.into(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn root_page(&self) -> PageID {
+        self.root_page.get()
+    }
+
+    #[inline]
+    pub(crate) fn set_root_page(&mut self, root_page: PageID) {
+        self.root_page.set(root_page);
+    }
+
+    #[inline]
+    pub(crate) fn next_int(&self) -> u64 {
+        self.next_int.get()
+    }
+
+    #[inline]
+    pub(crate) fn set_next_int(&mut self, next_int: u64) {
+        self.next_int.set(next_int);
+    }
 }
 
 impl AsRef<[u8]> for BucketMeta {
@@ -1001,7 +1133,8 @@ impl AsRef<[u8]> for BucketMeta {
 impl From<&[u8]> for BucketMeta {
     fn from(value: &[u8]) -> Self {
         let ptr = &value[0] as *const u8;
-        unsafe { *(ptr as *const BucketMeta) }
+        // values aren't aligned within their page
+        unsafe { core::ptr::read_unaligned(ptr as *const BucketMeta) }
     }
 }
 
@@ -1014,10 +1147,7 @@ mod tests {
 
     #[test]
     fn bytes() {
-        let meta = BucketMeta {
-            root_page: 3,
-            next_int: 1,
-        };
+        let meta = BucketMeta::new(3, 1);
         let bytes = meta.as_ref();
         assert_eq!(bytes, &[3, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
     }
@@ -1044,13 +1174,13 @@ mod tests {
             let _ = b.put("a", "b");
         })
         deleted_bucket_get: ("Cannot get data from a deleted bucket.", |b: &Bucket| {
-            b.get("a");
+            let _ = b.get("a");
         })
         deleted_bucket_delete: ("Cannot delete data from a deleted bucket.", |b: &Bucket| {
             let _ = b.delete("a");
         })
         deleted_bucket_get_kv: ("Cannot get data from a deleted bucket.", |b: &Bucket| {
-            b.get_kv("a");
+            let _ = b.get_kv("a");
         })
         deleted_bucket_get_bucket: ("Cannot get bucket from a deleted bucket.", |b: &Bucket| {
             let _ = b.get_bucket("a");
@@ -1158,7 +1288,7 @@ mod tests {
                 Ok(_) => panic!("Expected a IncompatibleValue error"),
                 Err(e) => assert!(e == Error::IncompatibleValue)
             }
-            assert!(b.get_kv("abc").is_none())
+            assert!(b.get_kv("abc").unwrap().is_none())
         })
     }
 
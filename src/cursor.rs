@@ -1,6 +1,7 @@
 use crate::{
     bucket::{Bucket, InnerBucket},
     data::Data,
+    errors::{Error, Result},
     freelist::TxFreelist,
     page::PageID,
     page_node::PageNodeID,
@@ -25,6 +26,10 @@ use core::{
 /// Note that if the key you seek to exists, the cursor will begin to iterate after
 /// the
 ///
+/// If a page can't be read, for example because it fails its checksum, the iterator stops early
+/// and the error is kept in [`error`](#method.error). Use [`try_next`](#method.try_next)
+/// to get errors as they happen instead.
+///
 /// # Examples
 ///
 /// ```no_run
@@ -49,9 +54,12 @@ use core::{
 /// let mut cursor = bucket.cursor();
 /// // seek to the key "f"
 /// // if it doesn't exist, it will start at the position where it should have been
-/// cursor.seek("f");
+/// cursor.seek("f")?;
 /// //
-/// for data in cursor {
+/// for data in cursor.by_ref() {
+/// }
+/// if let Some(e) = cursor.error() {
+///     println!("stopped early: {e}");
 /// }
 ///
 /// # Ok(())
@@ -63,6 +71,8 @@ pub struct Cursor<'b, 'tx> {
     writable: bool,
     stack: Vec<SearchPath>,
     next_called: bool,
+    // the error that stopped the iterator, if any
+    error: Option<Error>,
     _phantom: PhantomData<&'b ()>,
 }
 
@@ -74,6 +84,7 @@ impl<'b, 'tx> Cursor<'b, 'tx> {
             writable: b.writable,
             stack: Vec::new(),
             next_called: false,
+            error: None,
             _phantom: PhantomData,
         }
     }
@@ -83,44 +94,87 @@ impl<'b, 'tx> Cursor<'b, 'tx> {
     /// where the key _would_ be.
     ///
     /// Returns whether or not the key exists in the bucket.
-    pub fn seek<T: AsRef<[u8]>>(&mut self, key: T) -> bool {
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`Corruption`](enum.Error.html#variant.Corruption) error
+    /// if a page on the way to the key fails its checksum.
+    pub fn seek<T: AsRef<[u8]>>(&mut self, key: T) -> Result<bool> {
         self.next_called = false;
+        self.stack.clear();
         let mut b = self.bucket.borrow_mut();
         if b.deleted {
             panic!("Cannot seek cursor on a deleted bucket.");
         }
-        let (exists, stack) = search(key.as_ref(), b.meta.root_page, &mut b);
+        let (exists, stack) = search(key.as_ref(), b.meta.root_page(), &mut b)?;
         self.stack = stack;
-        exists
+        Ok(exists)
     }
 
     /// Returns the data at the cursor's current position.
     /// You can use this to get data after doing a [`seek`](#method.seek).
-    pub fn current<'a>(&'a self) -> Option<Data<'b, 'tx>> {
+    pub fn current<'a>(&'a self) -> Result<Option<Data<'b, 'tx>>> {
         let b = self.bucket.borrow_mut();
         if b.deleted {
             panic!("Cannot get data from a deleted bucket.");
         }
         match self.stack.last() {
             Some(e) => {
-                let n = b.page_node(e.id);
-                n.val(e.index).map(|data| data.into())
+                let n = b.page_node(e.id)?;
+                Ok(n.val(e.index).map(|data| data.into()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Moves the cursor to the next element and returns it,
+    /// like [`next`](#method.next) but returning any error instead of stopping.
+    pub fn try_next(&mut self) -> Result<Option<Data<'b, 'tx>>> {
+        if self.stack.is_empty() {
+            self.seek_first()?;
+        } else if self.next_called {
+            loop {
+                {
+                    let b = self.bucket.borrow();
+                    if b.deleted {
+                        panic!("Cannot get data from a deleted bucket.");
+                    }
+                    let elem = self.stack.last_mut().unwrap();
+                    let page_node = b.page_node(elem.id)?;
+                    if elem.index >= (page_node.len() - 1) {
+                        if self.stack.len() == 1 {
+                            return Ok(None);
+                        }
+                        self.stack.pop();
+                        continue;
+                    } else {
+                        elem.index += 1;
+                    }
+                }
+                self.seek_first()?;
+                break;
             }
-            None => None,
         }
+        self.next_called = true;
+        self.current()
+    }
+
+    /// Returns the error that stopped the iterator early, if there was one.
+    pub fn error(&self) -> Option<&Error> {
+        self.error.as_ref()
     }
 
-    fn seek_first(&mut self) {
+    fn seek_first(&mut self) -> Result<()> {
         let b = self.bucket.borrow();
         if self.stack.is_empty() {
             self.stack.push(SearchPath {
                 index: 0,
-                id: PageNodeID::Page(b.meta.root_page),
+                id: PageNodeID::Page(b.meta.root_page()),
             });
         }
         loop {
             let elem = self.stack.last().unwrap();
-            let page_node = b.page_node(elem.id);
+            let page_node = b.page_node(elem.id)?;
             if page_node.leaf() {
                 break;
             }
@@ -134,6 +188,18 @@ impl<'b, 'tx> Cursor<'b, 'tx> {
                 id: PageNodeID::Page(page_id),
             });
         }
+        Ok(())
+    }
+
+    // stops the iterator at the first error, keeping it for `error`
+    fn stop_on_error(&mut self, result: Result<Option<Data<'b, 'tx>>>) -> Option<Data<'b, 'tx>> {
+        match result {
+            Ok(data) => data,
+            Err(e) => {
+                self.error = Some(e);
+                None
+            }
+        }
     }
 }
 
@@ -142,20 +208,20 @@ pub(crate) fn search(
     key: &[u8],
     mut page_id: PageID,
     b: &mut InnerBucket,
-) -> (bool, Vec<SearchPath>) {
+) -> Result<(bool, Vec<SearchPath>)> {
     let mut stack = Vec::new();
     loop {
-        let page_node = b.page_node(PageNodeID::Page(page_id));
+        let page_node = b.page_node(PageNodeID::Page(page_id))?;
         let id = page_node.id();
         let (index, exact) = page_node.index(key);
         let leaf = page_node.leaf();
         stack.push(SearchPath { index, id });
         if leaf {
-            return (exact, stack);
+            return Ok((exact, stack));
         }
         let next_page_id = page_node.index_page(index);
         if next_page_id == 0 {
-            return (false, stack);
+            return Ok((false, stack));
         }
         b.add_page_parent(next_page_id, page_id);
         page_id = next_page_id;
@@ -172,33 +238,11 @@ impl<'b, 'tx> Iterator for Cursor<'b, 'tx> {
     type Item = Data<'b, 'tx>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.stack.is_empty() {
-            self.seek_first();
-        } else if self.next_called {
-            loop {
-                {
-                    let b = self.bucket.borrow();
-                    if b.deleted {
-                        panic!("Cannot get data from a deleted bucket.");
-                    }
-                    let elem = self.stack.last_mut().unwrap();
-                    let page_node = b.page_node(elem.id);
-                    if elem.index >= (page_node.len() - 1) {
-                        if self.stack.len() == 1 {
-                            return None;
-                        }
-                        self.stack.pop();
-                        continue;
-                    } else {
-                        elem.index += 1;
-                    }
-                }
-                self.seek_first();
-                break;
-            }
+        if self.error.is_some() {
+            return None;
         }
-        self.next_called = true;
-        self.current()
+        let next = self.try_next();
+        self.stop_on_error(next)
     }
 }
 /// A bounded iterator over the data in a bucket.
@@ -218,22 +262,37 @@ where
     type Item = Data<'b, 'tx>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.c.error.is_some() {
+            return None;
+        }
+        let next = self.try_next();
+        self.c.stop_on_error(next)
+    }
+}
+
+impl<'r, 'b, 'tx, R> Range<'r, 'b, 'tx, R>
+where
+    R: RangeBounds<&'r [u8]>,
+{
+    /// Returns the next element in the range,
+    /// like [`next`](#method.next) but returning any error instead of stopping.
+    pub fn try_next(&mut self) -> Result<Option<Data<'b, 'tx>>> {
         if !self.c.next_called {
             if let Bound::Included(s) = self.bounds.start_bound() {
-                let exists = self.c.seek(*s);
+                let exists = self.c.seek(*s)?;
                 // if the start key is not there,
                 // skip to the key after where it should be.
                 if !exists {
-                    if let Some(data) = self.c.current() {
+                    if let Some(data) = self.c.current()? {
                         if data.key() < *s {
-                            self.c.next();
+                            self.c.try_next()?;
                         }
                     }
                 }
             }
         }
-        let next = self.c.next();
-        match next {
+        let next = self.c.try_next()?;
+        Ok(match next {
             Some(data) => match self.bounds.end_bound() {
                 Bound::Excluded(e) => {
                     if data.key() < *e {
@@ -252,7 +311,12 @@ where
                 Bound::Unbounded => Some(data),
             },
             None => None,
-        }
+        })
+    }
+
+    /// Returns the error that stopped the iterator early, if there was one.
+    pub fn error(&self) -> Option<&Error> {
+        self.c.error()
     }
 }
 /// An iterator over a bucket's sub-buckets.
@@ -370,10 +434,11 @@ where
 
 #[cfg(test)]
 mod tests {
-    use crate::db::DB;
-    use crate::errors::Result;
+    use crate::db::{OpenOptions, DB};
+    use crate::errors::{Error, Result};
     use crate::memfile::{FakeMap, FileOpenOptions};
     use crate::testutil::RandomFile;
+    use core2::io::SeekFrom;
     use std::sync::Arc;
 
     #[test]
@@ -436,6 +501,50 @@ mod tests {
         db.check()
     }
 
+    #[test]
+    fn test_corrupt_page() -> Result<()> {
+        let random_file = RandomFile::new();
+        let page_id = {
+            let db = OpenOptions::new()
+                .pagesize(1024)
+                .open::<_, FileOpenOptions>(Arc::new(FakeMap), &random_file)?;
+            db.update(|tx| {
+                let b = tx.create_bucket("abc")?;
+                for i in 0..100_u64 {
+                    b.put(i.to_be_bytes(), i.to_string())?;
+                }
+                Ok(())
+            })?;
+            let tx = db.tx(false)?;
+            let page_id = tx.get_bucket("abc")?.inner.borrow().meta.root_page();
+            // flip a bit in the stored checksum of the bucket's root page
+            let mut file = db.file();
+            file.seek(SeekFrom::Start(page_id * 1024 + 12))?;
+            file.write_all(&[0xFF])?;
+            file.flush()?;
+            page_id
+        };
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file)?;
+        let tx = db.tx(false)?;
+        let b = tx.get_bucket("abc")?;
+        let corruption = Some(Error::Corruption { page_id });
+        assert_eq!(b.get(1_u64.to_be_bytes()).err(), corruption);
+        assert_eq!(b.get_kv(1_u64.to_be_bytes()).err(), corruption);
+        assert_eq!(b.cursor().seek(1_u64.to_be_bytes()).err(), corruption);
+        assert_eq!(b.cursor().try_next().err(), corruption);
+
+        // iterators stop at the error and keep it
+        let mut cursor = b.cursor();
+        assert_eq!(cursor.by_ref().count(), 0);
+        assert_eq!(cursor.error(), corruption.as_ref());
+        assert!(cursor.next().is_none());
+        let start = 1_u64.to_be_bytes();
+        let mut range = b.range(start.as_slice()..);
+        assert!(range.next().is_none());
+        assert_eq!(range.error(), corruption.as_ref());
+        Ok(())
+    }
+
     #[test]
     #[should_panic]
     fn deleted_bucket_create_cursor() {
@@ -1,26 +1,93 @@
-use alloc::string::ToString;
 use alloc::sync::Arc;
 use alloc::vec;
-use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "std")]
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::thread::ThreadId;
 
-use spin::{Mutex, MutexGuard, RwLock};
+use core2::io::{ErrorKind, SeekFrom};
+use spin::{Mutex, MutexGuard};
 
-use crate::fs::{File, MemoryMap, OpenOption, PathLike};
-use crate::{bucket::BucketMeta, errors::Result, page::Page, tx::Tx, IndexByPageID};
-use crate::{freelist::Freelist, meta::Meta};
+#[cfg(feature = "std")]
+use crate::batch::{Batcher, DEFAULT_MAX_BATCH_DELAY, DEFAULT_MAX_BATCH_SIZE};
+use crate::fs::{File, IOResult, MemoryMap, OpenOption, PathLike};
+use crate::{
+    bucket::BucketMeta,
+    errors::{Error, Result},
+    page::{Page, Pages},
+    readers::{LatestMeta, ReaderTable},
+    snapshot::Snapshot,
+    tx::{Tx, Wait},
+    IndexByPageID,
+};
+use crate::{
+    freelist::Freelist,
+    meta::{HashAlgorithm, Meta},
+};
 
 const MAGIC_VALUE: u32 = 0x00AB_CDEF;
-const VERSION: u32 = 1;
+
+/// Version of the file format written by this version of the library.
+///
+/// A database keeps the format version it was created with, so opening it never changes
+/// its format behind your back. Any file with a version between [`MIN_SUPPORTED_VERSION`]
+/// and `VERSION` can be opened and written to. Files written by a newer version of the library,
+/// or older than [`MIN_SUPPORTED_VERSION`], are rejected with an
+/// [`Error::UnsupportedVersion`](crate::Error::UnsupportedVersion).
+pub const VERSION: u32 = 2;
+
+/// Oldest file format version this version of the library can open.
+pub const MIN_SUPPORTED_VERSION: u32 = 1;
 
 const fn get_page_size() -> usize {
     4096
 }
+// Smallest supported pagesize, big enough to hold a meta page
+const MIN_PAGESIZE: u64 = 1024;
+
 // Minimum number of bytes to allocate when growing the databse
 pub(crate) const MIN_ALLOC_SIZE: u64 = 8 * 1024 * 1024;
 
 // Number of pages to allocate when creating the database
 const DEFAULT_NUM_PAGES: usize = 32;
 
+// Called with the ID of a writable transaction dropped with uncommitted changes
+pub(crate) type UncommittedTxFn = dyn Fn(u64) + Send + Sync;
+
+/// How hard a commit works to make sure its changes survive a crash.
+///
+/// Whatever the durability, the database only ever writes a meta page after the pages it points to,
+/// so a process crash never loses a committed transaction. The weaker levels skip some of the calls
+/// to `sync_all`, making commits much faster, at the cost of what survives an operating system crash
+/// or power loss. Use [`DB::sync`] to make every committed transaction durable at a point of your choosing.
+/// [`DB::close`] syncs as well, but dropping a `DB` does not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Syncs the new pages before writing the meta page that points to them, and then syncs the meta page,
+    /// so a transaction is durable as soon as `commit` returns.
+    #[default]
+    Full,
+    /// Syncs the new pages before writing the meta page, but leaves the meta page to be synced
+    /// by the next commit. A crash may lose the last committed transaction,
+    /// but the database always reopens in a consistent state.
+    NoMetaSync,
+    /// Never syncs on commit, leaving it to the operating system to write the changes out.
+    /// An operating system crash or power loss may lose any transaction committed since
+    /// the last [`DB::sync`], and may leave the database corrupted.
+    NoSync,
+}
+
+/// How long [`DB::tx_timeout`] waits for the locks a transaction needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxTimeout {
+    /// Gives up after trying this many more times, spinning in between
+    Spins(u64),
+    /// Gives up once this much time has passed, yielding to other threads in between
+    #[cfg(feature = "std")]
+    Duration(Duration),
+}
+
 /// Options to configure how a [`DB`] is opened.
 ///
 /// This struct acts as a builder for a [`DB`] and allows you to specify
@@ -45,9 +112,21 @@ const DEFAULT_NUM_PAGES: usize = 32;
 /// # }
 /// ```
 pub struct OpenOptions {
-    pagesize: u64,
+    // `None` unless set explicitly, so an existing file's own pagesize can be used
+    pagesize: Option<u64>,
     num_pages: usize,
     strict_mode: bool,
+    read_only: bool,
+    durability: Durability,
+    checksums: bool,
+    hash_algorithm: HashAlgorithm,
+    #[cfg(feature = "std")]
+    max_batch_size: usize,
+    #[cfg(feature = "std")]
+    max_batch_delay: Duration,
+    on_uncommitted_tx: Option<Arc<UncommittedTxFn>>,
+    // the first option that was set to an invalid value, reported when opening
+    invalid: Option<&'static str>,
 }
 
 impl OpenOptions {
@@ -58,17 +137,19 @@ impl OpenOptions {
 
     /// Sets the pagesize for the database
     ///
-    /// By default, your OS's pagesize is used as the database's pagesize, but if the file is
-    /// moved across systems with different page sizes, it is necessary to set the correct value.
-    /// Trying to open an existing database with the incorrect page size will result in a panic.
+    /// By default, a new database uses your OS's pagesize, and an existing database is opened
+    /// with the pagesize stored in its meta page, so there is usually no need to set this.
+    /// If it is set, trying to open an existing database with a different page size will return an
+    /// [`Error::PagesizeMismatch`](crate::Error::PagesizeMismatch).
     ///
-    /// # Panics
-    /// Will panic if you try to set the pagesize < 1024 bytes.
+    /// The pagesize must be at least 1024 bytes, otherwise opening the database
+    /// will return an [`Error::InvalidOption`](crate::Error::InvalidOption).
     pub fn pagesize(mut self, pagesize: u64) -> Self {
-        if pagesize < 1024 {
-            panic!("Pagesize must be 1024 bytes minimum");
+        if pagesize < MIN_PAGESIZE {
+            self.invalid
+                .get_or_insert("Pagesize must be 1024 bytes minimum");
         }
-        self.pagesize = pagesize;
+        self.pagesize = Some(pagesize);
         self
     }
 
@@ -77,11 +158,11 @@ impl OpenOptions {
     /// The default `num_pages` is set to 32, so if your pagesize is 4096 bytes (4kb), then 131,072 bytes (128kb) will be allocated for the initial file.
     /// Setting `num_pages` when opening an existing database has no effect.
     ///
-    /// # Panics
-    /// Since a minimum of four pages are required for the database, this function will panic if you provide a value < 4.
+    /// Since a minimum of four pages are required for the database, opening the database
+    /// will return an [`Error::InvalidOption`](crate::Error::InvalidOption) if you provide a value < 4.
     pub fn num_pages(mut self, num_pages: usize) -> Self {
         if num_pages < 4 {
-            panic!("Must have a minimum of 4 pages");
+            self.invalid.get_or_insert("Must have a minimum of 4 pages");
         }
         self.num_pages = num_pages;
         self
@@ -96,6 +177,82 @@ impl OpenOptions {
         self
     }
 
+    /// Opens the database without write permission.
+    ///
+    /// The file is opened read-only and only takes a shared lock, so other read-only handles
+    /// (in this process or others) can open it at the same time. The database must already exist,
+    /// it is never created or grown, and [`DB::tx(true)`](struct.DB.html#method.tx) returns
+    /// [`Error::ReadOnlyDB`](crate::Error::ReadOnlyDB).
+    ///
+    /// The default is `false`.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Sets how much work a commit does to make its changes durable, see [`Durability`].
+    ///
+    /// The default is [`Durability::Full`].
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Sets the maximum number of calls to [`DB::batch`] that are run in a single transaction.
+    ///
+    /// A batch is run as soon as it is full, without waiting for the
+    /// [`max_batch_delay`](#method.max_batch_delay). The default is 1000.
+    #[cfg(feature = "std")]
+    pub fn max_batch_size(mut self, max_batch_size: usize) -> Self {
+        if max_batch_size == 0 {
+            self.invalid
+                .get_or_insert("Max batch size must be at least 1");
+        }
+        self.max_batch_size = max_batch_size;
+        self
+    }
+
+    /// Sets how long the first call to [`DB::batch`] waits for other calls to join its batch.
+    ///
+    /// The default is 10 milliseconds.
+    #[cfg(feature = "std")]
+    pub fn max_batch_delay(mut self, max_batch_delay: Duration) -> Self {
+        self.max_batch_delay = max_batch_delay;
+        self
+    }
+
+    /// Sets a callback to help find transactions that were never committed by mistake.
+    ///
+    /// Whenever a writable [`Tx`] with changes is dropped without calling
+    /// [`commit`](crate::Tx::commit) or [`rollback`](crate::Tx::rollback),
+    /// the callback is called with the ID of that transaction. Its changes are still discarded.
+    /// There is no callback by default.
+    pub fn on_uncommitted_tx<F: Fn(u64) + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.on_uncommitted_tx = Some(Arc::new(f));
+        self
+    }
+
+    /// Enables or disables per-page checksums for a new database file.
+    ///
+    /// The default is `true`, so every branch, leaf and freelist page stores a checksum
+    /// that is verified the first time a transaction reads the page, returning an
+    /// [`Error::Corruption`](crate::Error::Corruption) instead of bad data.
+    /// Setting `checksums` when opening an existing database has no effect,
+    /// whether a database uses checksums is decided when it is created.
+    pub fn checksums(mut self, checksums: bool) -> Self {
+        self.checksums = checksums;
+        self
+    }
+
+    /// Sets the hash algorithm used to validate the meta pages of a new database file.
+    ///
+    /// The default is [`HashAlgorithm::default`]. The algorithm is recorded in the database,
+    /// so setting `hash_algorithm` when opening an existing database has no effect.
+    pub fn hash_algorithm(mut self, hash_algorithm: HashAlgorithm) -> Self {
+        self.hash_algorithm = hash_algorithm;
+        self
+    }
+
     /// Opens the database with the current options.
     ///
     /// If the file does not exist, it will initialize an empty database with a size of (`num_pages * pagesize`) bytes.
@@ -107,38 +264,104 @@ impl OpenOptions {
     /// # Errors
     ///
     /// Will return an error if there are issues creating a new file, opening an existing file, obtaining the file lock, or creating the memory map.
+    /// Also returns an error if one of the options is invalid, if the pagesize the database is opened with
+    /// is not the same as the pagesize it was created with, or if neither meta page of an existing file is valid.
+    pub fn open<T: PathLike, O: OpenOption>(self, mmap: Arc<dyn MemoryMap>, path: T) -> Result<DB> {
+        self.open_with(mmap, O::new(), path)
+    }
+
+    /// Same as [`open`](#method.open), but opens the file through the given `options`
+    /// instead of a fresh `O::new()`.
     ///
-    /// # Panics
-    ///
-    /// Will panic if the pagesize the database is opened with is not the same as the pagesize it was created with.
-    pub fn open<T: ToString + PathLike, O: OpenOption>(
+    /// This is how to open a database in a specific [`MemFs`](crate::memfile::MemFs),
+    /// by passing in its [`options`](crate::memfile::MemFs::options).
+    pub fn open_with<T: PathLike, O: OpenOption>(
         self,
         mmap: Arc<dyn MemoryMap>,
+        mut options: O,
         path: T,
     ) -> Result<DB> {
-        let file = if !path.exists() {
-            init_file::<_, O>(&path, self.pagesize, self.num_pages)?
+        self.check()?;
+        let file = if self.read_only {
+            options.read(true).write(false).open(&path)?
+        } else if !options.exists(&path) {
+            init_file(
+                &mut options,
+                &path,
+                self.new_pagesize(),
+                self.num_pages,
+                self.new_flags(),
+            )?
         } else {
-            O::new().read(true).write(true).open(&path)?
+            options.read(true).write(true).open(&path)?
         };
 
-        let db = DBInner::open(mmap, file, self.pagesize, self.strict_mode)?;
+        let db = DBInner::open(mmap, file, &self)?;
         Ok(DB {
             inner: Arc::new(db),
         })
     }
+
+    /// Opens the database stored in an already opened file.
+    ///
+    /// This is for storage that isn't reached through a path, like a
+    /// [`BlockFile`](crate::blockdev::BlockFile) on a block device.
+    /// An empty file is initialized the same way [`open`](#method.open) initializes a new one.
+    pub fn open_file(self, mmap: Arc<dyn MemoryMap>, mut file: File) -> Result<DB> {
+        self.check()?;
+        if !self.read_only && file.metadata()?.len() == 0 {
+            init_db(
+                &mut file,
+                self.new_pagesize(),
+                self.num_pages,
+                self.new_flags(),
+            )?;
+        }
+        let db = DBInner::open(mmap, file, &self)?;
+        Ok(DB {
+            inner: Arc::new(db),
+        })
+    }
+
+    // the pagesize to create a new database with
+    fn new_pagesize(&self) -> u64 {
+        self.pagesize.unwrap_or(get_page_size() as u64)
+    }
+
+    // the meta flags to create a new database with
+    fn new_flags(&self) -> u32 {
+        let hash = u32::from(self.hash_algorithm.id()) << HashAlgorithm::FLAGS_SHIFT;
+        if self.checksums {
+            hash | Meta::FLAG_CHECKSUMS
+        } else {
+            hash
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        match self.invalid {
+            Some(reason) => Err(Error::InvalidOption(reason)),
+            None => Ok(()),
+        }
+    }
 }
 
 impl Default for OpenOptions {
     fn default() -> Self {
-        let pagesize = get_page_size() as u64;
-        if pagesize < 1024 {
-            panic!("Pagesize must be 1024 bytes minimum");
-        }
         OpenOptions {
-            pagesize,
+            pagesize: None,
             num_pages: DEFAULT_NUM_PAGES,
             strict_mode: false,
+            read_only: false,
+            durability: Durability::Full,
+            checksums: true,
+            hash_algorithm: HashAlgorithm::default(),
+            #[cfg(feature = "std")]
+            max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+            #[cfg(feature = "std")]
+            max_batch_delay: DEFAULT_MAX_BATCH_DELAY,
+            on_uncommitted_tx: None,
+            invalid: None,
         }
     }
 }
@@ -149,7 +372,12 @@ impl Default for OpenOptions {
 /// From a DB, you can create a [`Tx`] to access the data in the database.
 /// If you want to use the database across threads, so you can `clone` the database
 /// to have concurrent transactions (you're really just cloning an [`Arc`] so it's pretty cheap).
+/// A [`Tx`] can't leave the thread it was created on, so use a [`Snapshot`] to share
+/// a read-only view of the database between threads.
 /// **Do not** try to open multiple transactions in the same thread, you're pretty likely to cause a deadlock.
+/// With the `std` feature, opening a second writable transaction on a thread that already has one
+/// returns an [`Error::Deadlock`](crate::Error::Deadlock) instead, and [`try_tx`](#method.try_tx)
+/// or [`tx_timeout`](#method.tx_timeout) can be used to avoid waiting forever for a transaction.
 #[derive(Clone)]
 pub struct DB {
     pub(crate) inner: Arc<DBInner>,
@@ -176,10 +404,7 @@ impl DB {
     /// # Ok(())
     /// # }
     /// ```
-    pub fn open<O: OpenOption, T: ToString + PathLike>(
-        mmap: Arc<dyn MemoryMap>,
-        path: T,
-    ) -> Result<Self> {
+    pub fn open<O: OpenOption, T: PathLike>(mmap: Arc<dyn MemoryMap>, path: T) -> Result<Self> {
         OpenOptions::new().open::<T, O>(mmap, path)
     }
 
@@ -187,7 +412,160 @@ impl DB {
     /// This transaction is either read-only or writable depending on the `writable` parameter.
     /// Please read the docs on a [`Tx`] for more details.
     pub fn tx(&self, writable: bool) -> Result<Tx> {
-        Tx::new(self, writable)
+        Tx::new(self, writable, Wait::Block)
+    }
+
+    /// Creates a [`Tx`] like [`tx`](#method.tx), but without waiting.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`WouldBlock`](enum.Error.html#variant.WouldBlock) error if another writable
+    /// transaction is open. Read-only transactions never wait.
+    pub fn try_tx(&self, writable: bool) -> Result<Tx<'_>> {
+        Tx::new(self, writable, Wait::Try)
+    }
+
+    /// Creates a [`Tx`] like [`tx`](#method.tx), but only waits as long as the `timeout` allows.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::{DB, Error, TxTimeout};
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// use std::sync::Arc;
+    /// use jammdb::memfile::{FakeMap, FileOpenOptions};
+    /// let db = DB::open::<FileOpenOptions,_>(Arc::new(FakeMap),"my.db")?;
+    /// match db.tx_timeout(true, TxTimeout::Spins(1000)) {
+    ///     Ok(tx) => tx.commit()?,
+    ///     Err(Error::Timeout) => println!("the database is busy"),
+    ///     Err(e) => return Err(e),
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`Timeout`](enum.Error.html#variant.Timeout) error if the transaction
+    /// could not be started in time.
+    pub fn tx_timeout(&self, writable: bool, timeout: TxTimeout) -> Result<Tx<'_>> {
+        Tx::new(self, writable, Wait::Timeout(timeout))
+    }
+
+    /// Creates a [`Snapshot`], a read-only view of the database that can be shared between threads.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`DatabaseClosed`](enum.Error.html#variant.DatabaseClosed) error if the database has been closed.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        Snapshot::new(self)
+    }
+
+    /// Runs `f` in a writable [`Tx`], committing it if `f` returns `Ok`.
+    ///
+    /// If `f` returns an error the transaction is rolled back, and if it panics the transaction
+    /// is dropped without committing, so none of its changes are saved.
+    /// Errors from `f` and from the commit are returned as is.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::DB;
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// use std::sync::Arc;
+    /// use jammdb::memfile::{FakeMap, FileOpenOptions};
+    /// let db = DB::open::<FileOpenOptions,_>(Arc::new(FakeMap),"my.db")?;
+    /// db.update(|tx| {
+    ///     let bucket = tx.create_bucket("names")?;
+    ///     bucket.put("Kanan", "Jarrus")?;
+    ///     Ok(())
+    /// })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn update<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Tx) -> Result<T>,
+    {
+        let tx = self.tx(true)?;
+        let result = match f(&tx) {
+            Ok(result) => result,
+            Err(e) => {
+                tx.rollback()?;
+                return Err(e);
+            }
+        };
+        tx.commit()?;
+        Ok(result)
+    }
+
+    /// Runs `f` in a read-only [`Tx`], returning whatever `f` returns.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::DB;
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// use std::sync::Arc;
+    /// use jammdb::memfile::{FakeMap, FileOpenOptions};
+    /// let db = DB::open::<FileOpenOptions,_>(Arc::new(FakeMap),"my.db")?;
+    /// let last_name = db.view(|tx| {
+    ///     let bucket = tx.get_bucket("names")?;
+    ///     Ok(bucket.get_kv("Kanan")?.map(|kv| kv.value().to_vec()))
+    /// })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn view<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Tx) -> Result<T>,
+    {
+        let tx = self.tx(false)?;
+        f(&tx)
+    }
+
+    /// Runs `f` in a writable transaction shared with concurrent calls to `batch`.
+    ///
+    /// The first call to `batch` waits up to [`max_batch_delay`](OpenOptions::max_batch_delay)
+    /// for calls from other threads to join it (or until [`max_batch_size`](OpenOptions::max_batch_size)
+    /// calls have joined), then runs all of them in one transaction with one commit,
+    /// so many small concurrent writes don't each wait for their own sync.
+    ///
+    /// If `f` returns an error, it is taken out of the batch and run again in a transaction
+    /// of its own, just like [`update`](#method.update), so its error is returned to its caller
+    /// without affecting the other calls. Because of that, `f` may be called more than once
+    /// and must not have side effects outside of the transaction.
+    ///
+    /// Only available with the `std` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::DB;
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// use std::sync::Arc;
+    /// use jammdb::memfile::{FakeMap, FileOpenOptions};
+    /// let db = DB::open::<FileOpenOptions,_>(Arc::new(FakeMap),"my.db")?;
+    /// db.batch(|tx| {
+    ///     tx.get_or_create_bucket("counters")?.put("visits", "1")?;
+    ///     Ok(())
+    /// })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn batch<F>(&self, f: F) -> Result<()>
+    where
+        F: Fn(&Tx) -> Result<()> + Send + 'static,
+    {
+        self.inner.batcher.batch(self, Box::new(f))
     }
 
     /// Returns the database's pagesize.
@@ -195,53 +573,193 @@ impl DB {
         self.inner.pagesize
     }
 
+    /// Returns the number of open read-only transactions and [`Snapshot`]s.
+    pub fn reader_count(&self) -> usize {
+        self.inner.readers.count()
+    }
+
+    /// Returns the ID of the oldest transaction that an open read-only transaction or [`Snapshot`] sees.
+    ///
+    /// Pages freed after that transaction can't be reused until its readers are done with them,
+    /// so a reader that stays open for a long time shows up here.
+    pub fn oldest_reader_tx(&self) -> Option<u64> {
+        self.inner.readers.oldest()
+    }
+
     pub fn file(&self) -> MutexGuard<File> {
-       self.inner.file.lock()
+        self.inner.file.lock()
     }
 
     #[doc(hidden)]
     pub fn check(&self) -> Result<()> {
         self.tx(false)?.check()
     }
+
+    /// Flushes every committed transaction to disk.
+    ///
+    /// This waits for the current writable transaction, if there is one, to finish first.
+    /// It is only needed when the database is opened with a [`Durability`] other than
+    /// [`Durability::Full`], and does nothing for a database opened read-only.
+    ///
+    /// # Errors
+    ///
+    /// Will return an [`Io`](enum.Error.html#variant.Io) error if syncing the file fails,
+    /// or a [`DatabaseClosed`](enum.Error.html#variant.DatabaseClosed) error if the database has been closed.
+    /// With the `std` feature, returns a [`Deadlock`](enum.Error.html#variant.Deadlock) error if
+    /// a writable transaction is open on the same thread.
+    pub fn sync(&self) -> Result<()> {
+        self.inner.check_deadlock()?;
+        let mut file = self.inner.file.lock();
+        if self.inner.closed.load(Ordering::Acquire) {
+            return Err(Error::DatabaseClosed);
+        }
+        if !self.inner.read_only {
+            file.flush()?;
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Closes the database.
+    ///
+    /// Waits for the writable transaction of every clone of this `DB` to finish, then syncs and
+    /// unlocks the file and releases the memory map. Read-only transactions and snapshots that are
+    /// still open keep the data they can see mapped until they're dropped.
+    /// Any error from the final sync is returned,
+    /// in which case the file is left locked until the last clone is dropped.
+    /// Once the database is closed, calling [`tx`](#method.tx) on a clone returns
+    /// [`Error::DatabaseClosed`](crate::Error::DatabaseClosed).
+    ///
+    /// Dropping the last clone of a `DB` also unlocks the file, but can't report errors.
+    /// Closing a database while a writable transaction is open on the same thread will deadlock,
+    /// or with the `std` feature, return an [`Error::Deadlock`](crate::Error::Deadlock).
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::DB;
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// use std::sync::Arc;
+    /// use jammdb::memfile::{FakeMap, FileOpenOptions};
+    /// let db = DB::open::<FileOpenOptions,_>(Arc::new(FakeMap),"my.db")?;
+    /// let tx = db.tx(true)?;
+    /// tx.create_bucket("abc")?;
+    /// tx.commit()?;
+    /// db.close()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn close(self) -> Result<()> {
+        let inner = &self.inner;
+        inner.check_deadlock()?;
+        // wait for the writer, readers hold on to their own mapping
+        let mut file = inner.file.lock();
+        if inner.closed.load(Ordering::Acquire) {
+            return Err(Error::DatabaseClosed);
+        }
+        if !inner.read_only {
+            file.flush()?;
+            file.sync_all()?;
+        }
+        file.unlock()?;
+        inner.closed.store(true, Ordering::Release);
+        // other clones may keep the DBInner alive, but the mapping can be released now
+        *inner.data.lock() = Arc::new(Unmapped);
+        Ok(())
+    }
 }
+
+// Replaces the mapping of a closed database
+struct Unmapped;
+
+impl IndexByPageID for Unmapped {
+    fn index_range(&self, _page_id: u64, _count: u64, _page_size: usize) -> IOResult<&[u8]> {
+        Err(core2::io::Error::new(
+            ErrorKind::Other,
+            "the database is closed",
+        ))
+    }
+
+    fn len(&self) -> usize {
+        0
+    }
+}
+
 pub(crate) struct DBInner {
     pub(crate) generator: Arc<dyn MemoryMap>,
     pub(crate) data: Mutex<Arc<dyn IndexByPageID>>,
-    pub(crate) mmap_lock: RwLock<()>,
     pub(crate) freelist: Mutex<Freelist>,
     pub(crate) file: Mutex<File>,
-    pub(crate) open_ro_txs: Mutex<Vec<u64>>,
+    pub(crate) readers: ReaderTable,
+    // the meta of the last commit, so new transactions don't have to read and hash the meta pages
+    pub(crate) latest_meta: LatestMeta,
     pub(crate) strict_mode: bool,
+    pub(crate) read_only: bool,
+    pub(crate) durability: Durability,
     pub(crate) pagesize: u64,
+    // set by `DB::close` while holding both the file and mmap locks
+    pub(crate) closed: AtomicBool,
+    #[cfg(feature = "std")]
+    pub(crate) batcher: Batcher,
+    // the thread with the open writable transaction, to catch it trying to open another one
+    #[cfg(feature = "std")]
+    pub(crate) writer: Mutex<Option<ThreadId>>,
+    pub(crate) on_uncommitted_tx: Option<Arc<UncommittedTxFn>>,
 }
 
 impl DBInner {
     pub(crate) fn open(
         mmap: Arc<dyn MemoryMap>,
         mut file: File,
-        pagesize: u64,
-        strict_mode: bool,
+        options: &OpenOptions,
     ) -> Result<Self> {
-        file.lock_exclusive()?;
-        let data = mmap.do_map(&mut file)?;
+        let read_only = options.read_only;
+        if read_only {
+            file.lock_shared()?;
+        } else {
+            file.lock_exclusive()?;
+        }
+        let mapped = mmap
+            .do_map(&mut file)
+            .map_err(Error::from)
+            .and_then(|data| Ok((resolve_pagesize(options.pagesize, &*data)?, data)));
+        let (pagesize, data) = match mapped {
+            Ok(mapped) => mapped,
+            Err(e) => {
+                let _ = file.unlock();
+                return Err(e);
+            }
+        };
         let data = Mutex::new(data);
         let db = DBInner {
             generator: mmap,
             data,
-            mmap_lock: RwLock::new(()),
             freelist: Mutex::new(Freelist::new()),
             file: Mutex::new(file),
-            open_ro_txs: Mutex::new(Vec::new()),
+            readers: ReaderTable::new(),
+            latest_meta: LatestMeta::new(),
             pagesize,
-            strict_mode,
+            strict_mode: options.strict_mode,
+            read_only,
+            durability: options.durability,
+            closed: AtomicBool::new(false),
+            #[cfg(feature = "std")]
+            batcher: Batcher::new(options.max_batch_size, options.max_batch_delay),
+            #[cfg(feature = "std")]
+            writer: Mutex::new(None),
+            on_uncommitted_tx: options.on_uncommitted_tx.clone(),
         };
         {
             let meta = db.meta()?;
+            db.latest_meta.store(&meta);
             // let data = db.data.lock();
             // let free_pages = Page::from_buf(&data, meta.freelist_page, pagesize).freelist();
 
-            let data = db.data.lock();
-            let free_pages = Page::from_index(&data, meta.freelist_page, pagesize).freelist();
+            let pages =
+                Pages::new(db.data.lock().clone(), pagesize).verify_checksums(meta.checksums());
+            let free_pages = pages.page(meta.freelist_page())?.freelist();
 
             if !free_pages.is_empty() {
                 db.freelist.lock().init(free_pages);
@@ -252,80 +770,170 @@ impl DBInner {
     }
 
     /// we increase the size of the file, and then remap the file
+    ///
+    /// Open transactions keep the old mapping alive, so they don't need to finish first.
+    /// New transactions pick up the new one.
     pub(crate) fn resize(&self, file: &mut File, new_size: u64) -> Result<Arc<dyn IndexByPageID>> {
         file.allocate(new_size)?;
-        let _lock = self.mmap_lock.write();
-        let mut data = self.data.lock();
         let mmap = self.generator.do_map(file)?;
-        *data = mmap;
+        *self.data.lock() = mmap.clone();
+        Ok(mmap)
+    }
 
-        Ok(data.clone())
+    // the writer lock isn't reentrant, so a thread that holds it would wait for itself forever
+    pub(crate) fn check_deadlock(&self) -> Result<()> {
+        #[cfg(feature = "std")]
+        if *self.writer.lock() == Some(std::thread::current().id()) {
+            return Err(Error::Deadlock);
+        }
+        Ok(())
     }
 
     pub(crate) fn meta(&self) -> Result<Meta> {
-        let data = self.data.lock();
-        let meta1 = Page::from_index(&data, 0, self.pagesize).meta();
+        let pages = Pages::new(self.data.lock().clone(), self.pagesize);
+        let meta = read_meta(&pages)?;
+        // don't fall back to the other meta page, that would silently lose the latest commit
+        if !(MIN_SUPPORTED_VERSION..=VERSION).contains(&meta.version()) {
+            return Err(Error::UnsupportedVersion {
+                found: meta.version(),
+                supported: MIN_SUPPORTED_VERSION..=VERSION,
+            });
+        }
+        Ok(meta)
+    }
+}
 
-        // Double check that we have the right pagesize before we read the second page.
-        if meta1.valid() && meta1.pagesize != self.pagesize {
-            assert_eq!(
-                meta1.pagesize, self.pagesize,
-                "Invalid pagesize from meta1 {}. Expected {}.",
-                meta1.pagesize, self.pagesize
-            );
+impl Drop for DBInner {
+    fn drop(&mut self) {
+        if !*self.closed.get_mut() {
+            let _ = self.file.get_mut().unlock();
         }
+    }
+}
 
-        // let meta2 = Page::from_buf(&data, 1, self.pagesize).meta();
-        let meta2 = Page::from_index(&data, 1, self.pagesize).meta();
-
-        let meta = match (meta1.valid(), meta2.valid()) {
-            (true, true) => {
-                assert_eq!(
-                    meta1.pagesize, self.pagesize,
-                    "Invalid pagesize from meta1 {}. Expected {}.",
-                    meta1.pagesize, self.pagesize
-                );
-                assert_eq!(
-                    meta2.pagesize, self.pagesize,
-                    "Invalid pagesize from meta2 {}. Expected {}.",
-                    meta2.pagesize, self.pagesize
-                );
-                if meta1.tx_id > meta2.tx_id {
-                    meta1
-                } else {
-                    meta2
-                }
-            }
-            (true, false) => {
-                assert_eq!(
-                    meta1.pagesize, self.pagesize,
-                    "Invalid pagesize from meta1 {}. Expected {}.",
-                    meta1.pagesize, self.pagesize
-                );
+// Picks the latest valid meta page, checking that it fits in the file.
+// The format version isn't checked, so older databases can still be read to migrate them.
+pub(crate) fn read_meta(pages: &Pages) -> Result<Meta> {
+    let (meta1, meta2) = (pages.meta(0)?, pages.meta(1)?);
+    // Without the magic value on either meta page, the file was never a database.
+    // With it but no valid hash, it's a database with both meta pages damaged.
+    if !meta1
+        .into_iter()
+        .chain(meta2)
+        .any(|m| m.magic() == MAGIC_VALUE)
+    {
+        return Err(Error::NotADatabase);
+    }
+    let meta1 = meta1.filter(|m| m.magic() == MAGIC_VALUE && m.valid());
+    let meta2 = meta2.filter(|m| m.magic() == MAGIC_VALUE && m.valid());
+
+    // Double check that we have the right pagesize before we read the second page.
+    if let Some(meta1) = meta1.filter(|m| m.pagesize() != pages.pagesize) {
+        return Err(Error::PagesizeMismatch {
+            expected: pages.pagesize,
+            found: meta1.pagesize(),
+        });
+    }
+
+    if let (Some(meta1), Some(meta2)) = (meta1, meta2) {
+        if meta1.pagesize() != meta2.pagesize() {
+            return Err(Error::CorruptMeta);
+        }
+    }
+    if let Some(meta2) = meta2.filter(|m| m.pagesize() != pages.pagesize) {
+        return Err(Error::PagesizeMismatch {
+            expected: pages.pagesize,
+            found: meta2.pagesize(),
+        });
+    }
+
+    let meta = match (meta1, meta2) {
+        (Some(meta1), Some(meta2)) => {
+            if meta1.tx_id() > meta2.tx_id() {
                 meta1
-            }
-            (false, true) => {
-                assert_eq!(
-                    meta2.pagesize, self.pagesize,
-                    "Invalid pagesize from meta2 {}. Expected {}.",
-                    meta2.pagesize, self.pagesize
-                );
+            } else {
                 meta2
             }
-            (false, false) => panic!("NO VALID META PAGES"),
-        };
+        }
+        (Some(meta1), None) => meta1,
+        (None, Some(meta2)) => meta2,
+        (None, None) => {
+            // the hashes can't be checked if the algorithm isn't available
+            let unsupported = [pages.meta(0)?, pages.meta(1)?]
+                .into_iter()
+                .flatten()
+                .find(|m| m.magic() == MAGIC_VALUE && m.hash_algorithm().is_none());
+            return match unsupported {
+                Some(meta) => Err(Error::UnsupportedHashAlgorithm {
+                    id: (meta.flags() >> HashAlgorithm::FLAGS_SHIFT) as u8,
+                }),
+                None => Err(Error::CorruptMeta),
+            };
+        }
+    };
+    // a valid meta still has to describe pages that are in the file
+    let file_pages = pages.data.len() as u64 / pages.pagesize;
+    if meta.num_pages() > file_pages
+        || meta.freelist_page() >= meta.num_pages()
+        || meta.root.root_page() >= meta.num_pages()
+    {
+        return Err(Error::CorruptMeta);
+    }
+
+    Ok(meta.clone())
+}
 
-        Ok(meta.clone())
+// The pagesize to read the database with, preferring the one stored in the file
+// and only falling back to the `requested` one if the first meta page is damaged.
+pub(crate) fn resolve_pagesize(requested: Option<u64>, data: &dyn IndexByPageID) -> Result<u64> {
+    match (requested, detect_pagesize(data)?) {
+        (Some(expected), Some(found)) if expected != found => {
+            Err(Error::PagesizeMismatch { expected, found })
+        }
+        (_, Some(found)) => Ok(found),
+        (Some(expected), None) => Ok(expected),
+        (None, None) => Ok(get_page_size() as u64),
     }
 }
 
-fn init_file<T: ToString + PathLike, O: OpenOption>(
+// Reads the pagesize stored in the first meta page, which starts at the beginning of the file
+// whatever the pagesize is. Returns `None` if that meta page isn't valid, like after a torn write,
+// in which case the second meta page can only be found with the pagesize the database was opened with.
+fn detect_pagesize(data: &dyn IndexByPageID) -> Result<Option<u64>> {
+    if data.len() < MIN_PAGESIZE as usize {
+        return Err(Error::NotADatabase);
+    }
+    let buf = data.index_range(0, 1, MIN_PAGESIZE as usize)?;
+    #[allow(clippy::cast_ptr_alignment)]
+    let page = unsafe { &*(&buf[0] as *const u8 as *const Page) };
+    if page.page_type != Page::TYPE_META
+        || page.meta().magic() != MAGIC_VALUE
+        || !page.meta().valid()
+    {
+        return Ok(None);
+    }
+    match page.meta().pagesize() {
+        pagesize if pagesize < MIN_PAGESIZE => Err(Error::CorruptMeta),
+        pagesize => Ok(Some(pagesize)),
+    }
+}
+
+fn init_file<T: PathLike, O: OpenOption>(
+    options: &mut O,
     path: &T,
     pagesize: u64,
     num_pages: usize,
+    flags: u32,
 ) -> Result<File> {
-    let mut file = O::new().create(true).read(true).write(true).open(path)?;
+    let mut file = options.create(true).read(true).write(true).open(path)?;
+    init_db(&mut file, pagesize, num_pages, flags)?;
+    Ok(file)
+}
+
+// writes the meta, freelist and root pages of an empty database to the start of the file
+fn init_db(file: &mut File, pagesize: u64, num_pages: usize, flags: u32) -> Result<()> {
     file.allocate(pagesize * (num_pages as u64))?;
+    file.seek(SeekFrom::Start(0))?;
     let mut buf = vec![0; (pagesize * 4) as usize];
     let mut get_page = |index: u64| {
         #[allow(clippy::cast_ptr_alignment)]
@@ -335,43 +943,50 @@ fn init_file<T: ToString + PathLike, O: OpenOption>(
     };
     for i in 0..2 {
         let page = get_page(i);
-        page.id = i;
+        page.set_id(i);
         page.page_type = Page::TYPE_META;
         let m = page.meta_mut();
-        m.meta_page = i as u32;
-        m.magic = MAGIC_VALUE;
-        m.version = VERSION;
-        m.pagesize = pagesize;
-        m.freelist_page = 2;
-        m.root = BucketMeta {
-            root_page: 3,
-            next_int: 0,
-        };
-        m.num_pages = 4;
+        m.set_meta_page(i as u32);
+        m.set_magic(MAGIC_VALUE);
+        m.set_version(VERSION);
+        m.set_flags(flags);
+        m.set_pagesize(pagesize);
+        m.set_freelist_page(2);
+        m.root = BucketMeta::new(3, 0);
+        m.set_num_pages(4);
         m.hash = m.hash_self();
     }
 
     let p = get_page(2);
-    p.id = 2;
+    p.set_id(2);
     p.page_type = Page::TYPE_FREELIST;
-    p.count = 0;
+    p.set_count(0);
 
     let p = get_page(3);
-    p.id = 3;
+    p.set_id(3);
     p.page_type = Page::TYPE_LEAF;
-    p.count = 0;
+    p.set_count(0);
+
+    if flags & Meta::FLAG_CHECKSUMS != 0 {
+        for i in 2..4 {
+            let p = get_page(i);
+            p.set_checksum(p.compute_checksum(pagesize).unwrap());
+        }
+    }
 
     file.write_all(&buf[..])?;
     file.flush()?;
     file.sync_all()?;
-    Ok(file)
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::memfile::{FakeMap, FileOpenOptions};
+    use crate::page::LeafElement;
     use crate::testutil::RandomFile;
+    use core::mem::size_of;
 
     #[test]
     fn test_open_options() {
@@ -401,19 +1016,24 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
     fn test_open_options_min_pages() {
-        OpenOptions::new().num_pages(3);
+        let random_file = RandomFile::new();
+        let result = OpenOptions::new()
+            .num_pages(3)
+            .open::<_, FileOpenOptions>(Arc::new(FakeMap), &random_file);
+        assert!(matches!(result, Err(Error::InvalidOption(_))));
     }
 
     #[test]
-    #[should_panic]
     fn test_open_options_min_pagesize() {
-        OpenOptions::new().pagesize(1000);
+        let random_file = RandomFile::new();
+        let result = OpenOptions::new()
+            .pagesize(1000)
+            .open::<_, FileOpenOptions>(Arc::new(FakeMap), &random_file);
+        assert!(matches!(result, Err(Error::InvalidOption(_))));
     }
 
     #[test]
-    #[should_panic]
     fn test_different_pagesizes() {
         assert_ne!(get_page_size(), 5000);
         let random_file = RandomFile::new();
@@ -425,6 +1045,468 @@ mod tests {
                 .unwrap();
             assert_eq!(db.pagesize(), 5000);
         }
-        DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file).unwrap();
+        let result = OpenOptions::new()
+            .pagesize(get_page_size() as u64)
+            .open::<_, FileOpenOptions>(Arc::new(FakeMap), &random_file);
+        assert_eq!(
+            result.err(),
+            Some(Error::PagesizeMismatch {
+                expected: get_page_size() as u64,
+                found: 5000,
+            })
+        );
+    }
+
+    #[test]
+    fn test_detect_pagesize() -> Result<()> {
+        let random_file = RandomFile::new();
+        {
+            let db = OpenOptions::new()
+                .pagesize(5000)
+                .open::<_, FileOpenOptions>(Arc::new(FakeMap), &random_file)?;
+            let tx = db.tx(true)?;
+            tx.create_bucket("abc")?.put("key", "value")?;
+            tx.commit()?;
+        }
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file)?;
+        assert_eq!(db.pagesize(), 5000);
+        let tx = db.tx(false)?;
+        assert_eq!(
+            tx.get_bucket("abc")?.get_kv("key")?.unwrap().value(),
+            b"value"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_detect_pagesize_torn_meta() -> Result<()> {
+        let random_file = RandomFile::new();
+        {
+            let db = OpenOptions::new()
+                .pagesize(5000)
+                .open::<_, FileOpenOptions>(Arc::new(FakeMap), &random_file)?;
+            let mut file = db.file();
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&[0xFF; 5000])?;
+            file.flush()?;
+        }
+        // without a valid first meta page, the second one can't be found at the default pagesize
+        let result = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file);
+        assert_eq!(result.err(), Some(Error::NotADatabase));
+        let db = OpenOptions::new()
+            .pagesize(5000)
+            .open::<_, FileOpenOptions>(Arc::new(FakeMap), &random_file)?;
+        assert_eq!(db.pagesize(), 5000);
+        Ok(())
+    }
+
+    // opens a new database with a pagesize of 1024 and edits both of its meta pages
+    fn edit_metas(random_file: &RandomFile, edit: impl Fn(&mut Meta)) -> Result<()> {
+        let db = OpenOptions::new()
+            .pagesize(1024)
+            .open::<_, FileOpenOptions>(Arc::new(FakeMap), random_file)?;
+        let mut file = db.file();
+        let mut buf = vec![0; 2048];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut buf)?;
+        for i in 0..2 {
+            #[allow(clippy::cast_ptr_alignment)]
+            let page = unsafe { &mut *(&mut buf[i * 1024] as *mut u8 as *mut Page) };
+            edit(page.meta_mut());
+        }
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&buf)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_grow_with_open_reader() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file)?;
+        db.update(|tx| tx.create_bucket("abc")?.put("key", "value").map(|_| ()))?;
+        let tx = db.tx(false)?;
+        let bucket = tx.get_bucket("abc")?;
+        let size = db.file().metadata()?.len();
+        // growing the file doesn't wait for the reader, even on the same thread
+        db.update(|tx| {
+            let b = tx.get_bucket("abc")?;
+            for i in 0..10_000_u64 {
+                b.put(i.to_be_bytes(), i.to_string())?;
+            }
+            Ok(())
+        })?;
+        assert!(db.file().metadata()?.len() > size);
+        // the reader still sees the data through the old mapping
+        assert_eq!(bucket.get_kv("key")?.unwrap().value(), b"value");
+        assert_eq!(bucket.kv_pairs().count(), 1);
+        drop(tx);
+        assert_eq!(db.tx(false)?.get_bucket("abc")?.kv_pairs().count(), 10_001);
+        Ok(())
+    }
+
+    #[test]
+    fn test_readers() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file)?;
+        db.update(|tx| tx.create_bucket("abc").map(|_| ()))?;
+        assert_eq!(db.reader_count(), 0);
+        assert_eq!(db.oldest_reader_tx(), None);
+        let tx = db.tx(false)?;
+        let oldest = tx.inner.borrow().meta.tx_id();
+        let snapshot = db.snapshot()?;
+        db.update(|tx| tx.get_bucket("abc")?.put("key", "value").map(|_| ()))?;
+        let newer = db.tx(false)?;
+        assert_eq!(db.reader_count(), 3);
+        assert_eq!(db.oldest_reader_tx(), Some(oldest));
+        drop(tx);
+        drop(snapshot);
+        assert_eq!(db.oldest_reader_tx(), Some(oldest + 1));
+        drop(newer);
+        assert_eq!(db.reader_count(), 0);
+
+        // lots of short readers alongside a writer
+        let workers: Vec<_> = (0..8)
+            .map(|_| {
+                let db = db.clone();
+                std::thread::spawn(move || -> Result<()> {
+                    for _ in 0..200 {
+                        let tx = db.tx(false)?;
+                        let bucket = tx.get_bucket("abc")?;
+                        let count = bucket.kv_pairs().count();
+                        assert_eq!(count, bucket.kv_pairs().count());
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for i in 0..200_u64 {
+            db.update(|tx| {
+                tx.get_bucket("abc")?
+                    .put(i.to_be_bytes(), "value")
+                    .map(|_| ())
+            })?;
+        }
+        for worker in workers {
+            worker.join().unwrap()?;
+        }
+        assert_eq!(db.reader_count(), 0);
+        db.check()
+    }
+
+    #[test]
+    fn test_close() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file)?;
+        {
+            let tx = db.tx(true)?;
+            tx.create_bucket("abc")?.put("key", "value")?;
+            tx.commit()?;
+        }
+        let clone = db.clone();
+        {
+            let tx = clone.tx(false)?;
+            let bucket = tx.get_bucket("abc")?;
+            db.close()?;
+            // open readers keep their data after the database is closed
+            assert_eq!(bucket.get_kv("key")?.unwrap().value(), b"value");
+        }
+        assert_eq!(clone.tx(false).err(), Some(Error::DatabaseClosed));
+        assert_eq!(clone.tx(true).err(), Some(Error::DatabaseClosed));
+        assert_eq!(clone.sync(), Err(Error::DatabaseClosed));
+        assert_eq!(clone.close(), Err(Error::DatabaseClosed));
+
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file)?;
+        let tx = db.tx(false)?;
+        assert_eq!(
+            tx.get_bucket("abc")?.get_kv("key")?.unwrap().value(),
+            b"value"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_only() -> Result<()> {
+        let random_file = RandomFile::new();
+        let result = OpenOptions::new()
+            .read_only(true)
+            .open::<_, FileOpenOptions>(Arc::new(FakeMap), &random_file);
+        assert!(matches!(result, Err(Error::Io(_))));
+        // a read-only open never creates the file
+        assert!(!FileOpenOptions::new().exists(&&random_file));
+
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file)?;
+        {
+            let tx = db.tx(true)?;
+            tx.create_bucket("abc")?.put("key", "value")?;
+            tx.commit()?;
+        }
+        let read_only = OpenOptions::new()
+            .read_only(true)
+            .open::<_, FileOpenOptions>(Arc::new(FakeMap), &random_file)?;
+        assert_eq!(read_only.tx(true).err(), Some(Error::ReadOnlyDB));
+        read_only.sync()?;
+        let tx = read_only.tx(false)?;
+        assert_eq!(
+            tx.get_bucket("abc")?.get_kv("key")?.unwrap().value(),
+            b"value"
+        );
+        drop(tx);
+        read_only.close()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_tx() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file)?;
+        {
+            // stand in for a writer on another thread
+            let _writer = db.inner.file.lock();
+            assert_eq!(db.try_tx(true).err(), Some(Error::WouldBlock));
+            assert_eq!(
+                db.tx_timeout(true, TxTimeout::Spins(10)).err(),
+                Some(Error::Timeout)
+            );
+            #[cfg(feature = "std")]
+            assert_eq!(
+                db.tx_timeout(true, TxTimeout::Duration(Duration::from_millis(1)))
+                    .err(),
+                Some(Error::Timeout)
+            );
+            // readers don't wait for the writer
+            db.try_tx(false)?;
+            db.tx_timeout(false, TxTimeout::Spins(0))?;
+        }
+        db.try_tx(true)?.create_bucket("abc")?;
+        db.tx_timeout(false, TxTimeout::Spins(0))?;
+        #[cfg(feature = "std")]
+        db.tx_timeout(true, TxTimeout::Duration(Duration::MAX))?;
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_deadlock() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file)?;
+        {
+            let _tx = db.tx(true)?;
+            assert_eq!(db.tx(true).err(), Some(Error::Deadlock));
+            assert_eq!(db.try_tx(true).err(), Some(Error::Deadlock));
+            assert_eq!(db.update(|_| db.update(|_| Ok(()))), Err(Error::Deadlock));
+            assert_eq!(db.sync(), Err(Error::Deadlock));
+            assert_eq!(db.clone().close(), Err(Error::Deadlock));
+            db.tx(false)?;
+        }
+        db.update(|tx| tx.create_bucket("abc").map(|_| ()))?;
+        db.tx(true)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_view() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file)?;
+        let value = db.update(|tx| {
+            tx.create_bucket("abc")?.put("key", "value")?;
+            Ok(42)
+        })?;
+        assert_eq!(value, 42);
+        // errors roll the transaction back
+        let result = db.update(|tx| {
+            tx.create_bucket("def")?;
+            tx.get_bucket("missing")?;
+            Ok(())
+        });
+        assert_eq!(result, Err(Error::BucketMissing));
+        // and so do panics
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            db.update(|tx| -> Result<()> {
+                tx.create_bucket("ghi")?;
+                panic!("oops");
+            })
+        }));
+        assert!(result.is_err());
+
+        let value = db.view(|tx| {
+            assert_eq!(tx.get_bucket("def").err(), Some(Error::BucketMissing));
+            assert_eq!(tx.get_bucket("ghi").err(), Some(Error::BucketMissing));
+            Ok(tx
+                .get_bucket("abc")?
+                .get_kv("key")?
+                .unwrap()
+                .value()
+                .to_vec())
+        })?;
+        assert_eq!(value, b"value");
+        assert_eq!(
+            db.view(|tx| tx.create_bucket("def").map(|_| ())),
+            Err(Error::ReadOnlyTx)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_byte_order() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = OpenOptions::new()
+            .pagesize(1024)
+            .open::<_, FileOpenOptions>(Arc::new(FakeMap), &random_file)?;
+        let mut file = db.file();
+        let mut buf = vec![0; 1024];
+        file.seek(SeekFrom::Start(1024))?;
+        file.read_exact(&mut buf)?;
+        // the header and meta of page 1 are stored in little-endian, whatever the host
+        assert_eq!(buf[..8], 1_u64.to_le_bytes());
+        assert_eq!(buf[8], Page::TYPE_META);
+        assert_eq!(buf[32..36], 1_u32.to_le_bytes());
+        assert_eq!(buf[36..40], MAGIC_VALUE.to_le_bytes());
+        assert_eq!(buf[40..44], VERSION.to_le_bytes());
+        assert_eq!(buf[48..56], 1024_u64.to_le_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_meta() -> Result<()> {
+        let random_file = RandomFile::new();
+        // change the metas without updating their hashes
+        edit_metas(&random_file, |meta| meta.set_tx_id(meta.tx_id() + 1))?;
+        let result = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file);
+        assert_eq!(result.err(), Some(Error::CorruptMeta));
+        Ok(())
+    }
+
+    #[test]
+    fn test_not_a_database() -> Result<()> {
+        let random_file = RandomFile::new();
+        {
+            let mut file = FileOpenOptions::new()
+                .create(true)
+                .write(true)
+                .open(&random_file)?;
+            file.write_all(&[0xAB; 8192])?;
+            file.flush()?;
+        }
+        let result = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file);
+        assert_eq!(result.err(), Some(Error::NotADatabase));
+
+        let random_file = RandomFile::new();
+        {
+            let mut file = FileOpenOptions::new()
+                .create(true)
+                .write(true)
+                .open(&random_file)?;
+            file.write_all(b"too short")?;
+            file.flush()?;
+        }
+        let result = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file);
+        assert_eq!(result.err(), Some(Error::NotADatabase));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsupported_version() -> Result<()> {
+        let random_file = RandomFile::new();
+        edit_metas(&random_file, |meta| {
+            meta.set_version(VERSION + 1);
+            meta.hash = meta.hash_self();
+        })?;
+        let result = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file);
+        assert_eq!(
+            result.err(),
+            Some(Error::UnsupportedVersion {
+                found: VERSION + 1,
+                supported: MIN_SUPPORTED_VERSION..=VERSION,
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_algorithms() -> Result<()> {
+        let algorithms = [
+            #[cfg(feature = "sha3")]
+            HashAlgorithm::Sha3,
+            HashAlgorithm::Crc32c,
+            #[cfg(feature = "xxhash")]
+            HashAlgorithm::XxHash,
+        ];
+        for algorithm in algorithms {
+            let random_file = RandomFile::new();
+            {
+                let db = OpenOptions::new()
+                    .hash_algorithm(algorithm)
+                    .open::<_, FileOpenOptions>(Arc::new(FakeMap), &random_file)?;
+                let tx = db.tx(true)?;
+                tx.create_bucket("abc")?.put("key", "value")?;
+                tx.commit()?;
+                assert_eq!(db.inner.meta()?.hash_algorithm(), Some(algorithm));
+            }
+            // the algorithm is read from the meta, not the options
+            let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file)?;
+            assert_eq!(db.inner.meta()?.hash_algorithm(), Some(algorithm));
+            {
+                let tx = db.tx(true)?;
+                let data = tx.get_bucket("abc")?.get_kv("key")?.unwrap();
+                assert_eq!(data.value(), b"value");
+                tx.get_bucket("abc")?.put("key2", "value2")?;
+                tx.commit()?;
+            }
+            assert_eq!(db.inner.meta()?.hash_algorithm(), Some(algorithm));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsupported_hash_algorithm() -> Result<()> {
+        let random_file = RandomFile::new();
+        edit_metas(&random_file, |meta| {
+            meta.set_flags(meta.flags() | 0xFF << HashAlgorithm::FLAGS_SHIFT);
+        })?;
+        let result = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file);
+        assert_eq!(
+            result.err(),
+            Some(Error::UnsupportedHashAlgorithm { id: 0xFF })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_checksums() -> Result<()> {
+        for checksums in [true, false] {
+            let random_file = RandomFile::new();
+            let root_page = {
+                let db = OpenOptions::new()
+                    .pagesize(1024)
+                    .checksums(checksums)
+                    .open::<_, FileOpenOptions>(Arc::new(FakeMap), &random_file)?;
+                let tx = db.tx(true)?;
+                tx.create_bucket("abc")?.put("key", "value")?;
+                tx.commit()?;
+                let root_page = db.inner.meta()?.root.root_page();
+                // flip a bit in the first byte of the bucket's name
+                let offset = root_page * 1024 + 32 + size_of::<LeafElement>() as u64;
+                let mut file = db.file();
+                let mut byte = [0];
+                file.seek(SeekFrom::Start(offset))?;
+                file.read_exact(&mut byte)?;
+                assert_eq!(byte, *b"a");
+                byte[0] ^= 0x04;
+                file.seek(SeekFrom::Start(offset))?;
+                file.write_all(&byte)?;
+                file.flush()?;
+                root_page
+            };
+            let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file)?;
+            let tx = db.tx(false)?;
+            let result = tx.get_bucket("abc").err();
+            if checksums {
+                assert_eq!(result, Some(Error::Corruption { page_id: root_page }));
+            } else {
+                assert_eq!(result, Some(Error::BucketMissing));
+            }
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,72 @@
+// Integers in the on-disk structures are always stored in little-endian byte order,
+// so a database file can be moved between hosts of any endianness.
+// The wrappers keep the size and alignment of the plain integers,
+// so the structures can still be cast directly from the mapped bytes.
+use core::fmt::{Debug, Formatter};
+
+macro_rules! little_endian {
+    ($name:ident, $int:ty) => {
+        #[repr(transparent)]
+        #[derive(Clone, Copy, Default, PartialEq, Eq)]
+        pub(crate) struct $name($int);
+
+        impl $name {
+            #[inline]
+            pub(crate) const fn new(value: $int) -> $name {
+                $name(value.to_le())
+            }
+
+            #[inline]
+            pub(crate) const fn get(self) -> $int {
+                <$int>::from_le(self.0)
+            }
+
+            #[inline]
+            pub(crate) fn set(&mut self, value: $int) {
+                self.0 = value.to_le();
+            }
+        }
+
+        impl From<$int> for $name {
+            #[inline]
+            fn from(value: $int) -> $name {
+                $name::new(value)
+            }
+        }
+
+        impl Debug for $name {
+            fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+                Debug::fmt(&self.get(), f)
+            }
+        }
+    };
+}
+
+little_endian!(U32, u32);
+little_endian!(U64, u64);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::{align_of, size_of};
+
+    #[test]
+    fn test_little_endian() {
+        let mut n = U64::new(0x0102_0304_0506_0708);
+        assert_eq!(n.get(), 0x0102_0304_0506_0708);
+        let bytes = unsafe { *(&n as *const U64 as *const [u8; 8]) };
+        assert_eq!(bytes, [8, 7, 6, 5, 4, 3, 2, 1]);
+        n.set(42);
+        assert_eq!(n.get(), 42);
+        assert_eq!(format!("{n:?}"), "42");
+
+        let n = U32::from(0x0102_0304);
+        let bytes = unsafe { *(&n as *const U32 as *const [u8; 4]) };
+        assert_eq!(bytes, [4, 3, 2, 1]);
+
+        assert_eq!(size_of::<U32>(), size_of::<u32>());
+        assert_eq!(align_of::<U32>(), align_of::<u32>());
+        assert_eq!(size_of::<U64>(), size_of::<u64>());
+        assert_eq!(align_of::<U64>(), align_of::<u64>());
+    }
+}
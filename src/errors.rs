@@ -1,6 +1,7 @@
 use alloc::string::String;
 use core::error::Error as StdError;
 use core::fmt;
+use core::ops::RangeInclusive;
 pub(crate) type Result<T> = core::result::Result<T, Error>;
 /// Possible database errors
 #[derive(Debug)]
@@ -15,12 +16,59 @@ pub enum Error {
     IncompatibleValue,
     /// Tried to write to a read only transaction
     ReadOnlyTx,
+    /// Tried to start a writable transaction on a database opened with
+    /// [`OpenOptions::read_only`](crate::OpenOptions::read_only)
+    ReadOnlyDB,
     /// Wrapper around a [`std::io::Error`] that occurred while opening the file or writing to it
     Io(core2::io::Error),
     /// Wrapper around a [`PoisonError`]
     Sync(&'static str),
     /// Error returned when the DB is found to be in an invalid state
     InvalidDB(String),
+    /// Neither meta page holds a valid meta, or the valid one points outside of the file
+    CorruptMeta,
+    /// Tried to open a database with a different pagesize than it was created with
+    PagesizeMismatch {
+        /// the pagesize the database was opened with
+        expected: u64,
+        /// the pagesize stored in the database
+        found: u64,
+    },
+    /// An [`OpenOptions`](crate::OpenOptions) value is out of range
+    InvalidOption(&'static str),
+    /// Tried to read a page past the end of the file
+    PageOutOfRange {
+        /// the page that was read
+        page_id: u64,
+    },
+    /// The file doesn't start with a jammdb meta page
+    NotADatabase,
+    /// The database was written in a file format version this version of the library can't open
+    UnsupportedVersion {
+        /// the version stored in the database
+        found: u32,
+        /// the versions this version of the library can open
+        supported: RangeInclusive<u32>,
+    },
+    /// A page failed its checksum
+    Corruption {
+        /// the page that was read
+        page_id: u64,
+    },
+    /// The meta pages were hashed with an unknown algorithm, or one whose feature isn't enabled
+    UnsupportedHashAlgorithm {
+        /// the id of the algorithm stored in the meta page
+        id: u8,
+    },
+    /// Tried to use a database after it was closed with [`DB::close`](crate::DB::close)
+    DatabaseClosed,
+    /// [`DB::try_tx`](crate::DB::try_tx) couldn't start a transaction without waiting
+    WouldBlock,
+    /// [`DB::tx_timeout`](crate::DB::tx_timeout) couldn't start a transaction before the timeout
+    Timeout,
+    /// Tried to start a writable transaction, sync or close the database
+    /// on a thread that already has a writable transaction open, which would wait forever
+    Deadlock,
 }
 
 impl StdError for Error {}
@@ -33,9 +81,32 @@ impl fmt::Display for Error {
             Error::KeyValueMissing => write!(f, "Key / Value pair does not exist"),
             Error::IncompatibleValue => write!(f, "Value not compatible"),
             Error::ReadOnlyTx => write!(f, "Cannot write in a read-only transaction"),
+            Error::ReadOnlyDB => write!(f, "Cannot write to a database opened read-only"),
             Error::Io(e) => write!(f, "IO Error: {e}"),
             Error::Sync(s) => write!(f, "Sync Error: {s}"),
             Error::InvalidDB(s) => write!(f, "Invalid DB: {s}"),
+            Error::CorruptMeta => write!(f, "No valid meta page"),
+            Error::PagesizeMismatch { expected, found } => write!(
+                f,
+                "Invalid pagesize: expected {expected}, database has {found}"
+            ),
+            Error::InvalidOption(s) => write!(f, "Invalid option: {s}"),
+            Error::PageOutOfRange { page_id } => write!(f, "Page {page_id} is out of range"),
+            Error::NotADatabase => write!(f, "File is not a database"),
+            Error::UnsupportedVersion { found, supported } => write!(
+                f,
+                "Unsupported version {found}, supported versions are {} to {}",
+                supported.start(),
+                supported.end()
+            ),
+            Error::Corruption { page_id } => write!(f, "Page {page_id} is corrupted"),
+            Error::UnsupportedHashAlgorithm { id } => {
+                write!(f, "Unsupported meta hash algorithm {id}")
+            }
+            Error::DatabaseClosed => write!(f, "Database is closed"),
+            Error::WouldBlock => write!(f, "Transaction would block"),
+            Error::Timeout => write!(f, "Timed out waiting for a transaction"),
+            Error::Deadlock => write!(f, "A writable transaction is already open on this thread"),
         }
     }
 }
@@ -54,8 +125,38 @@ impl PartialEq for Error {
             (Error::KeyValueMissing, Error::KeyValueMissing) => true,
             (Error::IncompatibleValue, Error::IncompatibleValue) => true,
             (Error::ReadOnlyTx, Error::ReadOnlyTx) => true,
+            (Error::ReadOnlyDB, Error::ReadOnlyDB) => true,
             (Error::Sync(s1), Error::Sync(s2)) => s1 == s2,
             (Error::InvalidDB(s1), Error::InvalidDB(s2)) => s1 == s2,
+            (Error::CorruptMeta, Error::CorruptMeta) => true,
+            (
+                Error::PagesizeMismatch { expected, found },
+                Error::PagesizeMismatch {
+                    expected: e2,
+                    found: f2,
+                },
+            ) => expected == e2 && found == f2,
+            (Error::InvalidOption(s1), Error::InvalidOption(s2)) => s1 == s2,
+            (Error::PageOutOfRange { page_id }, Error::PageOutOfRange { page_id: p2 }) => {
+                page_id == p2
+            }
+            (Error::NotADatabase, Error::NotADatabase) => true,
+            (
+                Error::UnsupportedVersion { found, supported },
+                Error::UnsupportedVersion {
+                    found: f2,
+                    supported: s2,
+                },
+            ) => found == f2 && supported == s2,
+            (Error::Corruption { page_id }, Error::Corruption { page_id: p2 }) => page_id == p2,
+            (
+                Error::UnsupportedHashAlgorithm { id },
+                Error::UnsupportedHashAlgorithm { id: id2 },
+            ) => id == id2,
+            (Error::DatabaseClosed, Error::DatabaseClosed) => true,
+            (Error::WouldBlock, Error::WouldBlock) => true,
+            (Error::Timeout, Error::Timeout) => true,
+            (Error::Deadlock, Error::Deadlock) => true,
             _ => false,
         }
     }
@@ -82,6 +183,10 @@ mod tests {
             format!("{}", Error::ReadOnlyTx),
             "Cannot write in a read-only transaction"
         );
+        assert_eq!(
+            format!("{}", Error::ReadOnlyDB),
+            "Cannot write to a database opened read-only"
+        );
 
         assert_eq!(
             format!(
@@ -98,5 +203,44 @@ mod tests {
             format!("{}", Error::InvalidDB(String::from("uh oh"))),
             "Invalid DB: uh oh"
         );
+        assert_eq!(format!("{}", Error::CorruptMeta), "No valid meta page");
+        assert_eq!(
+            format!(
+                "{}",
+                Error::PagesizeMismatch {
+                    expected: 4096,
+                    found: 1024
+                }
+            ),
+            "Invalid pagesize: expected 4096, database has 1024"
+        );
+        assert_eq!(
+            format!("{}", Error::InvalidOption("abc")),
+            "Invalid option: abc"
+        );
+        assert_eq!(
+            format!("{}", Error::PageOutOfRange { page_id: 7 }),
+            "Page 7 is out of range"
+        );
+        assert_eq!(format!("{}", Error::NotADatabase), "File is not a database");
+        assert_eq!(
+            format!(
+                "{}",
+                Error::UnsupportedVersion {
+                    found: 3,
+                    supported: 1..=2
+                }
+            ),
+            "Unsupported version 3, supported versions are 1 to 2"
+        );
+        assert_eq!(
+            format!("{}", Error::Corruption { page_id: 7 }),
+            "Page 7 is corrupted"
+        );
+        assert_eq!(
+            format!("{}", Error::UnsupportedHashAlgorithm { id: 9 }),
+            "Unsupported meta hash algorithm 9"
+        );
+        assert_eq!(format!("{}", Error::DatabaseClosed), "Database is closed");
     }
 }
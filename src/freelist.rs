@@ -1,11 +1,12 @@
 use alloc::collections::{BTreeMap, BTreeSet};
 use alloc::vec::Vec;
 use core::alloc::Layout;
-use core::mem::size_of;
+use core::mem::{align_of, size_of};
 use core::ptr::NonNull;
 
 use bumpalo::Bump;
 
+use crate::endian::U64;
 use crate::meta::Meta;
 use crate::page::{Page, PageID};
 
@@ -29,7 +30,7 @@ impl<'a> TxFreelist {
     pub(crate) fn free(&mut self, page_id: PageID, num_pages: u64) {
         debug_assert!(num_pages > 0, "cannot free zero pages");
         for id in page_id..(page_id + num_pages) {
-            self.inner.free(self.meta.tx_id, id);
+            self.inner.free(self.meta.tx_id(), id);
         }
     }
 
@@ -41,30 +42,55 @@ impl<'a> TxFreelist {
             size_of::<Page>(),
             bytes < (size_of::<Page>() as u64)
         );
-        let num_pages = if (bytes % self.meta.pagesize) == 0 {
-            bytes / self.meta.pagesize
+        let num_pages = if (bytes % self.meta.pagesize()) == 0 {
+            bytes / self.meta.pagesize()
         } else {
-            (bytes / self.meta.pagesize) + 1
+            (bytes / self.meta.pagesize()) + 1
         };
         let page_id = match self.inner.allocate(num_pages as usize) {
             Some(page_id) => page_id,
             None => {
-                let page_id = self.meta.num_pages;
-                self.meta.num_pages += num_pages;
+                let page_id = self.meta.num_pages();
+                self.meta.set_num_pages(self.meta.num_pages() + num_pages);
                 page_id
             }
         };
         let ptr = self
             .arena
-            .alloc_layout(Layout::array::<u8>(bytes as usize).unwrap());
+            .alloc_layout(Layout::from_size_align(bytes as usize, align_of::<Page>()).unwrap());
+        // the whole block is written out, so start from zeroes rather than whatever the arena held
+        unsafe { ptr.as_ptr().write_bytes(0, bytes as usize) };
 
         let page = unsafe { &mut *(ptr.as_ptr() as *mut Page) };
-        page.id = page_id;
-        page.overflow = num_pages - 1;
+        page.set_id(page_id);
+        page.set_overflow(num_pages - 1);
         self.pages.insert(page_id, (ptr, bytes as usize));
 
         page
     }
+
+    pub(crate) fn snapshot(&self) -> FreelistSnapshot {
+        FreelistSnapshot {
+            meta: self.meta.clone(),
+            inner: self.inner.clone(),
+            pages: self.pages.keys().copied().collect(),
+        }
+    }
+
+    // Pages allocated since the snapshot are forgotten,
+    // but their memory stays in the arena until the transaction is dropped.
+    pub(crate) fn restore(&mut self, snapshot: FreelistSnapshot) {
+        self.meta = snapshot.meta;
+        self.inner = snapshot.inner;
+        self.pages.retain(|id, _| snapshot.pages.contains(id));
+    }
+}
+
+// The state of a transaction's freelist when a savepoint was taken.
+pub(crate) struct FreelistSnapshot {
+    meta: Meta,
+    inner: Freelist,
+    pages: BTreeSet<PageID>,
 }
 
 #[derive(Clone)]
@@ -84,9 +110,9 @@ impl Freelist {
         }
     }
 
-    pub(crate) fn init(&mut self, free_pages: &[PageID]) {
+    pub(crate) fn init(&mut self, free_pages: &[U64]) {
         free_pages.iter().for_each(|id| {
-            self.free_pages.insert(*id);
+            self.free_pages.insert(id.get());
         });
     }
 
@@ -178,7 +204,8 @@ mod tests {
             free_pages: v.iter().cloned().collect(),
             pending_pages: BTreeMap::new(),
         };
-        freelist.init(v.as_slice());
+        let ids: Vec<U64> = v.iter().map(|id| U64::new(*id)).collect();
+        freelist.init(&ids);
         freelist
     }
 
@@ -283,25 +310,32 @@ mod tests {
         let mut freelist = tx.freelist.borrow_mut();
         // make sure we have an empty freelist and only four pages
         assert_eq!(freelist.inner.pages().len(), 0);
-        assert_eq!(tx.meta.num_pages, 4);
+        assert_eq!(tx.meta.num_pages(), 4);
         // allocate one page worth of bytes
         let page = freelist.allocate(1024);
-        assert!(page.id == 4);
-        assert!(page.overflow == 0);
+        assert!(page.id() == 4);
+        assert!(page.overflow() == 0);
         // allocate a half page worth of bytes
         let page = freelist.allocate(512);
-        assert!(page.id == 5);
-        assert!(page.overflow == 0);
+        assert!(page.id() == 5);
+        assert!(page.overflow() == 0);
 
         // allocate ten pages worth of bytes
         let page = freelist.allocate(10240);
-        assert!(page.id == 6);
-        assert!(page.overflow == 9);
+        assert!(page.id() == 6);
+        assert!(page.overflow() == 9);
 
         // allocate a non pagesize number of bytes
         let page = freelist.allocate(1234);
-        assert!(page.id == 16);
-        assert!(page.overflow == 1);
+        assert!(page.id() == 16);
+        assert!(page.overflow() == 1);
+        // blocks are aligned for a page, and everything but the header fields set above is zero
+        let ptr = page as *const Page as *const u8;
+        assert_eq!(ptr as usize % align_of::<Page>(), 0);
+        let bytes = unsafe { core::slice::from_raw_parts(ptr, 1234) };
+        // the header is the id, type, padding, checksum, count and overflow
+        assert!(bytes[8..24].iter().all(|b| *b == 0));
+        assert!(bytes[32..].iter().all(|b| *b == 0));
 
         Ok(())
     }
@@ -322,26 +356,26 @@ mod tests {
             freelist.free(*page, 1);
         }
         freelist.inner.release(1);
-        freelist.meta.num_pages = 99;
+        freelist.meta.set_num_pages(99);
 
         // allocate one page worth of bytes (should come from freelist)
         let page = freelist.allocate(1024);
-        assert!(page.id == 10);
-        assert!(page.overflow == 0);
+        assert!(page.id() == 10);
+        assert!(page.overflow() == 0);
         // allocate a half page worth of bytes (should come from freelist)
         let page = freelist.allocate(512);
-        assert!(page.id == 11);
-        assert!(page.overflow == 0);
+        assert!(page.id() == 11);
+        assert!(page.overflow() == 0);
 
         // allocate three-ish pages worth of bytes (should come from freelist)
         let page = freelist.allocate(3000);
-        assert!(page.id == 13);
-        assert!(page.overflow == 2);
+        assert!(page.id() == 13);
+        assert!(page.overflow() == 2);
 
         // allocate a small number of bytes
         let page = freelist.allocate(100);
-        assert!(page.id == 99);
-        assert!(page.overflow == 0);
+        assert!(page.id() == 99);
+        assert!(page.overflow() == 0);
         Ok(())
     }
 
@@ -356,7 +390,7 @@ mod tests {
         let tx = tx.inner.borrow_mut();
         let mut freelist = tx.freelist.borrow_mut();
 
-        assert_eq!(tx.meta.tx_id, 0);
+        assert_eq!(tx.meta.tx_id(), 0);
         assert_eq!(freelist.inner.pages().len(), 0);
         freelist.free(80, 1);
         assert_eq!(freelist.inner.pages(), vec![80]);
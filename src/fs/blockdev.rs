@@ -0,0 +1,488 @@
+//! Database files on sector addressed block devices.
+//!
+//! [`BlockFile`] turns anything implementing [`BlockDevice`] into a [`DbFile`],
+//! so the database can run directly on a storage driver without a filesystem.
+//! The first block of the device holds a small header recording the file length,
+//! and the file's contents start at the second block.
+//! Pages are read through the same bounded cache as [`PreadMap`](super::pread::PreadMap),
+//! using [`BlockMap`] as the database's [`MemoryMap`].
+use crate::fs::pread::{PageCache, DEFAULT_CACHE_PAGES};
+use crate::fs::{DbFile, File, FileExt, IOResult, IndexByPageID, MemoryMap, MetaData};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core2::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use spin::Mutex;
+
+const HEADER_MAGIC: [u8; 8] = *b"JAMMBLK1";
+const HEADER_SIZE: usize = 16;
+
+/// A storage device that can only be read and written in whole blocks
+pub trait BlockDevice: Send + Sync {
+    /// size of one block in bytes
+    fn block_size(&self) -> usize;
+    /// number of blocks on the device
+    fn num_blocks(&self) -> u64;
+    /// reads block `block_id` into `buf`, which is exactly one block long
+    fn read_block(&self, block_id: u64, buf: &mut [u8]) -> IOResult<()>;
+    /// writes `buf`, which is exactly one block long, to block `block_id`
+    fn write_block(&self, block_id: u64, buf: &[u8]) -> IOResult<()>;
+    /// makes every completed write durable
+    fn flush(&self) -> IOResult<()>;
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for Arc<D> {
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+    fn num_blocks(&self) -> u64 {
+        (**self).num_blocks()
+    }
+    fn read_block(&self, block_id: u64, buf: &mut [u8]) -> IOResult<()> {
+        (**self).read_block(block_id, buf)
+    }
+    fn write_block(&self, block_id: u64, buf: &[u8]) -> IOResult<()> {
+        (**self).write_block(block_id, buf)
+    }
+    fn flush(&self) -> IOResult<()> {
+        (**self).flush()
+    }
+}
+
+struct Device<D> {
+    device: D,
+    len: Mutex<u64>,
+    // number of shared lock holders, or `EXCLUSIVE`
+    locks: AtomicUsize,
+}
+
+const EXCLUSIVE: usize = usize::MAX;
+
+impl<D: BlockDevice> Device<D> {
+    // number of bytes available to the file, excluding the header block
+    fn capacity(&self) -> u64 {
+        self.device.num_blocks().saturating_sub(1) * self.device.block_size() as u64
+    }
+
+    fn read_at(&self, offset: u64, mut buf: &mut [u8]) -> IOResult<()> {
+        let block_size = self.device.block_size();
+        let mut scratch = Vec::new();
+        // skip the header block
+        let mut offset = offset + block_size as u64;
+        while !buf.is_empty() {
+            let block_id = offset / block_size as u64;
+            let start = (offset % block_size as u64) as usize;
+            let n = (block_size - start).min(buf.len());
+            if n == block_size {
+                self.device.read_block(block_id, &mut buf[..n])?;
+            } else {
+                scratch.resize(block_size, 0);
+                self.device.read_block(block_id, &mut scratch)?;
+                buf[..n].copy_from_slice(&scratch[start..start + n]);
+            }
+            offset += n as u64;
+            buf = &mut buf[n..];
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, mut buf: &[u8]) -> IOResult<()> {
+        if offset + buf.len() as u64 > self.capacity() {
+            return Err(Error::new(ErrorKind::WriteZero, "block device is full"));
+        }
+        let block_size = self.device.block_size();
+        let mut scratch = Vec::new();
+        let mut offset = offset + block_size as u64;
+        while !buf.is_empty() {
+            let block_id = offset / block_size as u64;
+            let start = (offset % block_size as u64) as usize;
+            let n = (block_size - start).min(buf.len());
+            if n == block_size {
+                self.device.write_block(block_id, &buf[..n])?;
+            } else {
+                // only part of the block changes, so keep the rest of what is on the device
+                scratch.resize(block_size, 0);
+                self.device.read_block(block_id, &mut scratch)?;
+                scratch[start..start + n].copy_from_slice(&buf[..n]);
+                self.device.write_block(block_id, &scratch)?;
+            }
+            offset += n as u64;
+            buf = &buf[n..];
+        }
+        Ok(())
+    }
+
+    fn set_len(&self, len: &mut u64, new_len: u64) -> IOResult<()> {
+        let mut header = vec![0; self.device.block_size()];
+        header[..8].copy_from_slice(&HEADER_MAGIC);
+        header[8..HEADER_SIZE].copy_from_slice(&new_len.to_le_bytes());
+        self.device.write_block(0, &header)?;
+        *len = new_len;
+        Ok(())
+    }
+}
+
+/// A file stored on a [`BlockDevice`]
+///
+/// Cloning a `BlockFile` gives another handle onto the same file, with its own position.
+pub struct BlockFile<D> {
+    device: Arc<Device<D>>,
+    pos: u64,
+}
+
+impl<D: BlockDevice> BlockFile<D> {
+    /// Opens the file stored on `device`.
+    ///
+    /// A device without a header is treated as holding an empty file,
+    /// so a new database gets initialized on it.
+    /// Space given to the file by [`allocate`](FileExt::allocate) isn't zeroed.
+    pub fn open(device: D) -> IOResult<Self> {
+        let block_size = device.block_size();
+        if block_size < HEADER_SIZE || device.num_blocks() == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "block device is too small",
+            ));
+        }
+        let mut header = vec![0; block_size];
+        device.read_block(0, &mut header)?;
+        let len = if header[..8] == HEADER_MAGIC {
+            let mut len = [0; 8];
+            len.copy_from_slice(&header[8..HEADER_SIZE]);
+            u64::from_le_bytes(len)
+        } else {
+            0
+        };
+        Ok(BlockFile {
+            device: Arc::new(Device {
+                device,
+                len: Mutex::new(len),
+                locks: AtomicUsize::new(0),
+            }),
+            pos: 0,
+        })
+    }
+}
+
+impl<D> Clone for BlockFile<D> {
+    fn clone(&self) -> Self {
+        BlockFile {
+            device: self.device.clone(),
+            pos: 0,
+        }
+    }
+}
+
+impl<D: BlockDevice> Seek for BlockFile<D> {
+    fn seek(&mut self, pos: SeekFrom) -> IOResult<u64> {
+        let pos = match pos {
+            SeekFrom::Start(l) => Some(l),
+            SeekFrom::Current(l) => self.pos.checked_add_signed(l),
+            SeekFrom::End(l) => self.device.len.lock().checked_add_signed(l),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(Error::new(ErrorKind::InvalidInput, "seek error")),
+        }
+    }
+}
+
+impl<D: BlockDevice> Read for BlockFile<D> {
+    fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+        let len = *self.device.len.lock();
+        let n = len.saturating_sub(self.pos).min(buf.len() as u64) as usize;
+        self.device.read_at(self.pos, &mut buf[..n])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<D: BlockDevice> Write for BlockFile<D> {
+    fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+        let mut len = self.device.len.lock();
+        self.device.write_at(self.pos, buf)?;
+        self.pos += buf.len() as u64;
+        if self.pos > *len {
+            self.device.set_len(&mut len, self.pos)?;
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> IOResult<()> {
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> FileExt for BlockFile<D> {
+    /// blocks until no other handle holds the lock
+    fn lock_exclusive(&self) -> IOResult<()> {
+        while self
+            .device
+            .locks
+            .compare_exchange_weak(0, EXCLUSIVE, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+    /// blocks while another handle holds the exclusive lock
+    fn lock_shared(&self) -> IOResult<()> {
+        let mut locks = self.device.locks.load(Ordering::Relaxed);
+        loop {
+            // exclusively locked, or out of shared locks
+            if locks >= EXCLUSIVE - 1 {
+                core::hint::spin_loop();
+                locks = self.device.locks.load(Ordering::Relaxed);
+                continue;
+            }
+            match self.device.locks.compare_exchange_weak(
+                locks,
+                locks + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Ok(()),
+                Err(current) => locks = current,
+            }
+        }
+    }
+    fn allocate(&mut self, new_size: u64) -> IOResult<()> {
+        let mut len = self.device.len.lock();
+        if new_size <= *len {
+            return Ok(());
+        }
+        if new_size > self.device.capacity() {
+            return Err(Error::new(ErrorKind::WriteZero, "block device is full"));
+        }
+        self.device.set_len(&mut len, new_size)
+    }
+    fn unlock(&self) -> IOResult<()> {
+        let locks = &self.device.locks;
+        if locks
+            .compare_exchange(EXCLUSIVE, 0, Ordering::Release, Ordering::Relaxed)
+            .is_err()
+        {
+            // a shared lock, there's nothing to release if the device isn't locked at all
+            let _ = locks.fetch_update(Ordering::Release, Ordering::Relaxed, |n| n.checked_sub(1));
+        }
+        Ok(())
+    }
+    fn metadata(&self) -> IOResult<MetaData> {
+        Ok(MetaData {
+            len: *self.device.len.lock(),
+        })
+    }
+    /// flushes the device
+    fn sync_all(&self) -> IOResult<()> {
+        self.device.device.flush()
+    }
+    fn size(&self) -> usize {
+        *self.device.len.lock() as usize
+    }
+    /// a block device has no address, pages are read through [`BlockMap`]
+    fn addr(&self) -> usize {
+        0
+    }
+}
+
+impl<D: BlockDevice + 'static> DbFile for BlockFile<D> {}
+
+/// memory map for [`BlockFile`]s, caching the pages it reads
+pub struct BlockMap<D> {
+    capacity: usize,
+    device: PhantomData<fn() -> D>,
+}
+
+impl<D> BlockMap<D> {
+    /// Creates a map that caches up to [`DEFAULT_CACHE_PAGES`] pages.
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_CACHE_PAGES)
+    }
+
+    /// Creates a map that caches up to `capacity` pages.
+    pub fn with_capacity(capacity: usize) -> Self {
+        BlockMap {
+            capacity,
+            device: PhantomData,
+        }
+    }
+}
+
+impl<D> Default for BlockMap<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D: BlockDevice + 'static> MemoryMap for BlockMap<D> {
+    fn do_map(&self, file: &mut File) -> IOResult<Arc<dyn IndexByPageID>> {
+        let file = file.file.downcast_ref::<BlockFile<D>>().map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "BlockMap can only map a BlockFile on the same kind of device",
+            )
+        })?;
+        let len = file.metadata()?.len();
+        let reader = File::new(Box::new(file.clone()));
+        Ok(Arc::new(PageCache::new(
+            Arc::new(Mutex::new(reader)),
+            len,
+            self.capacity,
+        )))
+    }
+}
+
+/// A [`BlockDevice`] kept in memory
+pub struct RamDisk {
+    block_size: usize,
+    data: Mutex<Vec<u8>>,
+}
+
+impl RamDisk {
+    pub fn new(block_size: usize, num_blocks: u64) -> Self {
+        RamDisk {
+            block_size,
+            data: Mutex::new(vec![0; block_size * num_blocks as usize]),
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+    fn num_blocks(&self) -> u64 {
+        (self.data.lock().len() / self.block_size) as u64
+    }
+    fn read_block(&self, block_id: u64, buf: &mut [u8]) -> IOResult<()> {
+        let start = block_id as usize * self.block_size;
+        match self.data.lock().get(start..start + self.block_size) {
+            Some(block) => {
+                buf.copy_from_slice(block);
+                Ok(())
+            }
+            None => Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "block is out of range",
+            )),
+        }
+    }
+    fn write_block(&self, block_id: u64, buf: &[u8]) -> IOResult<()> {
+        let start = block_id as usize * self.block_size;
+        match self.data.lock().get_mut(start..start + self.block_size) {
+            Some(block) => {
+                block.copy_from_slice(buf);
+                Ok(())
+            }
+            None => Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "block is out of range",
+            )),
+        }
+    }
+    fn flush(&self) -> IOResult<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::Result;
+    use crate::OpenOptions;
+
+    #[test]
+    fn test_partial_writes() -> IOResult<()> {
+        let mut file = BlockFile::open(RamDisk::new(512, 8))?;
+        file.write_all(&[1; 700])?;
+        file.seek(SeekFrom::Start(510))?;
+        file.write_all(&[2; 4])?;
+        assert_eq!(file.metadata()?.len(), 700);
+
+        let mut buf = vec![0; 700];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut buf)?;
+        assert!(buf[..510].iter().all(|b| *b == 1));
+        assert_eq!(buf[510..514], [2; 4]);
+        assert!(buf[514..].iter().all(|b| *b == 1));
+        // reads stop at the end of the file, not the end of the device
+        assert_eq!(file.read(&mut buf)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_locks() -> IOResult<()> {
+        let file = BlockFile::open(RamDisk::new(512, 8))?;
+        file.lock_shared()?;
+        file.lock_shared()?;
+        assert_eq!(file.device.locks.load(Ordering::Relaxed), 2);
+        file.unlock()?;
+        file.unlock()?;
+        file.lock_exclusive()?;
+        assert_eq!(file.device.locks.load(Ordering::Relaxed), EXCLUSIVE);
+        file.unlock()?;
+        file.lock_shared()?;
+        assert_eq!(file.device.locks.load(Ordering::Relaxed), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_length_persists() -> IOResult<()> {
+        let disk = Arc::new(RamDisk::new(512, 8));
+        {
+            let mut file = BlockFile::open(disk.clone())?;
+            assert_eq!(file.metadata()?.len(), 0);
+            file.allocate(1024)?;
+            file.seek(SeekFrom::End(-3))?;
+            file.write_all(b"end")?;
+            // the header block is not part of the file
+            assert!(file.allocate(7 * 512 + 1).is_err());
+            file.seek(SeekFrom::Start(7 * 512 - 1))?;
+            assert!(file.write_all(b"ab").is_err());
+        }
+        let mut file = BlockFile::open(disk)?;
+        assert_eq!(file.metadata()?.len(), 1024);
+        let mut buf = [0; 3];
+        file.seek(SeekFrom::Start(1021))?;
+        file.read_exact(&mut buf)?;
+        assert_eq!(&buf, b"end");
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_db() -> Result<()> {
+        let disk = Arc::new(RamDisk::new(512, 20 * 1024));
+        let open = |disk: &Arc<RamDisk>| -> Result<crate::DB> {
+            let file = File::new(Box::new(BlockFile::open(disk.clone())?));
+            OpenOptions::new()
+                .pagesize(1024)
+                .num_pages(8)
+                .open_file(Arc::new(BlockMap::<Arc<RamDisk>>::with_capacity(8)), file)
+        };
+        {
+            let db = open(&disk)?;
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..1000_u64 {
+                b.put(i.to_be_bytes(), i.to_string())?;
+            }
+            tx.commit()?;
+            db.check()?;
+        }
+        let db = open(&disk)?;
+        let tx = db.tx(false)?;
+        let b = tx.get_bucket("abc")?;
+        for (i, kv) in b.kv_pairs().enumerate() {
+            assert_eq!(kv.key(), (i as u64).to_be_bytes());
+            assert_eq!(kv.value(), i.to_string().as_bytes());
+        }
+        assert_eq!(b.kv_pairs().count(), 1000);
+        Ok(())
+    }
+}
@@ -0,0 +1,304 @@
+//! File and memory map wrappers that inject faults, for crash consistency testing.
+//!
+//! [`FaultyFile`] wraps another database file and, depending on its [`Faults`], fails writes,
+//! silently drops them, tears a write part way through, fails `sync_all`, or keeps track of the
+//! writes that haven't been synced. Dropping a `FaultyFile` simulates a power loss: writes that were
+//! never synced are either lost or land on the underlying file in an arbitrary order.
+//! Reopening the underlying file afterwards shows what would have survived the crash.
+//!
+//! The database has to be opened with [`OpenOptions::open_file`](crate::OpenOptions::open_file),
+//! with a [`FaultyMap`] wrapping the memory map for the underlying file.
+//!
+//! Only built with the `fault-injection` feature.
+use crate::fs::{DbFile, File, FileExt, IOResult, IndexByPageID, MemoryMap, MetaData};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core2::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use spin::Mutex;
+
+/// What happens to writes once the byte budget set with [`Faults::write_budget`] runs out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteFault {
+    /// The write that would go over the budget, and every write after it, returns an error.
+    Fail,
+    /// The write that would go over the budget, and every write after it,
+    /// reports success without writing anything.
+    Drop,
+    /// The write that goes over the budget is cut off at the budget,
+    /// and every write after it reports success without writing anything.
+    Tear,
+}
+
+#[derive(Default)]
+struct FaultState {
+    budget: Option<(u64, WriteFault)>,
+    written: u64,
+    syncs_left: Option<u64>,
+    syncs: u64,
+    reorder_seed: Option<u64>,
+}
+
+/// The faults to inject into a [`FaultyFile`]
+///
+/// Cloning `Faults` gives another handle onto the same settings and counters,
+/// so a test can keep one to inspect or change them after opening the database.
+#[derive(Clone, Default)]
+pub struct Faults {
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl Faults {
+    /// Creates settings that don't inject any faults.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows `bytes` more bytes to be written before `fault` kicks in.
+    pub fn write_budget(&self, bytes: u64, fault: WriteFault) -> &Self {
+        let mut state = self.state.lock();
+        state.budget = Some((state.written + bytes, fault));
+        self
+    }
+
+    /// Makes every call to `sync_all` after the next `syncs` calls fail,
+    /// leaving the writes since the last successful sync unsynced.
+    pub fn fail_sync_after(&self, syncs: u64) -> &Self {
+        self.state.lock().syncs_left = Some(syncs);
+        self
+    }
+
+    /// Keeps track of the writes made since the last `sync_all`.
+    ///
+    /// Like the operating system's page cache, writes are visible straight away, through the file
+    /// and its memory map. When the file is dropped, each write that was never synced is either
+    /// lost or applied, in an order picked from `seed`.
+    pub fn reorder_unsynced(&self, seed: u64) -> &Self {
+        self.state.lock().reorder_seed = Some(seed);
+        self
+    }
+
+    /// Number of bytes written so far, including any that were dropped.
+    pub fn bytes_written(&self) -> u64 {
+        self.state.lock().written
+    }
+
+    /// Number of calls to `sync_all` so far.
+    pub fn syncs(&self) -> u64 {
+        self.state.lock().syncs
+    }
+}
+
+/// A database file that injects the configured [`Faults`]
+pub struct FaultyFile {
+    // behind a lock so the file can be read and written through a shared reference
+    inner: Mutex<File>,
+    // writes since the last sync, so a crash can undo them
+    unsynced: Mutex<Vec<Unsynced>>,
+    faults: Faults,
+    pos: u64,
+}
+
+impl FaultyFile {
+    pub fn new(inner: File, faults: Faults) -> Self {
+        FaultyFile {
+            inner: Mutex::new(inner),
+            unsynced: Mutex::new(Vec::new()),
+            faults,
+            pos: 0,
+        }
+    }
+
+    fn write_through(&self, offset: u64, buf: &[u8]) -> IOResult<()> {
+        let mut inner = self.inner.lock();
+        inner.seek(SeekFrom::Start(offset))?;
+        inner.write_all(buf)
+    }
+
+    fn read_at(&self, offset: u64, len: usize) -> IOResult<Vec<u8>> {
+        let mut inner = self.inner.lock();
+        let mut buf = vec![0; len];
+        inner.seek(SeekFrom::Start(offset))?;
+        let mut read = 0;
+        while read < len {
+            match inner.read(&mut buf[read..])? {
+                0 => break,
+                r => read += r,
+            }
+        }
+        buf.truncate(read);
+        Ok(buf)
+    }
+}
+
+struct Unsynced {
+    offset: u64,
+    // the bytes the write replaced, and the bytes it wrote
+    old: Vec<u8>,
+    new: Vec<u8>,
+}
+
+impl Drop for FaultyFile {
+    // The process "loses power": writes that were never synced either make it to the file or not.
+    fn drop(&mut self) {
+        let seed = match self.faults.state.lock().reorder_seed {
+            Some(seed) => seed,
+            None => return,
+        };
+        let mut unsynced = core::mem::take(self.unsynced.get_mut());
+        // xorshift, so the same seed always loses the same writes
+        let mut rng = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+        let mut next = || {
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            rng
+        };
+        // put the file back the way it was at the last sync, then replay some of the writes
+        for write in unsynced.iter().rev() {
+            let _ = self.write_through(write.offset, &write.old);
+        }
+        for i in (1..unsynced.len()).rev() {
+            unsynced.swap(i, next() as usize % (i + 1));
+        }
+        for write in unsynced {
+            if next() % 2 == 0 {
+                let _ = self.write_through(write.offset, &write.new);
+            }
+        }
+    }
+}
+
+impl Seek for FaultyFile {
+    fn seek(&mut self, pos: SeekFrom) -> IOResult<u64> {
+        let pos = match pos {
+            SeekFrom::Start(l) => Some(l),
+            SeekFrom::Current(l) => self.pos.checked_add_signed(l),
+            SeekFrom::End(l) => self.metadata()?.len().checked_add_signed(l),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(Error::new(ErrorKind::InvalidInput, "seek error")),
+        }
+    }
+}
+
+impl Read for FaultyFile {
+    fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+        let len = self.metadata()?.len();
+        let n = len.saturating_sub(self.pos).min(buf.len() as u64) as usize;
+        let data = self.read_at(self.pos, n)?;
+        let buf = &mut buf[..n];
+        buf.fill(0);
+        buf[..data.len()].copy_from_slice(&data);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for FaultyFile {
+    fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+        let (keep, reorder) = {
+            let mut state = self.faults.state.lock();
+            let start = state.written;
+            state.written += buf.len() as u64;
+            let keep = match state.budget {
+                Some((budget, fault)) if state.written > budget => {
+                    let left = budget.saturating_sub(start) as usize;
+                    match fault {
+                        WriteFault::Fail => {
+                            return Err(Error::new(ErrorKind::Other, "injected write failure"))
+                        }
+                        WriteFault::Drop => 0,
+                        WriteFault::Tear => left,
+                    }
+                }
+                _ => buf.len(),
+            };
+            (keep, state.reorder_seed.is_some())
+        };
+        if keep > 0 {
+            if reorder {
+                let old = self.read_at(self.pos, keep)?;
+                self.unsynced.lock().push(Unsynced {
+                    offset: self.pos,
+                    old,
+                    new: buf[..keep].to_vec(),
+                });
+            }
+            self.write_through(self.pos, &buf[..keep])?;
+        }
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IOResult<()> {
+        self.inner.lock().flush()
+    }
+}
+
+impl FileExt for FaultyFile {
+    fn lock_exclusive(&self) -> IOResult<()> {
+        self.inner.lock().lock_exclusive()
+    }
+    fn lock_shared(&self) -> IOResult<()> {
+        self.inner.lock().lock_shared()
+    }
+    fn allocate(&mut self, new_size: u64) -> IOResult<()> {
+        self.inner.get_mut().allocate(new_size)
+    }
+    fn unlock(&self) -> IOResult<()> {
+        self.inner.lock().unlock()
+    }
+    fn metadata(&self) -> IOResult<MetaData> {
+        self.inner.lock().metadata()
+    }
+    fn sync_all(&self) -> IOResult<()> {
+        {
+            let mut state = self.faults.state.lock();
+            state.syncs += 1;
+            match state.syncs_left {
+                Some(0) => return Err(Error::new(ErrorKind::Other, "injected sync failure")),
+                Some(ref mut left) => *left -= 1,
+                None => (),
+            }
+        }
+        self.inner.lock().sync_all()?;
+        self.unsynced.lock().clear();
+        Ok(())
+    }
+    fn size(&self) -> usize {
+        self.metadata().map(|m| m.len() as usize).unwrap_or(0)
+    }
+    fn addr(&self) -> usize {
+        self.inner.lock().addr()
+    }
+}
+
+impl DbFile for FaultyFile {}
+
+/// memory map for [`FaultyFile`]s, mapping the file they wrap with another memory map
+pub struct FaultyMap {
+    inner: Arc<dyn MemoryMap>,
+}
+
+impl FaultyMap {
+    pub fn new(inner: Arc<dyn MemoryMap>) -> Self {
+        FaultyMap { inner }
+    }
+}
+
+impl MemoryMap for FaultyMap {
+    fn do_map(&self, file: &mut File) -> IOResult<Arc<dyn IndexByPageID>> {
+        let file = file.file.downcast_mut::<FaultyFile>().map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "FaultyMap can only map a FaultyFile",
+            )
+        })?;
+        self.inner.do_map(file.inner.get_mut())
+    }
+}
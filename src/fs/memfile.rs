@@ -1,48 +1,385 @@
 use crate::fs::{DbFile, File, FileExt, IOResult, MemoryMap, MetaData, OpenOption, PathLike};
+use alloc::alloc::{alloc, dealloc, handle_alloc_error};
 use alloc::alloc::{realloc, Layout};
 use alloc::boxed::Box;
 use alloc::string::{String, ToString};
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use core::ops::Add;
-use alloc::alloc::alloc;
+use core::fmt::{Debug, Formatter};
 
-use crate::{IndexByPageID};
-use core2::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use crate::IndexByPageID;
+use core2::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
 use hashbrown::HashMap;
 use lazy_static::lazy_static;
 use spin::Mutex;
 
 lazy_static! {
-    pub static ref FILE_S: Mutex<HashMap<String, MemoryFile>> = Mutex::new(HashMap::new());
+    static ref DEFAULT_FS: MemFs = MemFs::new();
 }
 
-#[derive(Debug, Clone)]
+/// One allocation holding the contents of a file
+struct Block {
+    addr: usize,
+    size: usize,
+}
+
+impl Block {
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size, 4096).unwrap()
+    }
+
+    /// grow the block to `new_size` bytes in place, zeroing the new space
+    fn grow(&mut self, new_size: usize) {
+        let r = unsafe {
+            if self.addr == 0 {
+                alloc(Block::layout(new_size))
+            } else {
+                realloc(self.addr as *mut u8, Block::layout(self.size), new_size)
+            }
+        };
+        if r.is_null() {
+            handle_alloc_error(Block::layout(new_size));
+        }
+        unsafe { r.add(self.size).write_bytes(0, new_size - self.size) };
+        self.addr = r as usize;
+        self.size = new_size;
+    }
+
+    fn bytes(&self) -> &[u8] {
+        if self.addr == 0 {
+            return &[];
+        }
+        unsafe { core::slice::from_raw_parts(self.addr as *const u8, self.size) }
+    }
+}
+
+impl Drop for Block {
+    fn drop(&mut self) {
+        if self.addr != 0 {
+            unsafe { dealloc(self.addr as *mut u8, Block::layout(self.size)) };
+        }
+    }
+}
+
+/// The contents of one file
+///
+/// Mappings share the block, so a file that grows while it is mapped moves to a new block
+/// and leaves the old one to the mappings, the way an old mmap stays valid when a file grows.
+struct Buffer {
+    block: Arc<Block>,
+}
+
+impl Buffer {
+    fn new() -> Self {
+        Buffer {
+            block: Arc::new(Block { addr: 0, size: 0 }),
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let mut buffer = Buffer::new();
+        buffer.grow(bytes.len());
+        buffer.bytes_mut().copy_from_slice(bytes);
+        buffer
+    }
+
+    fn size(&self) -> usize {
+        self.block.size
+    }
+
+    /// grow the buffer to `new_size` bytes, zeroing the new space
+    fn grow(&mut self, new_size: usize) {
+        if new_size <= self.size() {
+            return;
+        }
+        match Arc::get_mut(&mut self.block) {
+            Some(block) => block.grow(new_size),
+            None => {
+                let mut block = Block { addr: 0, size: 0 };
+                block.grow(new_size);
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        self.block.addr as *const u8,
+                        block.addr as *mut u8,
+                        self.block.size,
+                    )
+                };
+                self.block = Arc::new(block);
+            }
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        self.block.bytes()
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        if self.block.addr == 0 {
+            return &mut [];
+        }
+        // mapped blocks are written in place, like a file under an mmap
+        unsafe { core::slice::from_raw_parts_mut(self.block.addr as *mut u8, self.block.size) }
+    }
+
+    /// the current contents, kept alive for as long as the mapping needs them
+    fn map(&self) -> Arc<Block> {
+        self.block.clone()
+    }
+}
+
+type FileData = Arc<Mutex<Buffer>>;
+
+const IMAGE_MAGIC: [u8; 8] = *b"JAMMFS01";
+// Longest file name accepted from an image
+const MAX_NAME_LEN: u64 = 4096;
+
+// Reads part of an image, reporting an image that ends early as invalid data
+fn read_image<R: Read + ?Sized>(r: &mut R, buf: &mut [u8]) -> IOResult<()> {
+    r.read_exact(buf).map_err(|e| match e.kind() {
+        ErrorKind::UnexpectedEof => Error::new(ErrorKind::InvalidData, "MemFs image is truncated"),
+        _ => e,
+    })
+}
+
+// Reads `len` bytes of an image a chunk at a time, so a corrupt length runs out of image
+// instead of allocating all of it up front
+fn read_chunked<R: Read + ?Sized>(r: &mut R, len: u64) -> IOResult<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut chunk = [0; 4096];
+    while (data.len() as u64) < len {
+        let n = (len - data.len() as u64).min(chunk.len() as u64) as usize;
+        read_image(r, &mut chunk[..n])?;
+        data.extend_from_slice(&chunk[..n]);
+    }
+    Ok(data)
+}
+
+/// An in-memory filesystem
+///
+/// Each `MemFs` owns its own set of files, so files with the same name in
+/// different instances don't affect each other. Cloning a `MemFs` gives another
+/// handle onto the same files.
+/// [`MemFs::global`] is the filesystem used by [`FileOpenOptions::new`] and by
+/// paths that check whether they exist on their own.
+#[derive(Clone, Default)]
+pub struct MemFs {
+    files: Arc<Mutex<HashMap<String, FileData>>>,
+}
+
+impl MemFs {
+    /// Creates an empty filesystem.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the filesystem shared by the whole program.
+    pub fn global() -> Self {
+        DEFAULT_FS.clone()
+    }
+
+    /// Returns options that open files in this filesystem.
+    pub fn options(&self) -> FileOpenOptions {
+        FileOpenOptions {
+            fs: self.clone(),
+            create: false,
+        }
+    }
+
+    /// Opens an existing file.
+    pub fn open<T: PathLike>(&self, path: &T) -> IOResult<MemoryFile> {
+        let name = path.to_path_string();
+        match self.files.lock().get(&name) {
+            Some(data) => Ok(MemoryFile::new(name, data.clone())),
+            None => Err(Error::new(ErrorKind::NotFound, "file not found")),
+        }
+    }
+
+    /// Opens a file, creating an empty one if it doesn't exist.
+    pub fn create<T: PathLike>(&self, path: &T) -> IOResult<MemoryFile> {
+        let name = path.to_path_string();
+        let data = self
+            .files
+            .lock()
+            .entry(name.clone())
+            .or_insert_with(|| Arc::new(Mutex::new(Buffer::new())))
+            .clone();
+        Ok(MemoryFile::new(name, data))
+    }
+
+    /// Removes a file.
+    ///
+    /// Handles that are still open keep the file's contents alive until they are dropped.
+    pub fn remove<T: PathLike>(&self, path: &T) -> IOResult<()> {
+        match self.files.lock().remove(&path.to_path_string()) {
+            Some(_) => Ok(()),
+            None => Err(Error::new(ErrorKind::NotFound, "file not found")),
+        }
+    }
+
+    /// Renames a file, replacing `to` if it already exists.
+    pub fn rename<T: PathLike, U: PathLike>(&self, from: &T, to: &U) -> IOResult<()> {
+        let mut files = self.files.lock();
+        match files.remove(&from.to_path_string()) {
+            Some(data) => {
+                files.insert(to.to_path_string(), data);
+                Ok(())
+            }
+            None => Err(Error::new(ErrorKind::NotFound, "file not found")),
+        }
+    }
+
+    /// Returns the names of all files, sorted.
+    pub fn list(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.files.lock().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Returns the length of a file in bytes.
+    pub fn len<T: PathLike>(&self, path: &T) -> IOResult<u64> {
+        match self.files.lock().get(&path.to_path_string()) {
+            Some(data) => Ok(data.lock().size() as u64),
+            None => Err(Error::new(ErrorKind::NotFound, "file not found")),
+        }
+    }
+
+    /// Checks whether a file exists.
+    pub fn exists<T: PathLike>(&self, path: &T) -> bool {
+        self.files.lock().contains_key(&path.to_path_string())
+    }
+
+    /// Creates a file holding everything read from `r`, replacing any file already at `path`.
+    ///
+    /// `r` can be an image written by [`MemoryFile::export`], or a database file from any other backend.
+    pub fn import_file<T: PathLike, R: Read + ?Sized>(
+        &self,
+        path: &T,
+        r: &mut R,
+    ) -> IOResult<MemoryFile> {
+        let mut contents = Vec::new();
+        let mut chunk = [0; 4096];
+        loop {
+            let n = match r.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            contents.extend_from_slice(&chunk[..n]);
+        }
+        Ok(self.insert(path.to_path_string(), Buffer::from_bytes(&contents)))
+    }
+
+    /// Writes an image of every file to `w`, which [`import`](#method.import) can load back.
+    ///
+    /// Files that are being written to while exporting may be copied half written,
+    /// so don't export a database while it has an open writable transaction.
+    pub fn export<W: Write + ?Sized>(&self, w: &mut W) -> IOResult<()> {
+        let files: Vec<(String, FileData)> = self
+            .files
+            .lock()
+            .iter()
+            .map(|(name, data)| (name.clone(), data.clone()))
+            .collect();
+        w.write_all(&IMAGE_MAGIC)?;
+        w.write_all(&(files.len() as u64).to_le_bytes())?;
+        for (name, data) in files {
+            w.write_all(&(name.len() as u64).to_le_bytes())?;
+            w.write_all(name.as_bytes())?;
+            let data = data.lock();
+            w.write_all(&(data.size() as u64).to_le_bytes())?;
+            w.write_all(data.bytes())?;
+        }
+        w.flush()
+    }
+
+    /// Loads the files in an image written by [`export`](#method.export),
+    /// replacing any files with the same names.
+    ///
+    /// An image that is truncated or corrupt returns an [`ErrorKind::InvalidData`] error,
+    /// and doesn't add any files.
+    pub fn import<R: Read + ?Sized>(&self, r: &mut R) -> IOResult<()> {
+        let mut magic = [0; 8];
+        read_image(r, &mut magic)?;
+        if magic != IMAGE_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "not a MemFs image"));
+        }
+        let read_u64 = |r: &mut R| -> IOResult<u64> {
+            let mut buf = [0; 8];
+            read_image(r, &mut buf)?;
+            Ok(u64::from_le_bytes(buf))
+        };
+        let count = read_u64(r)?;
+        // read everything before adding any files, so a truncated image changes nothing
+        let mut files = Vec::new();
+        for _ in 0..count {
+            let len = read_u64(r)?;
+            if len > MAX_NAME_LEN {
+                return Err(Error::new(ErrorKind::InvalidData, "file name is too long"));
+            }
+            let name = String::from_utf8(read_chunked(r, len)?)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "file name is not utf-8"))?;
+            let len = read_u64(r)?;
+            let buffer = Buffer::from_bytes(&read_chunked(r, len)?);
+            files.push((name, buffer));
+        }
+        for (name, buffer) in files {
+            self.insert(name, buffer);
+        }
+        Ok(())
+    }
+
+    fn insert(&self, name: String, buffer: Buffer) -> MemoryFile {
+        let data = Arc::new(Mutex::new(buffer));
+        self.files.lock().insert(name.clone(), data.clone());
+        MemoryFile::new(name, data)
+    }
+}
+
+impl Debug for MemFs {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MemFs")
+            .field("files", &self.list())
+            .finish()
+    }
+}
+
+/// A handle onto a file in a [`MemFs`]
+///
+/// Every handle onto the same file shares its contents, but has its own position.
+#[derive(Clone)]
 pub struct MemoryFile {
     pub name: String,
     pub pos: usize,
-    pub data: Vec<u8>,
-    pub addr: usize,
-    pub size: usize,
+    data: FileData,
+}
+
+impl Debug for MemoryFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MemoryFile")
+            .field("name", &self.name)
+            .field("pos", &self.pos)
+            .field("size", &self.size())
+            .finish()
+    }
 }
 
 impl Seek for MemoryFile {
     /// seek
     fn seek(&mut self, pos: SeekFrom) -> IOResult<u64> {
         //info!("seek: {:?}", pos);
+        let pos = match pos {
+            SeekFrom::Start(l) => Some(l as usize),
+            SeekFrom::Current(l) => self.pos.checked_add_signed(l as isize),
+            SeekFrom::End(l) => self.size().checked_add_signed(l as isize),
+        };
         match pos {
-            SeekFrom::Start(l) => self.pos = l as usize,
-            SeekFrom::Current(l) => self.pos += l as usize,
-            SeekFrom::End(l) => {
-                if l.unsigned_abs() as usize > self.size {
-                    return Err(core2::io::Error::new(ErrorKind::Other, "seek error"));
-                } else {
-                    self.pos += l as usize;
-                }
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos as u64)
             }
-        };
-        FILE_S.lock().get_mut(self.name.as_str()).unwrap().pos = self.pos;
-        Ok(self.pos as u64)
+            None => Err(Error::new(ErrorKind::Other, "seek error")),
+        }
     }
 }
 
@@ -50,18 +387,12 @@ impl Read for MemoryFile {
     /// read
     fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
         //info!("read buf len: {}", buf.len());
-        let act_size = self.size.saturating_sub(self.pos);
-        let act_size = if act_size > buf.len() {
-            buf.len()
-        } else {
-            act_size
-        };
-        let addr = unsafe { (self.addr as *const u8).add(self.pos) };
-        unsafe {
-            core::ptr::copy(addr, buf.as_mut_ptr(), act_size);
+        let data = self.data.lock();
+        let act_size = data.size().saturating_sub(self.pos).min(buf.len());
+        if act_size > 0 {
+            buf[..act_size].copy_from_slice(&data.bytes()[self.pos..self.pos + act_size]);
         }
         self.pos += act_size;
-        FILE_S.lock().get_mut(self.name.as_str()).unwrap().pos = self.pos;
         Ok(act_size)
     }
 }
@@ -70,22 +401,11 @@ impl Write for MemoryFile {
     /// write
     fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
         //info!("write buf len: {}", buf.len());
+        let mut data = self.data.lock();
         let act_size = buf.len() + self.pos;
-        if act_size > self.size {
-            let r = unsafe {
-                realloc(
-                    self.addr as *mut u8,
-                    Layout::from_size_align(self.size, 4096).unwrap(),
-                    act_size,
-                )
-            };
-            self.addr = r as usize;
-            self.size = act_size;
-        }
-        let data = unsafe { core::slice::from_raw_parts_mut(self.addr as *mut u8, act_size) };
-        data[self.pos..act_size].copy_from_slice(buf);
+        data.grow(act_size);
+        data.bytes_mut()[self.pos..act_size].copy_from_slice(buf);
         self.pos += buf.len();
-        FILE_S.lock().insert(self.name.clone(), self.clone());
         Ok(buf.len())
     }
     /// flush
@@ -94,14 +414,18 @@ impl Write for MemoryFile {
     }
 }
 
-#[allow(unused)]
 /// 文件读取
-pub struct FileOpenOptions;
+///
+/// Opens files in a [`MemFs`], the global one unless created with [`MemFs::options`].
+pub struct FileOpenOptions {
+    fs: MemFs,
+    create: bool,
+}
 
 impl OpenOption for FileOpenOptions {
     /// new
     fn new() -> Self {
-        FileOpenOptions
+        MemFs::global().options()
     }
     /// set the read
     fn read(&mut self, _: bool) -> &mut Self {
@@ -112,39 +436,43 @@ impl OpenOption for FileOpenOptions {
         self
     }
     /// open file
-    fn open<T: ToString + PathLike>(&mut self, path: &T) -> IOResult<File> {
-        let file = MemoryFile::open(path);
-
-        match file {
-            Some(f) => Ok(File::new(Box::new(f))),
-            None => Err(core2::io::Error::new(ErrorKind::Other, "open file error")),
-        }
+    fn open<T: PathLike>(&mut self, path: &T) -> IOResult<File> {
+        let file = if self.create {
+            self.fs.create(path)?
+        } else {
+            self.fs.open(path)?
+        };
+        Ok(File::new(Box::new(file)))
     }
     /// create file
-    fn create(&mut self, _: bool) -> &mut Self {
+    fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
         self
     }
+    /// check the filesystem these options open files in
+    fn exists<T: PathLike>(&self, path: &T) -> bool {
+        self.fs.exists(path)
+    }
 }
 
 impl MemoryFile {
-    /// create or get file
-    pub fn open<T: PathLike + ToString>(name: &T) -> Option<Self> {
+    fn new(name: String, data: FileData) -> Self {
+        MemoryFile { name, pos: 0, data }
+    }
+
+    /// Writes the contents of the file to `w`.
+    ///
+    /// The image is the database file byte for byte, so it can be loaded back with
+    /// [`MemFs::import_file`] or written out as a file for any other backend.
+    pub fn export<W: Write + ?Sized>(&self, w: &mut W) -> IOResult<()> {
+        w.write_all(self.data.lock().bytes())?;
+        w.flush()
+    }
+
+    /// create or get file in the global [`MemFs`]
+    pub fn open<T: PathLike>(name: &T) -> Option<Self> {
         //info!("open file {}", name);
-        if FILE_S.lock().get(&name.to_string()).is_some() {
-            let mut file = FILE_S.lock().get(&name.to_string()).unwrap().clone();
-            file.pos = 0;
-            FILE_S.lock().insert(name.to_string(), file.clone());
-            return Some(file);
-        }
-        let file = Self {
-            name: name.to_string(),
-            pos: 0,
-            data: Vec::new(),
-            addr: 0,
-            size: 0,
-        };
-        FILE_S.lock().insert(name.to_string(), file.clone());
-        Some(file)
+        MemFs::global().create(name).ok()
     }
 }
 
@@ -153,27 +481,12 @@ impl FileExt for MemoryFile {
     fn lock_exclusive(&self) -> IOResult<()> {
         Ok(())
     }
+    fn lock_shared(&self) -> IOResult<()> {
+        Ok(())
+    }
     /// 扩展大小
     fn allocate(&mut self, new_size: u64) -> IOResult<()> {
-        if self.size > new_size as usize {
-            return Ok(());
-        }
-        let r = unsafe {
-            if self.addr == 0{
-                alloc(Layout::from_size_align(new_size as usize, 4096).unwrap())
-            }
-            else {
-                realloc(
-                    self.addr as *mut u8,
-                    Layout::from_size_align(self.size, 4096).unwrap(),
-                    new_size as usize,
-                )
-            }
-        };
-        self.size = new_size as usize;
-        self.addr = r as usize;
-
-        FILE_S.lock().insert(self.name.clone(), self.clone());
+        self.data.lock().grow(new_size as usize);
         Ok(())
     }
     fn unlock(&self) -> IOResult<()> {
@@ -183,7 +496,7 @@ impl FileExt for MemoryFile {
     /// get the metadata
     fn metadata(&self) -> IOResult<MetaData> {
         let data = MetaData {
-            len: self.size as u64,
+            len: self.size() as u64,
         };
         Ok(data)
     }
@@ -194,11 +507,11 @@ impl FileExt for MemoryFile {
     }
 
     fn size(&self) -> usize {
-        self.size
+        self.data.lock().size()
     }
 
     fn addr(&self) -> usize {
-        self.addr
+        self.data.lock().block.addr
     }
 }
 
@@ -206,13 +519,19 @@ impl DbFile for MemoryFile {}
 
 impl PathLike for &str {
     fn exists(&self) -> bool {
-        FILE_S.lock().contains_key(self.to_string().as_str())
+        DEFAULT_FS.exists(self)
+    }
+    fn to_path_string(&self) -> String {
+        self.to_string()
     }
 }
 
 impl PathLike for &String {
     fn exists(&self) -> bool {
-        FILE_S.lock().contains_key(self.as_str())
+        DEFAULT_FS.exists(self)
+    }
+    fn to_path_string(&self) -> String {
+        self.to_string()
     }
 }
 
@@ -222,30 +541,206 @@ pub struct FakeMap;
 
 impl MemoryMap for FakeMap {
     fn do_map(&self, file: &mut File) -> IOResult<Arc<dyn IndexByPageID>> {
+        let file = file.file.downcast_ref::<MemoryFile>().map_err(|_| {
+            Error::new(ErrorKind::InvalidInput, "FakeMap can only map a MemoryFile")
+        })?;
         let t = IndexByPageIDImpl {
-            size: file.size(),
-            addr: file.addr(),
+            block: file.data.lock().map(),
         };
         Ok(Arc::new(t))
     }
 }
 
 struct IndexByPageIDImpl {
-    size: usize,
-    addr: usize,
+    block: Arc<Block>,
 }
 
 impl IndexByPageID for IndexByPageIDImpl {
-    fn index(&self, page_id: u64, page_size: usize) -> IOResult<&[u8]> {
-        if (page_size * page_id as usize) > self.size {
-            panic!("index is out of range");
+    fn index_range(&self, page_id: u64, count: u64, page_size: usize) -> IOResult<&[u8]> {
+        let start = page_id as usize * page_size;
+        match self
+            .block
+            .bytes()
+            .get(start..start + page_size * count as usize)
+        {
+            Some(data) => Ok(data),
+            None => Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "index is out of range",
+            )),
         }
-        let addr = self.addr.add(page_id as usize * page_size);
-        let data = unsafe { core::slice::from_raw_parts(addr as *const u8, page_size) };
-        Ok(data)
     }
 
     fn len(&self) -> usize {
-        self.size
+        self.block.size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::Result;
+    use crate::OpenOptions;
+
+    #[test]
+    fn test_mem_fs() -> IOResult<()> {
+        let fs = MemFs::new();
+        assert!(fs.open(&"a").is_err());
+        let mut a = fs.create(&"a")?;
+        a.write_all(b"hello")?;
+        assert_eq!(fs.len(&"a")?, 5);
+        // another handle sees the same contents
+        let mut buf = [0; 5];
+        fs.open(&"a")?.read_exact(&mut buf)?;
+        assert_eq!(&buf, b"hello");
+
+        fs.create(&"b")?;
+        assert_eq!(fs.list(), ["a", "b"]);
+        fs.rename(&"a", &"b")?;
+        assert_eq!(fs.list(), ["b"]);
+        assert_eq!(fs.len(&"b")?, 5);
+        fs.remove(&"b")?;
+        assert!(fs.list().is_empty());
+        assert!(fs.remove(&"b").is_err());
+        assert!(fs.rename(&"a", &"b").is_err());
+        // removed files stay readable through open handles
+        a.seek(SeekFrom::Start(0))?;
+        a.read_exact(&mut buf)?;
+        assert_eq!(&buf, b"hello");
+        Ok(())
+    }
+
+    #[test]
+    fn test_isolated_fs() -> Result<()> {
+        let (fs1, fs2) = (MemFs::new(), MemFs::new());
+        for (fs, value) in [(&fs1, "one"), (&fs2, "two")] {
+            let db = OpenOptions::new().pagesize(1024).num_pages(4).open_with(
+                Arc::new(FakeMap),
+                fs.options(),
+                "isolated.db",
+            )?;
+            let tx = db.tx(true)?;
+            tx.get_or_create_bucket("abc")?.put("key", value)?;
+            tx.commit()?;
+        }
+        assert!(!"isolated.db".exists());
+        for (fs, value) in [(&fs1, "one"), (&fs2, "two")] {
+            let db = OpenOptions::new().pagesize(1024).open_with(
+                Arc::new(FakeMap),
+                fs.options(),
+                "isolated.db",
+            )?;
+            let tx = db.tx(false)?;
+            let b = tx.get_bucket("abc")?;
+            assert_eq!(b.get_kv("key")?.unwrap().value(), value.as_bytes());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_images() -> Result<()> {
+        let fs = MemFs::new();
+        {
+            let db = OpenOptions::new().pagesize(1024).num_pages(4).open_with(
+                Arc::new(FakeMap),
+                fs.options(),
+                "data.db",
+            )?;
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..100_u64 {
+                b.put(i.to_be_bytes(), i.to_string())?;
+            }
+            tx.commit()?;
+        }
+        let check = |fs: &MemFs, name: &str| -> Result<()> {
+            let db = OpenOptions::new().pagesize(1024).open_with(
+                Arc::new(FakeMap),
+                fs.options(),
+                name,
+            )?;
+            let tx = db.tx(false)?;
+            let b = tx.get_bucket("abc")?;
+            assert_eq!(b.kv_pairs().count(), 100);
+            assert_eq!(b.get_kv(42_u64.to_be_bytes())?.unwrap().value(), b"42");
+            Ok(())
+        };
+
+        // a single file
+        let images = MemFs::new();
+        let mut image = images.create(&"data.img")?;
+        fs.open(&"data.db")?.export(&mut image)?;
+        assert_eq!(images.len(&"data.img")?, fs.len(&"data.db")?);
+        image.seek(SeekFrom::Start(0))?;
+        let copy = MemFs::new();
+        copy.import_file(&"copy.db", &mut image)?;
+        check(&copy, "copy.db")?;
+
+        // the whole filesystem
+        let mut image = images.create(&"fs.img")?;
+        fs.export(&mut image)?;
+        image.seek(SeekFrom::Start(0))?;
+        let copy = MemFs::new();
+        copy.import(&mut image)?;
+        assert_eq!(copy.list(), ["data.db"]);
+        check(&copy, "data.db")?;
+
+        // a truncated image doesn't add anything
+        let mut image = images.open(&"fs.img")?;
+        image.seek(SeekFrom::Start(0))?;
+        let mut truncated = images.create(&"truncated.img")?;
+        let mut buf = vec![0; 100];
+        image.read_exact(&mut buf)?;
+        truncated.write_all(&buf)?;
+        truncated.seek(SeekFrom::Start(0))?;
+        let copy = MemFs::new();
+        assert_eq!(
+            copy.import(&mut truncated).map_err(|e| e.kind()),
+            Err(ErrorKind::InvalidData)
+        );
+        assert!(copy.list().is_empty());
+        // so do lengths that are far bigger than the image
+        for (name_len, data_len) in [(u64::MAX, 0), (4, u64::MAX)] {
+            let mut oversized = images.create(&"oversized.img")?;
+            oversized.write_all(&IMAGE_MAGIC)?;
+            oversized.write_all(&1_u64.to_le_bytes())?;
+            oversized.write_all(&name_len.to_le_bytes())?;
+            oversized.write_all(b"name")?;
+            oversized.write_all(&data_len.to_le_bytes())?;
+            oversized.write_all(&[0; 100])?;
+            oversized.seek(SeekFrom::Start(0))?;
+            assert_eq!(
+                copy.import(&mut oversized).map_err(|e| e.kind()),
+                Err(ErrorKind::InvalidData)
+            );
+            assert!(copy.list().is_empty());
+            images.remove(&"oversized.img")?;
+        }
+        let mut not_an_image = images.open(&"data.img")?;
+        assert_eq!(
+            copy.import(&mut not_an_image).map_err(|e| e.kind()),
+            Err(ErrorKind::InvalidData)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_map_outlives_grow() -> IOResult<()> {
+        let fs = MemFs::new();
+        let mut file = File::new(Box::new(fs.create(&"a")?));
+        file.write_all(&[1; 4096])?;
+        let old = FakeMap.do_map(&mut file)?;
+        file.allocate(8192)?;
+        let new = FakeMap.do_map(&mut file)?;
+        // writes show up in the current mapping, but the old one keeps its contents and size
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&[2; 4096])?;
+        assert_eq!(old.len(), 4096);
+        assert_eq!(old.index(0, 4096)?, &[1; 4096]);
+        assert!(old.index(1, 4096).is_err());
+        assert_eq!(new.len(), 8192);
+        assert_eq!(new.index(0, 4096)?, &[2; 4096]);
+        assert_eq!(new.index(1, 4096)?, &[0; 4096]);
+        Ok(())
     }
 }
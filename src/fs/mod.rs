@@ -2,12 +2,18 @@
 //!
 //! jammdb依赖操作系统的文件系统接口，在no_std环境下无法直接使用，
 //! 因此这里自定义了文件接口，以便在no_std环境下使用。
+pub mod blockdev;
+#[cfg(feature = "fault-injection")]
+pub mod faulty;
 pub mod memfile;
+pub mod pread;
+#[cfg(feature = "std")]
+pub mod stdfile;
 
 use alloc::boxed::Box;
-use alloc::string::ToString;
+use alloc::string::String;
 use alloc::sync::Arc;
-use core::fmt::{Debug, Display};
+use core::fmt::Debug;
 use core::ops::{Deref, DerefMut};
 use core2::io::{Read, Seek, Write};
 use downcast::{downcast, Any};
@@ -51,6 +57,8 @@ impl MetaData {
 
 pub trait FileExt {
     fn lock_exclusive(&self) -> IOResult<()>;
+    /// Takes a lock that can be held by many readers at once, but not alongside an exclusive lock
+    fn lock_shared(&self) -> IOResult<()>;
     fn allocate(&mut self, new_size: u64) -> IOResult<()>;
     fn unlock(&self) -> IOResult<()>;
     fn metadata(&self) -> IOResult<MetaData>;
@@ -63,25 +71,55 @@ pub trait OpenOption {
     fn new() -> Self;
     fn read(&mut self, read: bool) -> &mut Self;
     fn write(&mut self, write: bool) -> &mut Self;
-    fn open<T: ToString + PathLike>(&mut self, path: &T) -> IOResult<File>;
+    fn open<T: PathLike>(&mut self, path: &T) -> IOResult<File>;
     fn create(&mut self, create: bool) -> &mut Self;
+    /// check whether the file at `path` exists in the storage these options open files from
+    fn exists<T: PathLike>(&self, path: &T) -> bool {
+        path.exists()
+    }
 }
 
-pub trait PathLike: Display + Debug {
+pub trait PathLike: Debug {
     fn exists(&self) -> bool;
+    /// the path as a string, used to name the file
+    fn to_path_string(&self) -> String;
 }
 
-pub trait DbFile: Seek + Write + Read + FileExt + Any {}
+pub trait DbFile: Seek + Write + Read + FileExt + Any + Send {}
 
 downcast!(dyn DbFile);
 
-pub trait MemoryMap {
+pub trait MemoryMap: Send + Sync {
+    /// Maps the current contents of `file`.
+    ///
+    /// The file is mapped again each time it grows, while transactions are still reading the old mapping,
+    /// so a mapping must stay valid for as long as it is alive, whatever happens to the file afterwards.
     fn do_map(&self, file: &mut File) -> IOResult<Arc<dyn IndexByPageID>>;
 }
 
-pub trait IndexByPageID {
-    fn index(&self, page_id: u64, page_size: usize) -> IOResult<&[u8]>;
+pub trait IndexByPageID: Send + Sync {
+    /// Returns the `count` pages starting at `page_id` as one contiguous slice.
+    ///
+    /// Nodes and freelists that don't fit on one page overflow onto the pages after it,
+    /// so backends that don't keep the file contiguous in memory must copy them together.
+    fn index_range(&self, page_id: u64, count: u64, page_size: usize) -> IOResult<&[u8]>;
+    fn index(&self, page_id: u64, page_size: usize) -> IOResult<&[u8]> {
+        self.index_range(page_id, 1, page_size)
+    }
     fn len(&self) -> usize;
+    /// Called before a transaction starts reading pages.
+    ///
+    /// Every slice returned by [`index`](#tymethod.index) must stay valid until
+    /// [`unpin`](#method.unpin) is called with the returned token.
+    /// Backends that expose the whole file at once have nothing to do here.
+    fn pin(&self) -> u64 {
+        0
+    }
+    /// Releases a token returned by [`pin`](#method.pin).
+    fn unpin(&self, _pin: u64) {}
+    /// Called after `count` pages starting at `page_id` were rewritten in the file,
+    /// so any copy of them held by the backend is stale.
+    fn invalidate(&self, _page_id: u64, _count: u64) {}
 }
 
 pub struct Mmap {
@@ -0,0 +1,346 @@
+//! A page backend for targets that cannot memory map the database file.
+//!
+//! Instead of exposing the whole file as one address range, [`PreadMap`] reads pages on demand
+//! through a [`DbFile`](super::DbFile) handle (`Seek + Read`) and keeps them in a bounded cache.
+//! Pages handed to a transaction are pinned, so they are only evicted once every transaction
+//! that could still be holding them has finished.
+use crate::fs::{File, IOResult, IndexByPageID, MemoryMap};
+use crate::page::PageID;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core2::io::{ErrorKind, SeekFrom};
+use hashbrown::HashMap;
+use spin::Mutex;
+
+/// Default number of pages [`PreadMap::new`] keeps cached
+pub const DEFAULT_CACHE_PAGES: usize = 256;
+
+/// memory map that reads pages through a file handle instead of mapping them
+pub struct PreadMap {
+    reader: Arc<Mutex<File>>,
+    capacity: usize,
+}
+
+impl PreadMap {
+    /// Creates a map that reads pages through `reader`.
+    ///
+    /// `reader` must be a separate handle onto the same file the database is opened on,
+    /// since the database holds its own handle locked for the whole of a writable transaction.
+    pub fn new(reader: File) -> Self {
+        Self::with_capacity(reader, DEFAULT_CACHE_PAGES)
+    }
+
+    /// Same as [`new`](#method.new), but caches up to `capacity` pages.
+    ///
+    /// Pages pinned by open transactions are never evicted, so the cache can temporarily
+    /// grow past `capacity` while long running transactions are reading lots of pages.
+    pub fn with_capacity(reader: File, capacity: usize) -> Self {
+        PreadMap {
+            reader: Arc::new(Mutex::new(reader)),
+            capacity,
+        }
+    }
+}
+
+impl MemoryMap for PreadMap {
+    fn do_map(&self, file: &mut File) -> IOResult<Arc<dyn IndexByPageID>> {
+        let len = file.metadata()?.len();
+        Ok(Arc::new(PageCache::new(
+            self.reader.clone(),
+            len,
+            self.capacity,
+        )))
+    }
+}
+
+struct CachedPage {
+    // stored as u64s so the page is aligned for casting to a Page
+    data: Vec<u64>,
+    len: usize,
+    // the newest pin that existed when this page was last handed out
+    touched: u64,
+    // last use, for picking which page to evict
+    used: u64,
+}
+
+impl CachedPage {
+    fn bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.data.as_ptr() as *const u8, self.len) }
+    }
+}
+
+#[derive(Default)]
+struct CacheState {
+    pages: HashMap<PageID, CachedPage>,
+    // pages that were invalidated while pinned, kept alive until their pins are released
+    retired: Vec<CachedPage>,
+    // active pins by generation, with the number of holders for each
+    pins: BTreeMap<u64, usize>,
+    generation: u64,
+    clock: u64,
+}
+
+impl CacheState {
+    // A page may be freed once every pin that existed when it was last handed out is gone.
+    fn evictable(&self, page: &CachedPage) -> bool {
+        match self.pins.keys().next() {
+            Some(oldest) => *oldest > page.touched,
+            None => true,
+        }
+    }
+
+    fn evict(&mut self, capacity: usize) {
+        while self.pages.len() > capacity {
+            let victim = self
+                .pages
+                .iter()
+                .filter(|(_, page)| self.evictable(page))
+                .min_by_key(|(_, page)| page.used)
+                .map(|(id, _)| *id);
+            match victim {
+                Some(id) => {
+                    self.pages.remove(&id);
+                }
+                // everything left is pinned, so let the cache grow until the pins are released
+                None => break,
+            }
+        }
+        let retired = core::mem::take(&mut self.retired);
+        self.retired = retired
+            .into_iter()
+            .filter(|page| !self.evictable(page))
+            .collect();
+    }
+}
+
+/// Pages of one file, read on demand into a bounded cache
+pub(crate) struct PageCache {
+    reader: Arc<Mutex<File>>,
+    len: u64,
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+impl PageCache {
+    pub(crate) fn new(reader: Arc<Mutex<File>>, len: u64, capacity: usize) -> Self {
+        PageCache {
+            reader,
+            len,
+            capacity,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    fn read(&self, page_id: PageID, count: u64, page_size: usize) -> IOResult<CachedPage> {
+        let offset = page_id * page_size as u64;
+        let len = page_size * count as usize;
+        if offset + len as u64 > self.len {
+            return Err(core2::io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "index is out of range",
+            ));
+        }
+        let mut data = vec![0_u64; len.div_ceil(size_of::<u64>())];
+        let buf = unsafe { core::slice::from_raw_parts_mut(data.as_mut_ptr() as *mut u8, len) };
+        let mut reader = self.reader.lock();
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.read_exact(buf)?;
+        Ok(CachedPage {
+            data,
+            len,
+            touched: 0,
+            used: 0,
+        })
+    }
+}
+
+impl IndexByPageID for PageCache {
+    // Blocks are cached under the id of their first page, so reading a page and
+    // then the whole block it starts replaces the single page with the block.
+    fn index_range(&self, page_id: u64, count: u64, page_size: usize) -> IOResult<&[u8]> {
+        let len = page_size * count as usize;
+        {
+            let mut state = self.state.lock();
+            state.clock += 1;
+            let (generation, clock) = (state.generation, state.clock);
+            if let Some(page) = state.pages.get_mut(&page_id) {
+                if page.len >= len {
+                    page.touched = generation;
+                    page.used = clock;
+                    let bytes = &page.bytes()[..len];
+                    // The page's memory is only freed once it is evictable,
+                    // which cannot happen while the caller's pin is held.
+                    return Ok(unsafe { core::slice::from_raw_parts(bytes.as_ptr(), bytes.len()) });
+                }
+            }
+        }
+        let mut page = self.read(page_id, count, page_size)?;
+        let mut state = self.state.lock();
+        page.touched = state.generation;
+        page.used = state.clock;
+        let bytes = page.bytes();
+        let bytes = unsafe { core::slice::from_raw_parts(bytes.as_ptr(), bytes.len()) };
+        if let Some(old) = state.pages.insert(page_id, page) {
+            state.retired.push(old);
+        }
+        let capacity = self.capacity;
+        state.evict(capacity);
+        Ok(bytes)
+    }
+
+    fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    fn pin(&self) -> u64 {
+        let mut state = self.state.lock();
+        state.generation += 1;
+        let generation = state.generation;
+        *state.pins.entry(generation).or_insert(0) += 1;
+        generation
+    }
+
+    fn unpin(&self, pin: u64) {
+        let mut state = self.state.lock();
+        if let Some(count) = state.pins.get_mut(&pin) {
+            *count -= 1;
+            if *count == 0 {
+                state.pins.remove(&pin);
+            }
+        }
+        let capacity = self.capacity;
+        state.evict(capacity);
+    }
+
+    fn invalidate(&self, page_id: u64, count: u64) {
+        let mut state = self.state.lock();
+        for id in page_id..page_id + count {
+            if let Some(page) = state.pages.remove(&id) {
+                if !state.evictable(&page) {
+                    state.retired.push(page);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::Result;
+    use crate::memfile::{FakeMap, FileOpenOptions};
+    use crate::testutil::RandomFile;
+    use crate::{OpenOption, OpenOptions};
+
+    fn cache(random_file: &RandomFile, num_pages: u64, capacity: usize) -> PageCache {
+        let mut file = FileOpenOptions::new()
+            .create(true)
+            .open(random_file)
+            .unwrap();
+        file.allocate(num_pages * 1024).unwrap();
+        for id in 0..num_pages {
+            let mut page = [0_u8; 1024];
+            page[..8].copy_from_slice(&id.to_ne_bytes());
+            file.seek(SeekFrom::Start(id * 1024)).unwrap();
+            file.write_all(&page).unwrap();
+        }
+        let len = file.metadata().unwrap().len();
+        PageCache::new(Arc::new(Mutex::new(file)), len, capacity)
+    }
+
+    fn page_id(buf: &[u8]) -> u64 {
+        u64::from_ne_bytes(buf[..8].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_eviction() {
+        let random_file = RandomFile::new();
+        let cache = cache(&random_file, 8, 2);
+        for id in 0..8 {
+            assert_eq!(page_id(cache.index(id, 1024).unwrap()), id);
+            assert!(cache.state.lock().pages.len() <= 2);
+        }
+        assert!(cache.index(8, 1024).is_err());
+    }
+
+    #[test]
+    fn test_index_range() {
+        let random_file = RandomFile::new();
+        let cache = cache(&random_file, 8, 2);
+        assert_eq!(page_id(cache.index(2, 1024).unwrap()), 2);
+        let block = cache.index_range(2, 3, 1024).unwrap();
+        assert_eq!(block.len(), 3 * 1024);
+        for (i, page) in block.chunks(1024).enumerate() {
+            assert_eq!(page_id(page), 2 + i as u64);
+        }
+        // the single page is served from the cached block
+        assert_eq!(cache.index(2, 1024).unwrap().as_ptr(), block.as_ptr());
+        assert!(cache.index_range(6, 3, 1024).is_err());
+    }
+
+    #[test]
+    fn test_pinned_pages_are_kept() {
+        let random_file = RandomFile::new();
+        let cache = cache(&random_file, 8, 2);
+        let pin = cache.pin();
+        let pages: Vec<&[u8]> = (0..8).map(|id| cache.index(id, 1024).unwrap()).collect();
+        assert_eq!(cache.state.lock().pages.len(), 8);
+        // invalidated pages stay readable while they are pinned
+        cache.invalidate(3, 2);
+        assert_eq!(cache.state.lock().retired.len(), 2);
+        for (id, page) in pages.iter().enumerate() {
+            assert_eq!(page_id(page), id as u64);
+        }
+        cache.unpin(pin);
+        let state = cache.state.lock();
+        assert_eq!(state.pages.len(), 2);
+        assert!(state.retired.is_empty());
+    }
+
+    #[test]
+    fn test_pread_db() -> Result<()> {
+        let random_file = RandomFile::new();
+        {
+            let db = OpenOptions::new()
+                .pagesize(1024)
+                .num_pages(4)
+                .open::<_, FileOpenOptions>(Arc::new(FakeMap), &random_file)?;
+            drop(db);
+        }
+        let reader = FileOpenOptions::new().open(&random_file)?;
+        let db = OpenOptions::new()
+            .pagesize(1024)
+            .open::<_, FileOpenOptions>(
+                Arc::new(PreadMap::with_capacity(reader, 4)),
+                &random_file,
+            )?;
+        for round in 0..3_u64 {
+            let tx = db.tx(true)?;
+            let b = tx.get_or_create_bucket("abc")?;
+            for i in 0..500_u64 {
+                // large values so some nodes overflow onto multiple pages
+                b.put(
+                    i.to_be_bytes(),
+                    i.to_string().repeat(round as usize * 40 + 1),
+                )?;
+            }
+            // a value bigger than a page, so its leaf overflows onto the pages after it
+            b.put("big", vec![round as u8; 5000])?;
+            tx.commit()?;
+            let tx = db.tx(false)?;
+            let b = tx.get_bucket("abc")?;
+            assert_eq!(b.get_kv("big")?.unwrap().value(), [round as u8; 5000]);
+            for (i, kv) in b.kv_pairs().take(500).enumerate() {
+                assert_eq!(kv.key(), (i as u64).to_be_bytes());
+                assert_eq!(
+                    kv.value(),
+                    i.to_string().repeat(round as usize * 40 + 1).as_bytes()
+                );
+            }
+        }
+        db.check()
+    }
+}
@@ -0,0 +1,395 @@
+//! File and memory map backends built on the standard library.
+//!
+//! [`StdFile`] wraps a [`std::fs::File`], using real file locks and preallocation,
+//! and [`StdMmap`] maps that file into memory so the database can be read without copying.
+use crate::fs::{
+    DbFile, File, FileExt, IOResult, IndexByPageID, MemoryMap, MetaData, OpenOption, PathLike,
+};
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use core2::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// core2 errors can only hold a `&'static str`, so each distinct message is leaked once
+// and shared by every error with that message.
+static MESSAGES: spin::Mutex<BTreeSet<&'static str>> = spin::Mutex::new(BTreeSet::new());
+
+/// converts a [`std::io::Error`] into the `core2` error used by the file traits,
+/// keeping its message, which includes the OS error code
+fn io_error(err: std::io::Error) -> core2::io::Error {
+    let kind = match err.kind() {
+        std::io::ErrorKind::NotFound => ErrorKind::NotFound,
+        std::io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+        std::io::ErrorKind::AlreadyExists => ErrorKind::AlreadyExists,
+        std::io::ErrorKind::WouldBlock => ErrorKind::WouldBlock,
+        std::io::ErrorKind::InvalidInput => ErrorKind::InvalidInput,
+        std::io::ErrorKind::InvalidData => ErrorKind::InvalidData,
+        std::io::ErrorKind::TimedOut => ErrorKind::TimedOut,
+        std::io::ErrorKind::WriteZero => ErrorKind::WriteZero,
+        std::io::ErrorKind::Interrupted => ErrorKind::Interrupted,
+        std::io::ErrorKind::UnexpectedEof => ErrorKind::UnexpectedEof,
+        _ => ErrorKind::Other,
+    };
+    let message = err.to_string();
+    let mut messages = MESSAGES.lock();
+    let message = match messages.get(message.as_str()) {
+        Some(message) => *message,
+        None => {
+            let message: &'static str = Box::leak(message.into_boxed_str());
+            messages.insert(message);
+            message
+        }
+    };
+    core2::io::Error::new(kind, message)
+}
+
+/// A database file on the OS filesystem
+pub struct StdFile {
+    pub file: std::fs::File,
+}
+
+impl StdFile {
+    pub fn new(file: std::fs::File) -> Self {
+        Self { file }
+    }
+}
+
+impl Seek for StdFile {
+    fn seek(&mut self, pos: SeekFrom) -> IOResult<u64> {
+        let pos = match pos {
+            SeekFrom::Start(l) => std::io::SeekFrom::Start(l),
+            SeekFrom::Current(l) => std::io::SeekFrom::Current(l),
+            SeekFrom::End(l) => std::io::SeekFrom::End(l),
+        };
+        std::io::Seek::seek(&mut self.file, pos).map_err(io_error)
+    }
+}
+
+impl Read for StdFile {
+    fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+        std::io::Read::read(&mut self.file, buf).map_err(io_error)
+    }
+}
+
+impl Write for StdFile {
+    fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+        std::io::Write::write(&mut self.file, buf).map_err(io_error)
+    }
+
+    fn flush(&mut self) -> IOResult<()> {
+        std::io::Write::flush(&mut self.file).map_err(io_error)
+    }
+}
+
+impl FileExt for StdFile {
+    /// take an exclusive `flock` on the file, blocking until it is available
+    fn lock_exclusive(&self) -> IOResult<()> {
+        fs2::FileExt::lock_exclusive(&self.file).map_err(io_error)
+    }
+    fn lock_shared(&self) -> IOResult<()> {
+        fs2::FileExt::lock_shared(&self.file).map_err(io_error)
+    }
+    /// grow the file to `new_size` bytes, reserving the disk space up front
+    fn allocate(&mut self, new_size: u64) -> IOResult<()> {
+        fs2::FileExt::allocate(&self.file, new_size).map_err(io_error)
+    }
+    fn unlock(&self) -> IOResult<()> {
+        fs2::FileExt::unlock(&self.file).map_err(io_error)
+    }
+    fn metadata(&self) -> IOResult<MetaData> {
+        let metadata = self.file.metadata().map_err(io_error)?;
+        Ok(MetaData {
+            len: metadata.len(),
+        })
+    }
+    fn sync_all(&self) -> IOResult<()> {
+        self.file.sync_all().map_err(io_error)
+    }
+    fn size(&self) -> usize {
+        self.file.metadata().map(|m| m.len() as usize).unwrap_or(0)
+    }
+    /// a file on disk has no address, use [`StdMmap`] to map it into memory
+    fn addr(&self) -> usize {
+        0
+    }
+}
+
+impl DbFile for StdFile {}
+
+/// Opens [`StdFile`]s, mirroring [`std::fs::OpenOptions`]
+pub struct StdOpenOptions(std::fs::OpenOptions);
+
+impl OpenOption for StdOpenOptions {
+    fn new() -> Self {
+        StdOpenOptions(std::fs::OpenOptions::new())
+    }
+    fn read(&mut self, read: bool) -> &mut Self {
+        self.0.read(read);
+        self
+    }
+    fn write(&mut self, write: bool) -> &mut Self {
+        self.0.write(write);
+        self
+    }
+    fn open<T: PathLike>(&mut self, path: &T) -> IOResult<File> {
+        let file = self.0.open(path.to_path_string()).map_err(io_error)?;
+        Ok(File::new(Box::new(StdFile::new(file))))
+    }
+    fn create(&mut self, create: bool) -> &mut Self {
+        self.0.create(create);
+        self
+    }
+    /// check the OS filesystem, regardless of how `path` resolves it
+    fn exists<T: PathLike>(&self, path: &T) -> bool {
+        Path::new(&path.to_path_string()).exists()
+    }
+}
+
+impl PathLike for &Path {
+    fn exists(&self) -> bool {
+        Path::exists(self)
+    }
+    fn to_path_string(&self) -> String {
+        self.to_string_lossy().to_string()
+    }
+}
+
+impl PathLike for PathBuf {
+    fn exists(&self) -> bool {
+        self.as_path().exists()
+    }
+    fn to_path_string(&self) -> String {
+        self.to_string_lossy().to_string()
+    }
+}
+
+impl PathLike for &PathBuf {
+    fn exists(&self) -> bool {
+        self.as_path().exists()
+    }
+    fn to_path_string(&self) -> String {
+        self.to_string_lossy().to_string()
+    }
+}
+
+/// memory map for [`StdFile`]s
+#[derive(Clone)]
+pub struct StdMmap;
+
+impl MemoryMap for StdMmap {
+    fn do_map(&self, file: &mut File) -> IOResult<Arc<dyn IndexByPageID>> {
+        let file = file.file.downcast_ref::<StdFile>().map_err(|_| {
+            core2::io::Error::new(ErrorKind::InvalidInput, "StdMmap can only map a StdFile")
+        })?;
+        let mmap = unsafe { memmap2::Mmap::map(&file.file) }.map_err(io_error)?;
+        #[cfg(unix)]
+        mmap.advise(memmap2::Advice::Random).map_err(io_error)?;
+        Ok(Arc::new(StdMmapIndex { mmap }))
+    }
+}
+
+struct StdMmapIndex {
+    mmap: memmap2::Mmap,
+}
+
+impl IndexByPageID for StdMmapIndex {
+    fn index_range(&self, page_id: u64, count: u64, page_size: usize) -> IOResult<&[u8]> {
+        let start = page_id as usize * page_size;
+        match self.mmap.get(start..start + page_size * count as usize) {
+            Some(data) => Ok(data),
+            None => Err(core2::io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "index is out of range",
+            )),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.mmap.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::Result;
+    use crate::{OpenOptions, DB};
+    use std::vec::Vec;
+
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> TempFile {
+            let path =
+                std::env::temp_dir().join(std::format!("{}-{}.db", name, std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_io_error() {
+        let err = io_error(std::io::Error::from_raw_os_error(28));
+        assert_eq!(err.kind(), ErrorKind::Other);
+        let message = std::format!("{err}");
+        assert!(message.contains("os error 28"), "{message}");
+        // the message is only leaked the first time
+        let again = io_error(std::io::Error::from_raw_os_error(28));
+        assert!(core::ptr::eq(
+            again.into_inner().unwrap(),
+            err.into_inner().unwrap()
+        ));
+
+        let err = io_error(std::io::Error::new(std::io::ErrorKind::NotFound, "gone"));
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(std::format!("{err}"), "gone");
+    }
+
+    #[test]
+    fn test_std_file_persists() -> Result<()> {
+        let file = TempFile::new("jammdb-std-persists");
+        {
+            let db = OpenOptions::new()
+                .pagesize(1024)
+                .num_pages(8)
+                .open::<_, StdOpenOptions>(Arc::new(StdMmap), &file.0)?;
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..1000_u64 {
+                b.put(i.to_be_bytes(), i.to_string())?;
+            }
+            tx.commit()?;
+        }
+        assert_eq!(std::fs::metadata(&file.0).unwrap().len() % 1024, 0);
+        {
+            let db = OpenOptions::new()
+                .pagesize(1024)
+                .open::<_, StdOpenOptions>(Arc::new(StdMmap), file.0.as_path())?;
+            let tx = db.tx(false)?;
+            let b = tx.get_bucket("abc")?;
+            let keys: Vec<u64> = b
+                .kv_pairs()
+                .map(|kv| u64::from_be_bytes(kv.key().try_into().unwrap()))
+                .collect();
+            assert_eq!(keys, (0..1000).collect::<Vec<u64>>());
+            assert_eq!(b.get_kv(999_u64.to_be_bytes())?.unwrap().value(), b"999");
+        }
+        let file = TempFile::new("jammdb-std-default");
+        let db = DB::open::<StdOpenOptions, _>(Arc::new(StdMmap), file.0.clone())?;
+        {
+            let tx = db.tx(true)?;
+            tx.create_bucket("abc")?.put("key", "value")?;
+            tx.commit()?;
+        }
+        db.check()
+    }
+
+    #[test]
+    fn test_close_unlocks() -> Result<()> {
+        let file = TempFile::new("jammdb-std-close");
+        let locked = || {
+            let f = std::fs::File::open(&file.0).unwrap();
+            let locked = fs2::FileExt::try_lock_exclusive(&f).is_err();
+            if !locked {
+                fs2::FileExt::unlock(&f).unwrap();
+            }
+            locked
+        };
+        let db = DB::open::<StdOpenOptions, _>(Arc::new(StdMmap), &file.0)?;
+        assert!(locked());
+        db.close()?;
+        assert!(!locked());
+
+        let db = DB::open::<StdOpenOptions, _>(Arc::new(StdMmap), &file.0)?;
+        let clone = db.clone();
+        drop(db);
+        assert!(locked());
+        // dropping the last clone unlocks the file
+        drop(clone);
+        assert!(!locked());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_only() -> Result<()> {
+        let file = TempFile::new("jammdb-std-read-only");
+        {
+            let db = DB::open::<StdOpenOptions, _>(Arc::new(StdMmap), &file.0)?;
+            let tx = db.tx(true)?;
+            tx.create_bucket("abc")?.put("key", "value")?;
+            tx.commit()?;
+        }
+        let mut permissions = std::fs::metadata(&file.0).unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&file.0, permissions).unwrap();
+
+        let open = || {
+            OpenOptions::new()
+                .read_only(true)
+                .open::<_, StdOpenOptions>(Arc::new(StdMmap), &file.0)
+        };
+        // read-only handles share the lock
+        let db1 = open()?;
+        let db2 = open()?;
+        let f = std::fs::File::open(&file.0).unwrap();
+        assert!(fs2::FileExt::try_lock_exclusive(&f).is_err());
+        for db in [&db1, &db2] {
+            let tx = db.tx(false)?;
+            assert_eq!(
+                tx.get_bucket("abc")?.get_kv("key")?.unwrap().value(),
+                b"value"
+            );
+        }
+        assert_eq!(db1.tx(true).err(), Some(crate::Error::ReadOnlyDB));
+        Ok(())
+    }
+
+    #[test]
+    fn test_memfile_image_to_std_file() -> Result<()> {
+        let fs = crate::memfile::MemFs::new();
+        {
+            let db = OpenOptions::new().pagesize(1024).num_pages(4).open_with(
+                Arc::new(crate::memfile::FakeMap),
+                fs.options(),
+                "image.db",
+            )?;
+            let tx = db.tx(true)?;
+            tx.create_bucket("abc")?.put("key", "value")?;
+            tx.commit()?;
+        }
+        let file = TempFile::new("jammdb-std-image");
+        let mut std_file = StdOpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&file.0)?;
+        fs.open(&"image.db")?.export(&mut *std_file)?;
+        drop(std_file);
+
+        let db = OpenOptions::new()
+            .pagesize(1024)
+            .open::<_, StdOpenOptions>(Arc::new(StdMmap), &file.0)?;
+        let tx = db.tx(false)?;
+        assert_eq!(
+            tx.get_bucket("abc")?.get_kv("key")?.unwrap().value(),
+            b"value"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_std_mmap_requires_std_file() {
+        let mut file = crate::memfile::FileOpenOptions::new()
+            .create(true)
+            .open(&"jammdb-std-mmap-memfile")
+            .unwrap();
+        assert!(StdMmap.do_map(&mut file).is_err());
+    }
+}
@@ -53,7 +53,7 @@
 //!     // get the bucket we created in the last transaction
 //!     let names_bucket = tx.get_bucket("names")?;
 //!     // get the key / value pair we inserted into the bucket
-//!     if let Some(data) = names_bucket.get("Kanan") {
+//!     if let Some(data) = names_bucket.get("Kanan")? {
 //!         assert_eq!(data.kv().value(), b"Jarrus");
 //!     }
 //! }
@@ -103,7 +103,7 @@
 //!     // get the bucket we created in the last transaction
 //!     let users_bucket = tx.get_bucket("users")?;
 //!     // get the key / value pair we inserted into the bucket
-//!     if let Some(data) = users_bucket.get(b"user1") {
+//!     if let Some(data) = users_bucket.get(b"user1")? {
 //!         // deserialize into a user struct
 //!         let db_user: User = rmp_serde::from_slice(data.kv().value()).unwrap();
 //!         assert_eq!(db_user, user);
@@ -112,35 +112,46 @@
 //!     Ok(())
 //! }//
 #![feature(error_in_core)]
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+#[cfg(feature = "std")]
+mod batch;
 #[allow(clippy::mutable_key_type)]
 mod bucket;
 mod bytes;
 mod cursor;
 mod data;
 mod db;
+mod endian;
 mod errors;
 mod freelist;
 mod fs;
 mod lifetimes;
 mod meta;
+mod migrate;
 mod node;
 mod page;
 mod page_node;
+mod readers;
+mod snapshot;
 mod tx;
 extern crate alloc;
-#[cfg(test)]
+#[cfg(any(test, feature = "std"))]
 extern crate std;
 
 pub use crate::bytes::ToBytes;
 pub use bucket::Bucket;
 pub use cursor::{Buckets, Cursor, KVPairs, ToBuckets, ToKVPairs};
 pub use data::*;
-pub use db::{OpenOptions, DB};
+pub use db::{Durability, OpenOptions, TxTimeout, DB, MIN_SUPPORTED_VERSION, VERSION};
 pub use errors::*;
 pub use fs::memfile;
+#[cfg(feature = "std")]
+pub use fs::stdfile;
 pub use fs::*;
-pub use tx::Tx;
+pub use meta::HashAlgorithm;
+pub use migrate::{Migration, MigrationReport};
+pub use snapshot::{Snapshot, SnapshotBucket, SnapshotCursor};
+pub use tx::{Savepoint, Tx};
 
 #[cfg(test)]
 mod testutil {
@@ -188,6 +199,9 @@ mod testutil {
             let x = &self.path;
             x.exists()
         }
+        fn to_path_string(&self) -> String {
+            self.path.clone()
+        }
     }
 
     impl PathLike for &RandomFile {
@@ -195,11 +209,16 @@ mod testutil {
             let x = &self.path;
             x.exists()
         }
+        fn to_path_string(&self) -> String {
+            self.path.clone()
+        }
     }
 
     impl Drop for RandomFile {
         #[allow(unused_must_use)]
-        fn drop(&mut self) {}
+        fn drop(&mut self) {
+            crate::memfile::MemFs::global().remove(&self.path.as_str());
+        }
     }
     pub fn rand_bytes(size: usize) -> Bytes {
         let buf = BytesMut::new();
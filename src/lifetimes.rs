@@ -58,7 +58,7 @@ struct BucketLifetime();
 ///         // open a writable transaction so we can make changes
 ///         let tx = db.tx(true)?;
 ///         let b = tx.get_bucket("names")?;
-///         kv = b.get_kv("data").unwrap();
+///         kv = b.get_kv("data")?.unwrap();
 ///     }
 ///     let key = kv.key();
 ///     Ok(())
@@ -1,62 +1,239 @@
 use alloc::vec::Vec;
+#[cfg(feature = "sha3")]
 use sha3::{Digest, Sha3_256};
 
 use crate::bucket::BucketMeta;
-use crate::page::PageID;
+use crate::endian::{U32, U64};
+use crate::page::{PageID, CRC32C};
+
+/// The hash used to validate the meta pages of a database.
+///
+/// The algorithm is picked with [`OpenOptions::hash_algorithm`](crate::OpenOptions::hash_algorithm)
+/// when a database is created and recorded in its meta pages, so existing databases are always
+/// opened with the algorithm they were written with. Each algorithm other than CRC-32C needs its
+/// cargo feature: `sha3` (enabled by default) or `xxhash`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    /// SHA3-256, the only hash used by databases written before version 2 of the file format
+    #[cfg(feature = "sha3")]
+    Sha3,
+    /// CRC-32C, the same checksum used for pages
+    Crc32c,
+    /// The 64 bit variant of XXH3
+    #[cfg(feature = "xxhash")]
+    XxHash,
+}
+
+impl Default for HashAlgorithm {
+    /// SHA3-256 if the `sha3` feature is enabled, CRC-32C otherwise
+    fn default() -> Self {
+        #[cfg(feature = "sha3")]
+        return HashAlgorithm::Sha3;
+        #[cfg(not(feature = "sha3"))]
+        return HashAlgorithm::Crc32c;
+    }
+}
+
+impl HashAlgorithm {
+    // The algorithm is stored in the second byte of the meta flags
+    pub(crate) const FLAGS_SHIFT: u32 = 8;
+
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            #[cfg(feature = "sha3")]
+            HashAlgorithm::Sha3 => 0,
+            HashAlgorithm::Crc32c => 1,
+            #[cfg(feature = "xxhash")]
+            HashAlgorithm::XxHash => 2,
+        }
+    }
+
+    // `None` for unknown algorithms, or ones whose feature isn't enabled
+    pub(crate) fn from_id(id: u8) -> Option<HashAlgorithm> {
+        match id {
+            #[cfg(feature = "sha3")]
+            0 => Some(HashAlgorithm::Sha3),
+            1 => Some(HashAlgorithm::Crc32c),
+            #[cfg(feature = "xxhash")]
+            2 => Some(HashAlgorithm::XxHash),
+            _ => None,
+        }
+    }
+
+    // Hashes are padded with zeroes to fill the 32 bytes of the meta's hash
+    fn hash(self, bytes: &[u8]) -> [u8; 32] {
+        let mut hash_result: [u8; 32] = [0; 32];
+        match self {
+            #[cfg(feature = "sha3")]
+            HashAlgorithm::Sha3 => {
+                let mut hasher = Sha3_256::new();
+                hasher.update(bytes);
+                let hash = hasher.finalize();
+                assert_eq!(hash.len(), 32);
+                hash_result.copy_from_slice(&hash[..]);
+            }
+            HashAlgorithm::Crc32c => {
+                hash_result[..4].copy_from_slice(&CRC32C.checksum(bytes).to_be_bytes());
+            }
+            #[cfg(feature = "xxhash")]
+            HashAlgorithm::XxHash => {
+                let hash = xxhash_rust::xxh3::xxh3_64(bytes);
+                hash_result[..8].copy_from_slice(&hash.to_be_bytes());
+            }
+        }
+        hash_result
+    }
+}
 
 #[repr(C)]
 #[derive(Debug, Clone)]
 pub(crate) struct Meta {
-    pub(crate) meta_page: u32,
-    pub(crate) magic: u32,
-    pub(crate) version: u32,
-    pub(crate) pagesize: u64,
+    meta_page: U32,
+    magic: U32,
+    version: U32,
+    // Only used since version 2, it was padding before that.
+    flags: U32,
+    pagesize: U64,
     pub(crate) root: BucketMeta,
-    pub(crate) num_pages: PageID,
-    pub(crate) freelist_page: PageID,
-    pub(crate) tx_id: u64,
+    num_pages: U64,
+    freelist_page: U64,
+    tx_id: U64,
     pub(crate) hash: [u8; 32],
 }
 
 impl Meta {
+    // Branch, leaf and freelist pages store a checksum
+    pub(crate) const FLAG_CHECKSUMS: u32 = 0x01;
+
+    #[inline]
+    pub(crate) fn meta_page(&self) -> u32 {
+        self.meta_page.get()
+    }
+
+    #[inline]
+    pub(crate) fn set_meta_page(&mut self, meta_page: u32) {
+        self.meta_page.set(meta_page);
+    }
+
+    #[inline]
+    pub(crate) fn magic(&self) -> u32 {
+        self.magic.get()
+    }
+
+    #[inline]
+    pub(crate) fn set_magic(&mut self, magic: u32) {
+        self.magic.set(magic);
+    }
+
+    #[inline]
+    pub(crate) fn version(&self) -> u32 {
+        self.version.get()
+    }
+
+    #[inline]
+    pub(crate) fn set_version(&mut self, version: u32) {
+        self.version.set(version);
+    }
+
+    #[inline]
+    pub(crate) fn flags(&self) -> u32 {
+        self.flags.get()
+    }
+
+    #[inline]
+    pub(crate) fn set_flags(&mut self, flags: u32) {
+        self.flags.set(flags);
+    }
+
+    #[inline]
+    pub(crate) fn pagesize(&self) -> u64 {
+        self.pagesize.get()
+    }
+
+    #[inline]
+    pub(crate) fn set_pagesize(&mut self, pagesize: u64) {
+        self.pagesize.set(pagesize);
+    }
+
+    #[inline]
+    pub(crate) fn num_pages(&self) -> PageID {
+        self.num_pages.get()
+    }
+
+    #[inline]
+    pub(crate) fn set_num_pages(&mut self, num_pages: PageID) {
+        self.num_pages.set(num_pages);
+    }
+
+    #[inline]
+    pub(crate) fn freelist_page(&self) -> PageID {
+        self.freelist_page.get()
+    }
+
+    #[inline]
+    pub(crate) fn set_freelist_page(&mut self, freelist_page: PageID) {
+        self.freelist_page.set(freelist_page);
+    }
+
+    #[inline]
+    pub(crate) fn tx_id(&self) -> u64 {
+        self.tx_id.get()
+    }
+
+    #[inline]
+    pub(crate) fn set_tx_id(&mut self, tx_id: u64) {
+        self.tx_id.set(tx_id);
+    }
+
+    pub(crate) fn checksums(&self) -> bool {
+        self.version() >= 2 && self.flags() & Meta::FLAG_CHECKSUMS != 0
+    }
+
+    pub(crate) fn hash_algorithm(&self) -> Option<HashAlgorithm> {
+        if self.version() < 2 {
+            return HashAlgorithm::from_id(0);
+        }
+        HashAlgorithm::from_id((self.flags() >> HashAlgorithm::FLAGS_SHIFT) as u8)
+    }
+
     pub(crate) fn valid(&self) -> bool {
-        self.hash == self.hash_self()
+        self.hash_algorithm().is_some() && self.hash == self.hash_self()
     }
 
     pub(crate) fn hash_self(&self) -> [u8; 32] {
-        let mut hash_result: [u8; 32] = [0; 32];
-        let mut hasher = Sha3_256::new();
-        hasher.update(self.bytes());
-        let hash = hasher.finalize();
-        assert_eq!(hash.len(), 32);
-        hash_result.copy_from_slice(&hash[..]);
-        hash_result
+        let algorithm = self
+            .hash_algorithm()
+            .expect("meta page hash algorithm is not supported");
+        algorithm.hash(&self.bytes())
     }
 
     fn bytes(&self) -> Vec<u8> {
         // let buf = bytes::BytesMut::new();
         // let mut w = buf.writer();
-        // let _ = w.write(&self.meta_page.to_be_bytes());
-        // let _ = w.write(&self.magic.to_be_bytes());
-        // let _ = w.write(&self.version.to_be_bytes());
-        // let _ = w.write(&self.pagesize.to_be_bytes());
-        // let _ = w.write(&self.root.root_page.to_be_bytes());
-        // let _ = w.write(&self.root.next_int.to_be_bytes());
-        // let _ = w.write(&self.num_pages.to_be_bytes());
-        // let _ = w.write(&self.freelist_page.to_be_bytes());
-        // let _ = w.write(&self.tx_id.to_be_bytes());
+        // let _ = w.write(&self.meta_page().to_be_bytes());
+        // let _ = w.write(&self.magic().to_be_bytes());
+        // let _ = w.write(&self.version().to_be_bytes());
+        // let _ = w.write(&self.pagesize().to_be_bytes());
+        // let _ = w.write(&self.root.root_page().to_be_bytes());
+        // let _ = w.write(&self.root.next_int().to_be_bytes());
+        // let _ = w.write(&self.num_pages().to_be_bytes());
+        // let _ = w.write(&self.freelist_page().to_be_bytes());
+        // let _ = w.write(&self.tx_id().to_be_bytes());
         //
         // w.into_inner().freeze()
         let mut buf = Vec::new();
-        buf.extend_from_slice(&self.meta_page.to_be_bytes());
-        buf.extend_from_slice(&self.magic.to_be_bytes());
-        buf.extend_from_slice(&self.version.to_be_bytes());
-        buf.extend_from_slice(&self.pagesize.to_be_bytes());
-        buf.extend_from_slice(&self.root.root_page.to_be_bytes());
-        buf.extend_from_slice(&self.root.next_int.to_be_bytes());
-        buf.extend_from_slice(&self.num_pages.to_be_bytes());
-        buf.extend_from_slice(&self.freelist_page.to_be_bytes());
-        buf.extend_from_slice(&self.tx_id.to_be_bytes());
+        buf.extend_from_slice(&self.meta_page().to_be_bytes());
+        buf.extend_from_slice(&self.magic().to_be_bytes());
+        buf.extend_from_slice(&self.version().to_be_bytes());
+        if self.version() >= 2 {
+            buf.extend_from_slice(&self.flags().to_be_bytes());
+        }
+        buf.extend_from_slice(&self.pagesize().to_be_bytes());
+        buf.extend_from_slice(&self.root.root_page().to_be_bytes());
+        buf.extend_from_slice(&self.root.next_int().to_be_bytes());
+        buf.extend_from_slice(&self.num_pages().to_be_bytes());
+        buf.extend_from_slice(&self.freelist_page().to_be_bytes());
+        buf.extend_from_slice(&self.tx_id().to_be_bytes());
         buf
     }
 }
@@ -66,19 +243,18 @@ mod tests {
     use super::*;
 
     #[test]
+    #[cfg(feature = "sha3")]
     fn test_meta() {
         let mut meta = Meta {
-            meta_page: 1,
-            magic: 1_234_567_890,
-            version: 987_654_321,
-            pagesize: 4096,
-            root: BucketMeta {
-                root_page: 2,
-                next_int: 2020,
-            },
-            num_pages: 13,
-            freelist_page: 3,
-            tx_id: 8,
+            meta_page: 1.into(),
+            magic: 1_234_567_890.into(),
+            version: 987_654_321.into(),
+            flags: 0.into(),
+            pagesize: 4096.into(),
+            root: BucketMeta::new(2, 2020),
+            num_pages: 13.into(),
+            freelist_page: 3.into(),
+            tx_id: 8.into(),
             hash: [0; 32],
         };
 
@@ -88,11 +264,56 @@ mod tests {
         assert_eq!(meta.hash, meta.hash_self());
         // modify the last property before the hash
         // to change the hash
-        meta.tx_id = 88;
+        meta.set_tx_id(88);
         assert_ne!(meta.hash, meta.hash_self());
         // reset hash and make sure it is still valid
         meta.hash = meta.hash_self();
         assert!(meta.valid());
         assert_eq!(meta.hash, meta.hash_self());
+
+        // flags are part of the hash since version 2
+        meta.set_flags(Meta::FLAG_CHECKSUMS);
+        assert!(!meta.valid());
+        assert!(meta.checksums());
+        meta.set_version(1);
+        meta.hash = meta.hash_self();
+        meta.set_flags(0);
+        assert!(meta.valid());
+        meta.set_flags(Meta::FLAG_CHECKSUMS);
+        assert!(meta.valid());
+        assert!(!meta.checksums());
+    }
+
+    #[test]
+    fn test_hash_algorithm() {
+        let mut meta = Meta {
+            meta_page: 0.into(),
+            magic: 1_234_567_890.into(),
+            version: 2.into(),
+            flags: (u32::from(HashAlgorithm::Crc32c.id()) << HashAlgorithm::FLAGS_SHIFT).into(),
+            pagesize: 4096.into(),
+            root: BucketMeta::new(2, 0),
+            num_pages: 4.into(),
+            freelist_page: 3.into(),
+            tx_id: 0.into(),
+            hash: [0; 32],
+        };
+        assert_eq!(meta.hash_algorithm(), Some(HashAlgorithm::Crc32c));
+        meta.hash = meta.hash_self();
+        assert!(meta.valid());
+        assert_eq!(meta.hash[4..], [0; 28]);
+        // the checksums flag doesn't change the algorithm
+        meta.set_flags(meta.flags() | Meta::FLAG_CHECKSUMS);
+        assert_eq!(meta.hash_algorithm(), Some(HashAlgorithm::Crc32c));
+        assert!(!meta.valid());
+
+        // version 1 databases always use SHA3-256
+        meta.set_version(1);
+        assert_eq!(meta.hash_algorithm(), HashAlgorithm::from_id(0));
+
+        meta.set_version(2);
+        meta.set_flags(0xFF << HashAlgorithm::FLAGS_SHIFT);
+        assert_eq!(meta.hash_algorithm(), None);
+        assert!(!meta.valid());
     }
 }
@@ -0,0 +1,577 @@
+//! Migrating databases written in an older file format version to the current [`VERSION`].
+//!
+//! A [`Migration`] reads the latest meta page of a database, walks every page in use the same way
+//! [`check`](crate::DB::check) does, and upgrades each page one format version at a time.
+//! The result is either written back to the same file, or copied into a new one.
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::slice::from_raw_parts;
+use core2::io::SeekFrom;
+use hashbrown::HashMap;
+
+use crate::db::{read_meta, resolve_pagesize, VERSION};
+use crate::errors::{Error, Result};
+use crate::fs::{File, MemoryMap};
+use crate::meta::Meta;
+use crate::page::{Page, PageID, Pages};
+use crate::tx::check_pages;
+
+// The oldest file format version that can be migrated.
+const FIRST_VERSION: u32 = 1;
+
+// Describes the upgrade from each format version to the next,
+// `CHANGES[v - FIRST_VERSION]` being the upgrade from `v` to `v + 1`.
+const CHANGES: &[&str] = &["Branch, leaf and freelist pages store a checksum"];
+
+// Upgrades a page written in format `version` to `version + 1`, returning whether it changed.
+// In place migrations write upgraded pages over the old ones before the meta page is switched over,
+// so an upgrade may only change bytes that readers of the old version ignore.
+fn upgrade_page(version: u32, page: &mut Page, block_size: u64) -> Result<bool> {
+    match version {
+        // the checksum goes in what used to be padding
+        1 => {
+            let checksum = page
+                .compute_checksum(block_size)
+                .ok_or(Error::Corruption { page_id: page.id() })?;
+            page.set_checksum(checksum);
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+// Upgrades the meta of a database from format `version` to `version + 1`.
+fn upgrade_meta(version: u32, meta: &mut Meta) {
+    if version == 1 {
+        meta.set_flags(Meta::FLAG_CHECKSUMS);
+    }
+}
+
+/// What a [`Migration`] changed, or would have changed in a dry run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// Format version the database was written in
+    pub from_version: u32,
+    /// Format version of the migrated database
+    pub to_version: u32,
+    /// What changed between the two versions, one entry per version
+    pub changes: Vec<&'static str>,
+    /// Number of pages in use, not counting the meta pages or free pages
+    pub pages: u64,
+    /// Number of pages that were rewritten
+    pub pages_changed: u64,
+    /// Whether this was a dry run, so nothing was written
+    pub dry_run: bool,
+}
+
+/// Upgrades a database written in an older file format version to the current [`VERSION`].
+///
+/// This struct acts as a builder in the same way as [`OpenOptions`](crate::OpenOptions).
+///
+/// # Examples
+///
+/// ```no_run
+/// use jammdb::{File, Migration};
+/// # use jammdb::Error;
+///
+/// # fn main() -> Result<(), Error> {
+/// use std::sync::Arc;
+/// use jammdb::memfile::{FakeMap, MemFs};
+/// let file = File::new(Box::new(MemFs::global().open(&"my.db")?));
+/// let report = Migration::new().dry_run(true).in_place(Arc::new(FakeMap), file)?;
+/// println!("{} pages would be rewritten", report.pages_changed);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct Migration {
+    pagesize: Option<u64>,
+    dry_run: bool,
+}
+
+struct Source {
+    pages: Pages,
+    meta: Meta,
+    // upgraded copies of the blocks that changed
+    changed: HashMap<PageID, Vec<u8>>,
+    report: MigrationReport,
+}
+
+impl Migration {
+    /// Returns a new Migration, with the default values.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the pagesize of the database, only needed if its first meta page is damaged.
+    ///
+    /// Like [`OpenOptions::pagesize`](crate::OpenOptions::pagesize), the pagesize stored in the
+    /// database is used by default.
+    pub fn pagesize(mut self, pagesize: u64) -> Self {
+        self.pagesize = Some(pagesize);
+        self
+    }
+
+    /// Enables a dry run, which reads the database and reports what would change
+    /// without writing anything.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Migrates the database in `file`, writing the upgraded pages back to it.
+    ///
+    /// The upgraded pages are synced before a new meta page with the current version is written
+    /// in place of the older meta page, the same way a transaction is committed,
+    /// so the database is in either the old or the new version if the migration is interrupted.
+    /// A database that is already in the current version is left untouched.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as [`OpenOptions::open`](crate::OpenOptions::open) for files that aren't
+    /// valid databases, and an [`Error::InvalidDB`] if [`check`](crate::DB::check) would fail.
+    pub fn in_place(&self, mmap: Arc<dyn MemoryMap>, mut file: File) -> Result<MigrationReport> {
+        locked(&mut file, |file| {
+            let source = self.read(mmap, file)?;
+            if !self.dry_run && source.meta.version() != VERSION {
+                let pagesize = source.pages.pagesize;
+                for (page_id, buf) in source.changed.iter() {
+                    file.seek(SeekFrom::Start(page_id * pagesize))?;
+                    file.write_all(buf)?;
+                }
+                file.flush()?;
+                file.sync_all()?;
+
+                let mut meta = source.meta.clone();
+                meta.set_tx_id(meta.tx_id() + 1);
+                let meta_page_id = u64::from(meta.meta_page() == 0);
+                file.seek(SeekFrom::Start(meta_page_id * pagesize))?;
+                file.write_all(&meta_page(&meta, meta_page_id, pagesize))?;
+                file.flush()?;
+                file.sync_all()?;
+            }
+            Ok(source.report)
+        })
+    }
+
+    /// Migrates the database in `src` into `dest`, leaving `src` untouched.
+    ///
+    /// Pages keep their ids, so `dest` ends up the same size as `src`. Anything already in
+    /// `dest` is overwritten.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`in_place`](#method.in_place).
+    pub fn into_file(
+        &self,
+        mmap: Arc<dyn MemoryMap>,
+        mut src: File,
+        mut dest: File,
+    ) -> Result<MigrationReport> {
+        locked(&mut src, |src| {
+            let source = self.read(mmap, src)?;
+            if !self.dry_run {
+                locked(&mut dest, |dest| self.write(&source, dest))?;
+            }
+            Ok(source.report)
+        })
+    }
+
+    fn write(&self, source: &Source, dest: &mut File) -> Result<()> {
+        let pagesize = source.pages.pagesize;
+        let meta = &source.meta;
+        dest.allocate(meta.num_pages() * pagesize)?;
+        dest.seek(SeekFrom::Start(0))?;
+        for meta_page_id in 0..2 {
+            dest.write_all(&meta_page(meta, meta_page_id, pagesize))?;
+        }
+        let mut page_id = 2;
+        while page_id < meta.num_pages() {
+            match source.changed.get(&page_id) {
+                Some(buf) => {
+                    dest.write_all(buf)?;
+                    page_id += buf.len() as u64 / pagesize;
+                }
+                None => {
+                    let buf = source
+                        .pages
+                        .data
+                        .index_range(page_id, 1, pagesize as usize)?;
+                    dest.write_all(buf)?;
+                    page_id += 1;
+                }
+            }
+        }
+        dest.flush()?;
+        dest.sync_all()?;
+        Ok(())
+    }
+
+    fn read(&self, mmap: Arc<dyn MemoryMap>, file: &mut File) -> Result<Source> {
+        let data = mmap.do_map(file)?;
+        let pagesize = resolve_pagesize(self.pagesize, &*data)?;
+        let pages = Pages::new(data, pagesize);
+        let meta = read_meta(&pages)?;
+        let pages = pages.verify_checksums(meta.checksums());
+        if !(FIRST_VERSION..=VERSION).contains(&meta.version()) {
+            return Err(Error::UnsupportedVersion {
+                found: meta.version(),
+                supported: FIRST_VERSION..=VERSION,
+            });
+        }
+
+        let mut changed = HashMap::new();
+        let mut num_pages = 0;
+        let mut pages_changed = 0;
+        check_pages(&pages, &meta, |page_id, page| {
+            let block_pages = page.overflow() + 1;
+            num_pages += block_pages;
+            let mut buf = unsafe {
+                from_raw_parts(
+                    page as *const Page as *const u8,
+                    (block_pages * pagesize) as usize,
+                )
+            }
+            .to_vec();
+            #[allow(clippy::cast_ptr_alignment)]
+            let page = unsafe { &mut *(&mut buf[0] as *mut u8 as *mut Page) };
+            let mut upgraded = false;
+            for version in meta.version()..VERSION {
+                upgraded |= upgrade_page(version, page, block_pages * pagesize)?;
+            }
+            if upgraded {
+                pages_changed += block_pages;
+                changed.insert(page_id, buf);
+            }
+            Ok(())
+        })?;
+
+        let report = MigrationReport {
+            from_version: meta.version(),
+            to_version: VERSION,
+            changes: (meta.version()..VERSION)
+                .map(|version| CHANGES[(version - FIRST_VERSION) as usize])
+                .collect(),
+            pages: num_pages,
+            pages_changed,
+            dry_run: self.dry_run,
+        };
+        Ok(Source {
+            pages,
+            meta,
+            changed,
+            report,
+        })
+    }
+}
+
+// Runs `f` with `file` locked, and unlocks it again whether or not `f` succeeds
+fn locked<T>(file: &mut File, f: impl FnOnce(&mut File) -> Result<T>) -> Result<T> {
+    file.lock_exclusive()?;
+    let result = f(file);
+    let unlocked = file.unlock();
+    let value = result?;
+    unlocked?;
+    Ok(value)
+}
+
+// A meta page for `meta` upgraded to the current version, to be written at `meta_page_id`.
+fn meta_page(meta: &Meta, meta_page_id: u64, pagesize: u64) -> Vec<u8> {
+    let mut buf = vec![0; pagesize as usize];
+    #[allow(clippy::cast_ptr_alignment)]
+    let page = unsafe { &mut *(&mut buf[0] as *mut u8 as *mut Page) };
+    page.set_id(meta_page_id);
+    page.page_type = Page::TYPE_META;
+    let m = page.meta_mut();
+    *m = meta.clone();
+    m.set_meta_page(meta_page_id as u32);
+    for version in meta.version()..VERSION {
+        upgrade_meta(version, m);
+    }
+    m.set_version(VERSION);
+    m.hash = m.hash_self();
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::{DbFile, FileExt, IOResult, IndexByPageID, MetaData};
+    use crate::memfile::{FakeMap, MemFs};
+    use crate::{OpenOptions, DB};
+    use alloc::boxed::Box;
+    use alloc::format;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use core2::io::{Error as IOError, ErrorKind, Read, Seek, Write};
+
+    fn create_db(fs: &MemFs, path: &str, checksums: bool) -> Result<()> {
+        let db = OpenOptions::new()
+            .pagesize(1024)
+            .checksums(checksums)
+            .open_with(Arc::new(FakeMap), fs.options(), path)?;
+        let tx = db.tx(true)?;
+        let b = tx.create_bucket("abc")?;
+        for i in 0..500_u64 {
+            b.put(i.to_be_bytes(), format!("value {i}"))?;
+        }
+        b.create_bucket("nested")?.put("key", "value")?;
+        tx.commit()
+    }
+
+    // rewrites both meta pages of the database, rehashing them after `edit`
+    fn edit_metas(fs: &MemFs, path: &str, edit: impl Fn(&mut Meta)) -> Result<()> {
+        let db = OpenOptions::new().open_with(Arc::new(FakeMap), fs.options(), path)?;
+        let mut file = db.file();
+        let meta = db.inner.meta()?;
+        for meta_page_id in 0..2 {
+            let mut buf = meta_page(&meta, meta_page_id, db.pagesize());
+            #[allow(clippy::cast_ptr_alignment)]
+            let page = unsafe { &mut *(&mut buf[0] as *mut u8 as *mut Page) };
+            let m = page.meta_mut();
+            edit(m);
+            m.hash = m.hash_self();
+            file.seek(SeekFrom::Start(meta_page_id * db.pagesize()))?;
+            file.write_all(&buf)?;
+        }
+        file.flush()?;
+        Ok(())
+    }
+
+    fn assert_db(db: &DB) -> Result<()> {
+        db.check()?;
+        let tx = db.tx(false)?;
+        let b = tx.get_bucket("abc")?;
+        for i in 0..500_u64 {
+            let kv = b.get_kv(i.to_be_bytes())?.unwrap();
+            assert_eq!(kv.value(), format!("value {i}").as_bytes());
+        }
+        assert_eq!(
+            b.get_bucket("nested")?.get_kv("key")?.unwrap().value(),
+            b"value"
+        );
+        Ok(())
+    }
+
+    fn file(fs: &MemFs, path: &str) -> Result<File> {
+        Ok(File::new(Box::new(fs.open(&path)?)))
+    }
+
+    #[test]
+    fn test_migrate_current_version() -> Result<()> {
+        let fs = MemFs::new();
+        create_db(&fs, "a", true)?;
+        let before = fs.len(&"a")?;
+        let report = Migration::new().in_place(Arc::new(FakeMap), file(&fs, "a")?)?;
+        assert_eq!(report.from_version, VERSION);
+        assert_eq!(report.to_version, VERSION);
+        assert!(report.changes.is_empty());
+        assert!(report.pages > 2);
+        assert_eq!(report.pages_changed, 0);
+        assert!(!report.dry_run);
+        assert_eq!(fs.len(&"a")?, before);
+
+        let db = OpenOptions::new().open_with(Arc::new(FakeMap), fs.options(), "a")?;
+        assert_db(&db)
+    }
+
+    #[test]
+    fn test_migrate_into_file() -> Result<()> {
+        let fs = MemFs::new();
+        create_db(&fs, "a", true)?;
+
+        let report = Migration::new().dry_run(true).into_file(
+            Arc::new(FakeMap),
+            file(&fs, "a")?,
+            File::new(Box::new(fs.create(&"b")?)),
+        )?;
+        assert!(report.dry_run);
+        assert_eq!(fs.len(&"b")?, 0);
+
+        let dry_report = report;
+        let report =
+            Migration::new().into_file(Arc::new(FakeMap), file(&fs, "a")?, file(&fs, "b")?)?;
+        assert!(!report.dry_run);
+        assert_eq!(report.pages, dry_report.pages);
+        assert_eq!(report.pages_changed, dry_report.pages_changed);
+
+        let db = OpenOptions::new().open_with(Arc::new(FakeMap), fs.options(), "b")?;
+        assert_db(&db)
+    }
+
+    #[test]
+    #[cfg(feature = "sha3")]
+    fn test_migrate_checksums() -> Result<()> {
+        let fs = MemFs::new();
+        create_db(&fs, "a", false)?;
+        edit_metas(&fs, "a", |meta| {
+            meta.set_version(1);
+            meta.set_flags(0);
+        })?;
+        // version 1 databases can still be opened
+        {
+            let db = OpenOptions::new().open_with(Arc::new(FakeMap), fs.options(), "a")?;
+            assert_eq!(db.inner.meta()?.version(), 1);
+            assert_db(&db)?;
+        }
+
+        let report = Migration::new()
+            .dry_run(true)
+            .in_place(Arc::new(FakeMap), file(&fs, "a")?)?;
+        assert_eq!(report.from_version, 1);
+        assert_eq!(report.to_version, VERSION);
+        assert_eq!(report.changes, CHANGES);
+        assert_eq!(report.pages_changed, report.pages);
+        let db = OpenOptions::new().open_with(Arc::new(FakeMap), fs.options(), "a")?;
+        assert_eq!(db.inner.meta()?.version(), 1);
+        drop(db);
+
+        let report = Migration::new().in_place(Arc::new(FakeMap), file(&fs, "a")?)?;
+        assert!(!report.dry_run);
+        assert_eq!(report.pages_changed, report.pages);
+        let db = OpenOptions::new().open_with(Arc::new(FakeMap), fs.options(), "a")?;
+        let meta = db.inner.meta()?;
+        assert_eq!(meta.version(), VERSION);
+        assert!(meta.checksums());
+        assert_db(&db)?;
+        drop(db);
+
+        // migrating again has nothing left to do
+        let report = Migration::new().in_place(Arc::new(FakeMap), file(&fs, "a")?)?;
+        assert!(report.changes.is_empty());
+        assert_eq!(report.pages_changed, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_unsupported() -> Result<()> {
+        let fs = MemFs::new();
+        create_db(&fs, "a", true)?;
+        edit_metas(&fs, "a", |meta| meta.set_version(VERSION + 1))?;
+        let result = Migration::new().in_place(Arc::new(FakeMap), file(&fs, "a")?);
+        assert_eq!(
+            result.err(),
+            Some(Error::UnsupportedVersion {
+                found: VERSION + 1,
+                supported: FIRST_VERSION..=VERSION,
+            })
+        );
+        Ok(())
+    }
+
+    // A memory file that records whether it is locked, and can be made to fail its writes
+    struct Tracked {
+        inner: File,
+        locked: Arc<AtomicBool>,
+        fail_writes: bool,
+    }
+
+    impl Seek for Tracked {
+        fn seek(&mut self, pos: SeekFrom) -> IOResult<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    impl Read for Tracked {
+        fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for Tracked {
+        fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+            match self.fail_writes {
+                true => Err(IOError::new(ErrorKind::Other, "write failed")),
+                false => self.inner.write(buf),
+            }
+        }
+        fn flush(&mut self) -> IOResult<()> {
+            self.inner.flush()
+        }
+    }
+
+    impl FileExt for Tracked {
+        fn lock_exclusive(&self) -> IOResult<()> {
+            self.locked.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+        fn lock_shared(&self) -> IOResult<()> {
+            self.lock_exclusive()
+        }
+        fn allocate(&mut self, new_size: u64) -> IOResult<()> {
+            self.inner.allocate(new_size)
+        }
+        fn unlock(&self) -> IOResult<()> {
+            self.locked.store(false, Ordering::Relaxed);
+            Ok(())
+        }
+        fn metadata(&self) -> IOResult<MetaData> {
+            self.inner.metadata()
+        }
+        fn sync_all(&self) -> IOResult<()> {
+            self.inner.sync_all()
+        }
+        fn size(&self) -> usize {
+            self.inner.size()
+        }
+        fn addr(&self) -> usize {
+            self.inner.addr()
+        }
+    }
+
+    impl DbFile for Tracked {}
+
+    struct TrackedMap;
+
+    impl MemoryMap for TrackedMap {
+        fn do_map(&self, file: &mut File) -> IOResult<Arc<dyn IndexByPageID>> {
+            let file = file
+                .file
+                .downcast_mut::<Tracked>()
+                .map_err(|_| IOError::new(ErrorKind::InvalidInput, "not a Tracked file"))?;
+            FakeMap.do_map(&mut file.inner)
+        }
+    }
+
+    fn tracked(fs: &MemFs, path: &str, fail_writes: bool) -> Result<(File, Arc<AtomicBool>)> {
+        let locked = Arc::new(AtomicBool::new(false));
+        let file = Tracked {
+            inner: file(fs, path)?,
+            locked: locked.clone(),
+            fail_writes,
+        };
+        Ok((File::new(Box::new(file)), locked))
+    }
+
+    #[test]
+    fn test_migrate_unlocks_on_error() -> Result<()> {
+        let fs = MemFs::new();
+        create_db(&fs, "a", true)?;
+        create_db(&fs, "newer", true)?;
+        fs.create(&"b")?;
+
+        // the source isn't a database it can migrate
+        edit_metas(&fs, "newer", |meta| meta.set_version(VERSION + 1))?;
+        let (src, src_locked) = tracked(&fs, "newer", false)?;
+        assert!(Migration::new()
+            .in_place(Arc::new(TrackedMap), src)
+            .is_err());
+        assert!(!src_locked.load(Ordering::Relaxed));
+
+        // writing the destination fails
+        let (src, src_locked) = tracked(&fs, "a", false)?;
+        let (dest, dest_locked) = tracked(&fs, "b", true)?;
+        let result = Migration::new().into_file(Arc::new(TrackedMap), src, dest);
+        assert!(matches!(result, Err(Error::Io(_))));
+        assert!(!src_locked.load(Ordering::Relaxed));
+        assert!(!dest_locked.load(Ordering::Relaxed));
+
+        // and both are unlocked when it works
+        let (src, src_locked) = tracked(&fs, "a", false)?;
+        let (dest, dest_locked) = tracked(&fs, "b", false)?;
+        Migration::new().into_file(Arc::new(TrackedMap), src, dest)?;
+        assert!(!src_locked.load(Ordering::Relaxed));
+        assert!(!dest_locked.load(Ordering::Relaxed));
+        Ok(())
+    }
+}
@@ -16,6 +16,7 @@ const BRANCH_SIZE: u64 = size_of::<BranchElement>() as u64;
 const MIN_KEYS_PER_NODE: usize = 2;
 const FILL_PERCENT: f32 = 0.5;
 
+#[derive(Clone)]
 pub(crate) struct Node<'n> {
     pub(crate) id: NodeID,
     pub(crate) page_id: PageID,
@@ -57,17 +58,17 @@ impl<'n> Node<'n> {
     pub(crate) fn from_page(id: NodeID, p: &Page, pagesize: u64) -> Node<'n> {
         let data: NodeData = match p.page_type {
             Page::TYPE_BRANCH => {
-                let mut data = Vec::with_capacity(p.count as usize);
+                let mut data = Vec::with_capacity(p.count() as usize);
                 for branch in p.branch_elements() {
                     data.push(Branch {
                         key: Bytes::Slice(branch.key()),
-                        page: branch.page,
+                        page: branch.page(),
                     });
                 }
                 NodeData::Branches(data)
             }
             Page::TYPE_LEAF => {
-                let mut data = Vec::with_capacity(p.count as usize);
+                let mut data = Vec::with_capacity(p.count() as usize);
                 for leaf in p.leaf_elements() {
                     data.push(Leaf::from_leaf(leaf));
                 }
@@ -82,8 +83,8 @@ impl<'n> Node<'n> {
         };
         Node {
             id,
-            page_id: p.id,
-            num_pages: p.overflow + 1,
+            page_id: p.id(),
+            num_pages: p.overflow() + 1,
             children: Vec::new(),
             data,
             deleted: false,
@@ -370,8 +371,8 @@ impl<'n> Node<'n> {
         self.free_page(tx_freelist);
         let size = self.size();
         let page = tx_freelist.allocate(size);
-        self.page_id = page.id;
-        self.num_pages = page.overflow + 1;
+        self.page_id = page.id();
+        self.num_pages = page.overflow() + 1;
         page
     }
 
@@ -384,6 +385,7 @@ impl<'n> Node<'n> {
     }
 }
 
+#[derive(Clone)]
 pub(crate) enum NodeData<'a> {
     Branches(Vec<Branch<'a>>),
     Leaves(Vec<Leaf<'a>>),
@@ -445,6 +447,7 @@ impl<'a> NodeData<'a> {
     }
 }
 
+#[derive(Clone)]
 pub(crate) struct Branch<'a> {
     key: Bytes<'a>,
     pub(crate) page: PageID,
@@ -1,27 +1,51 @@
-use crate::errors::Result;
+use crate::endian::{U32, U64};
+use crate::errors::{Error, Result};
 
 use crate::meta::Meta;
 use crate::node::{Node, NodeData, NodeType};
 use crate::IndexByPageID;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use core::mem::size_of;
+use core::mem::{offset_of, size_of};
 use core::slice::{from_raw_parts, from_raw_parts_mut};
+use core::sync::atomic::{AtomicU64, Ordering};
 use core2::io::Write;
+use crc::{Crc, CRC_32_ISCSI};
 
 pub(crate) type PageID = u64;
 
 pub(crate) type PageType = u8;
 
-#[derive(Clone)]
+// CRC-32C, the checksum stored in the header of branch, leaf and freelist pages
+pub(crate) const CRC32C: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+
+// Pages pins the underlying index for as long as it (or any clone of it) is alive,
+// so the page references it hands out stay valid.
 pub(crate) struct Pages {
     pub(crate) data: Arc<dyn IndexByPageID>,
     pub(crate) pagesize: u64,
+    pin: u64,
+    // pages whose checksum was already verified, shared between clones.
+    // `None` if the database doesn't store checksums.
+    verified: Option<Arc<Verified>>,
 }
 
 impl Pages {
     pub fn new(data: Arc<dyn IndexByPageID>, pagesize: u64) -> Pages {
-        Pages { data, pagesize }
+        let pin = data.pin();
+        Pages {
+            data,
+            pagesize,
+            pin,
+            verified: None,
+        }
+    }
+
+    // Verify the checksum of each page the first time it is read.
+    pub fn verify_checksums(mut self, verify: bool) -> Pages {
+        let num_pages = self.data.len() as u64 / self.pagesize;
+        self.verified = verify.then(|| Arc::new(Verified::new(num_pages)));
+        self
     }
 
     // #[inline]
@@ -32,10 +56,105 @@ impl Pages {
     //     }
     // }
 
+    // The returned page covers its whole block, overflow pages included,
+    // so its elements can be read past the end of the first page.
     #[inline]
-    pub fn page<'a>(&self, id: PageID) -> &'a Page {
-        let buf = self.data.index(id, self.pagesize as usize).unwrap();
-        unsafe { &*(&buf[0] as *const u8 as *const Page) }
+    pub fn page<'a>(&self, id: PageID) -> Result<&'a Page> {
+        let buf = self.block(id, 1)?;
+        let overflow = unsafe { &*(&buf[0] as *const u8 as *const Page) }.overflow();
+        let buf = if overflow > 0 {
+            let block = overflow
+                .checked_add(1)
+                .ok_or(Error::PageOutOfRange { page_id: id })
+                .and_then(|count| self.block(id, count));
+            match block {
+                Ok(buf) => buf,
+                // a bad overflow count is just as likely to be a corrupted header
+                Err(Error::PageOutOfRange { .. }) if self.verified.is_some() => {
+                    return Err(Error::Corruption { page_id: id })
+                }
+                Err(e) => return Err(e),
+            }
+        } else {
+            buf
+        };
+        let page = unsafe { &*(&buf[0] as *const u8 as *const Page) };
+        if let Some(verified) = &self.verified {
+            if !verified.contains(id) {
+                if page.compute_checksum(buf.len() as u64) != Some(page.checksum()) {
+                    return Err(Error::Corruption { page_id: id });
+                }
+                verified.insert(id);
+            }
+        }
+        Ok(page)
+    }
+
+    // Meta pages never overflow, so only their first page is read,
+    // even if a corrupted header claims otherwise.
+    // Returns `None` if the page isn't a meta page, the meta itself still has to be validated.
+    pub fn meta<'a>(&self, id: PageID) -> Result<Option<&'a Meta>> {
+        let buf = self.block(id, 1)?;
+        let page = unsafe { &*(&buf[0] as *const u8 as *const Page) };
+        if page.page_type != Page::TYPE_META {
+            return Ok(None);
+        }
+        Ok(Some(page.meta()))
+    }
+
+    fn block<'a>(&self, id: PageID, count: u64) -> Result<&'a [u8]> {
+        let end = id
+            .checked_add(count)
+            .and_then(|end| end.checked_mul(self.pagesize));
+        match end {
+            Some(end) if end <= self.data.len() as u64 => (),
+            _ => return Err(Error::PageOutOfRange { page_id: id }),
+        }
+        let buf = self.data.index_range(id, count, self.pagesize as usize)?;
+        // the pin taken in `new` keeps the block alive for as long as the page is in use
+        Ok(unsafe { core::slice::from_raw_parts(buf.as_ptr(), buf.len()) })
+    }
+}
+
+// A bitset of verified pages that readers can share without taking a lock.
+// Two readers may both verify a page the first time, which is harmless.
+struct Verified(Vec<AtomicU64>);
+
+impl Verified {
+    fn new(num_pages: u64) -> Verified {
+        Verified(
+            (0..num_pages.div_ceil(64))
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+        )
+    }
+
+    fn contains(&self, id: PageID) -> bool {
+        match self.0.get((id / 64) as usize) {
+            Some(word) => word.load(Ordering::Acquire) & (1 << (id % 64)) != 0,
+            None => false,
+        }
+    }
+
+    // pages past the end of the set are left out, and verified every time they're read
+    fn insert(&self, id: PageID) {
+        if let Some(word) = self.0.get((id / 64) as usize) {
+            word.fetch_or(1 << (id % 64), Ordering::Release);
+        }
+    }
+}
+
+impl Clone for Pages {
+    fn clone(&self) -> Self {
+        let mut pages = Pages::new(self.data.clone(), self.pagesize);
+        pages.verified = self.verified.clone();
+        pages
+    }
+}
+
+impl Drop for Pages {
+    fn drop(&mut self) {
+        self.data.unpin(self.pin);
     }
 }
 
@@ -43,14 +162,19 @@ impl Pages {
 #[derive(Debug)]
 pub(crate) struct Page {
     // id * pagesize is the offset from the beginning of the file
-    pub(crate) id: PageID,
+    id: U64,
     pub(crate) page_type: PageType,
+    // Always zero, so the header the checksum covers has no uninitialized bytes
+    padding: [u8; 3],
+    // CRC-32C of the page, if the database stores checksums.
+    // This used to be padding, so older versions of the format just ignore it.
+    checksum: U32,
     // Number of elements on this page, the type of element depends on the pageType
-    pub(crate) count: u64,
+    count: U64,
     // Number of additional pages after this one that are part of this block
-    pub(crate) overflow: u64,
+    overflow: U64,
     // ptr serves as a reference to where the actual data starts
-    pub(crate) ptr: u64,
+    ptr: u64,
 }
 
 impl Page {
@@ -59,6 +183,46 @@ impl Page {
     pub(crate) const TYPE_META: PageType = 0x03;
     pub(crate) const TYPE_FREELIST: PageType = 0x04;
 
+    #[inline]
+    pub(crate) fn id(&self) -> PageID {
+        self.id.get()
+    }
+
+    #[inline]
+    pub(crate) fn set_id(&mut self, id: PageID) {
+        self.id.set(id);
+    }
+
+    #[inline]
+    pub(crate) fn checksum(&self) -> u32 {
+        self.checksum.get()
+    }
+
+    #[inline]
+    pub(crate) fn set_checksum(&mut self, checksum: u32) {
+        self.checksum.set(checksum);
+    }
+
+    #[inline]
+    pub(crate) fn count(&self) -> u64 {
+        self.count.get()
+    }
+
+    #[inline]
+    pub(crate) fn set_count(&mut self, count: u64) {
+        self.count.set(count);
+    }
+
+    #[inline]
+    pub(crate) fn overflow(&self) -> u64 {
+        self.overflow.get()
+    }
+
+    #[inline]
+    pub(crate) fn set_overflow(&mut self, overflow: u64) {
+        self.overflow.set(overflow);
+    }
+
     #[allow(unused)]
     #[inline]
     pub(crate) fn from_buf(buf: &[u8], id: PageID, pagesize: u64) -> &Page {
@@ -67,14 +231,53 @@ impl Page {
             &*(&buf[(id * pagesize) as usize] as *const u8 as *const Page)
         }
     }
-    #[inline]
-    pub(crate) fn from_index(
-        index_map: &Arc<dyn IndexByPageID>,
-        id: PageID,
-        pagesize: u64,
-    ) -> &Page {
-        let buf = index_map.index(id, pagesize as usize).unwrap();
-        unsafe { &*(&buf[0] as *const u8 as *const Page) }
+    // Number of bytes at the start of the block that hold the page's data,
+    // or `None` if the header doesn't fit in a block of `block_size` bytes.
+    fn used_size(&self, block_size: u64) -> Option<u64> {
+        let header = offset_of!(Page, ptr) as u64;
+        let elements = |size: usize| {
+            self.count()
+                .checked_mul(size as u64)
+                .and_then(|size| size.checked_add(header))
+                .filter(|size| *size <= block_size)
+        };
+        let size = match self.page_type {
+            Page::TYPE_FREELIST => elements(size_of::<U64>())?,
+            Page::TYPE_BRANCH => {
+                let size = elements(size_of::<BranchElement>())?;
+                match self.branch_elements().last() {
+                    // each element's data comes after the data of the ones before it
+                    Some(last) => (size - size_of::<BranchElement>() as u64)
+                        .checked_add(last.pos())?
+                        .checked_add(last.key_size())?,
+                    None => size,
+                }
+            }
+            Page::TYPE_LEAF => {
+                let size = elements(size_of::<LeafElement>())?;
+                match self.leaf_elements().last() {
+                    Some(last) => (size - size_of::<LeafElement>() as u64)
+                        .checked_add(last.pos())?
+                        .checked_add(last.key_size())?
+                        .checked_add(last.value_size())?,
+                    None => size,
+                }
+            }
+            _ => return None,
+        };
+        Some(size).filter(|size| *size <= block_size)
+    }
+
+    // Computes the checksum of the page's data, not including the checksum itself.
+    // The rest of the block isn't written, so it isn't covered.
+    pub(crate) fn compute_checksum(&self, block_size: u64) -> Option<u32> {
+        let size = self.used_size(block_size)? as usize;
+        let buf = unsafe { from_raw_parts(self as *const Page as *const u8, size) };
+        let offset = offset_of!(Page, checksum);
+        let mut digest = CRC32C.digest();
+        digest.update(&buf[..offset]);
+        digest.update(&buf[offset + size_of::<U32>()..]);
+        Some(digest.finalize())
     }
 
     pub(crate) fn meta(&self) -> &Meta {
@@ -87,19 +290,19 @@ impl Page {
         unsafe { &mut *(&mut self.ptr as *mut u64 as *mut Meta) }
     }
 
-    pub(crate) fn freelist(&self) -> &[PageID] {
+    pub(crate) fn freelist(&self) -> &[U64] {
         assert_eq!(self.page_type, Page::TYPE_FREELIST);
         unsafe {
-            let start = &self.ptr as *const u64 as *const PageID;
-            from_raw_parts(start, self.count as usize)
+            let start = &self.ptr as *const u64 as *const U64;
+            from_raw_parts(start, self.count() as usize)
         }
     }
 
-    pub(crate) fn freelist_mut(&mut self) -> &mut [PageID] {
+    pub(crate) fn freelist_mut(&mut self) -> &mut [U64] {
         assert_eq!(self.page_type, Page::TYPE_FREELIST);
         unsafe {
-            let start = &self.ptr as *const u64 as *mut PageID;
-            from_raw_parts_mut(start, self.count as usize)
+            let start = &self.ptr as *const u64 as *mut U64;
+            from_raw_parts_mut(start, self.count() as usize)
         }
     }
 
@@ -107,7 +310,7 @@ impl Page {
         assert_eq!(self.page_type, Page::TYPE_LEAF);
         unsafe {
             let start = &self.ptr as *const u64 as *const LeafElement;
-            from_raw_parts(start, self.count as usize)
+            from_raw_parts(start, self.count() as usize)
         }
     }
 
@@ -115,7 +318,7 @@ impl Page {
         assert_eq!(self.page_type, Page::TYPE_BRANCH);
         unsafe {
             let start = &self.ptr as *const u64 as *const BranchElement;
-            from_raw_parts(start, self.count as usize)
+            from_raw_parts(start, self.count() as usize)
         }
     }
 
@@ -123,7 +326,7 @@ impl Page {
         assert_eq!(self.page_type, Page::TYPE_LEAF);
         unsafe {
             let start = &self.ptr as *const u64 as *const LeafElement as *mut LeafElement;
-            from_raw_parts_mut(start, self.count as usize)
+            from_raw_parts_mut(start, self.count() as usize)
         }
     }
 
@@ -131,7 +334,7 @@ impl Page {
         assert_eq!(self.page_type, Page::TYPE_BRANCH);
         unsafe {
             let start = &self.ptr as *const u64 as *const BranchElement as *mut BranchElement;
-            from_raw_parts_mut(start, self.count as usize)
+            from_raw_parts_mut(start, self.count() as usize)
         }
     }
 
@@ -143,9 +346,10 @@ impl Page {
     }
 
     pub(crate) fn write_node(&mut self, n: &Node, num_pages: u64) -> Result<()> {
-        debug_assert!(self.id == n.page_id);
-        debug_assert!(self.overflow == num_pages - 1);
-        self.count = n.data.len() as u64;
+        debug_assert!(self.id() == n.page_id);
+        debug_assert!(self.overflow() == num_pages - 1);
+        let count = n.data.len() as u64;
+        self.set_count(count);
         let header_size;
         let mut data_size: u64 = 0;
         let mut data: Vec<&[u8]>;
@@ -154,14 +358,15 @@ impl Page {
                 self.page_type = Page::TYPE_BRANCH;
                 header_size = size_of::<BranchElement>() as u64;
                 let mut header_offsets = header_size * (branches.len() as u64);
-                data = Vec::with_capacity(self.count as usize);
+                data = Vec::with_capacity(count as usize);
                 let elems = self.branch_elements_mut();
                 for (b, elem) in branches.iter().zip(elems.iter_mut()) {
                     debug_assert!(b.page > 1, "Branch should not point to page {}", b.page);
-                    elem.page = b.page;
-                    elem.key_size = b.key_size() as u64;
-                    elem.pos = header_offsets + data_size;
-                    data_size += elem.key_size;
+                    let key_size = b.key_size() as u64;
+                    elem.page.set(b.page);
+                    elem.key_size.set(key_size);
+                    elem.pos.set(header_offsets + data_size);
+                    data_size += key_size;
                     header_offsets -= header_size;
                     data.push(b.key());
                 }
@@ -170,18 +375,18 @@ impl Page {
                 self.page_type = Page::TYPE_LEAF;
                 header_size = size_of::<LeafElement>() as u64;
                 let mut header_offsets = header_size * (leaves.len() as u64);
-                data = Vec::with_capacity(self.count as usize * 2);
+                data = Vec::with_capacity(count as usize * 2);
                 let elems = self.leaf_elements_mut();
                 for (l, elem) in leaves.iter().zip(elems.iter_mut()) {
                     elem.node_type = l.node_type();
 
                     let key = l.key();
                     let value = l.value();
-                    elem.key_size = key.len() as u64;
-                    elem.value_size = value.len() as u64;
-                    elem.pos = header_offsets + data_size;
+                    elem.key_size.set(key.len() as u64);
+                    elem.value_size.set(value.len() as u64);
+                    elem.pos.set(header_offsets + data_size);
 
-                    data_size += elem.key_size + elem.value_size;
+                    data_size += (key.len() + value.len()) as u64;
                     header_offsets -= header_size;
 
                     data.push(key);
@@ -189,7 +394,7 @@ impl Page {
                 }
             }
         };
-        let total_header = header_size * self.count;
+        let total_header = header_size * count;
         let buf = self.slice(total_header + data_size);
         let mut buf = &mut buf[(total_header as usize)..];
         for b in data.iter() {
@@ -201,17 +406,32 @@ impl Page {
 
 #[repr(C)]
 pub(crate) struct BranchElement {
-    pub(crate) page: PageID,
-    key_size: u64,
-    pos: u64,
+    page: U64,
+    key_size: U64,
+    pos: U64,
 }
 
 impl BranchElement {
+    #[inline]
+    pub(crate) fn page(&self) -> PageID {
+        self.page.get()
+    }
+
+    #[inline]
+    fn key_size(&self) -> u64 {
+        self.key_size.get()
+    }
+
+    #[inline]
+    fn pos(&self) -> u64 {
+        self.pos.get()
+    }
+
     pub(crate) fn key<'a>(&self) -> &'a [u8] {
-        let pos = self.pos as usize;
+        let pos = self.pos() as usize;
         unsafe {
             let start = self as *const BranchElement as *const u8;
-            let buf = core::slice::from_raw_parts(start, pos + (self.key_size as usize));
+            let buf = core::slice::from_raw_parts(start, pos + (self.key_size() as usize));
             &buf[pos..]
         }
     }
@@ -220,26 +440,64 @@ impl BranchElement {
 #[repr(C)]
 pub(crate) struct LeafElement {
     pub(crate) node_type: NodeType,
-    pos: u64,
-    key_size: u64,
-    value_size: u64,
+    pos: U64,
+    key_size: U64,
+    value_size: U64,
 }
 
 impl LeafElement {
+    #[inline]
+    fn pos(&self) -> u64 {
+        self.pos.get()
+    }
+
+    #[inline]
+    fn key_size(&self) -> u64 {
+        self.key_size.get()
+    }
+
+    #[inline]
+    fn value_size(&self) -> u64 {
+        self.value_size.get()
+    }
+
     pub(crate) fn key<'a>(&self) -> &'a [u8] {
-        let pos = self.pos as usize;
+        let pos = self.pos() as usize;
         unsafe {
             let start = self as *const LeafElement as *const u8;
-            let buf = from_raw_parts(start, pos + self.key_size as usize);
+            let buf = from_raw_parts(start, pos + self.key_size() as usize);
             &buf[pos..]
         }
     }
     pub(crate) fn value<'a>(&self) -> &'a [u8] {
-        let pos = (self.pos + self.key_size) as usize;
+        let pos = (self.pos() + self.key_size()) as usize;
         unsafe {
             let start = self as *const LeafElement as *const u8;
-            let buf = core::slice::from_raw_parts(start, pos + self.value_size as usize);
+            let buf = core::slice::from_raw_parts(start, pos + self.value_size() as usize);
             &buf[pos..]
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verified() {
+        let verified = Verified::new(100);
+        assert!(!verified.contains(0));
+        verified.insert(0);
+        verified.insert(63);
+        verified.insert(64);
+        verified.insert(99);
+        assert!(verified.contains(0));
+        assert!(!verified.contains(1));
+        assert!(verified.contains(63));
+        assert!(verified.contains(64));
+        assert!(verified.contains(99));
+        // out of range pages are never remembered
+        verified.insert(128);
+        assert!(!verified.contains(128));
+    }
+}
@@ -19,7 +19,7 @@ pub(crate) enum PageNode<'a> {
 impl<'a> PageNode<'a> {
     pub fn id(&self) -> PageNodeID {
         match self {
-            PageNode::Page(p) => PageNodeID::Page(p.id),
+            PageNode::Page(p) => PageNodeID::Page(p.id()),
             PageNode::Node(n) => PageNodeID::Node(n.borrow().id),
         }
     }
@@ -32,7 +32,7 @@ impl<'a> PageNode<'a> {
 
     pub fn len(&self) -> usize {
         match self {
-            PageNode::Page(p) => p.count as usize,
+            PageNode::Page(p) => p.count() as usize,
             PageNode::Node(n) => n.borrow().data.len(),
         }
     }
@@ -40,11 +40,11 @@ impl<'a> PageNode<'a> {
     pub fn index_page(&self, index: usize) -> PageID {
         match self {
             PageNode::Page(p) => {
-                if index >= p.count as usize {
+                if index >= p.count() as usize {
                     return 0;
                 }
                 match p.page_type {
-                    Page::TYPE_BRANCH => p.branch_elements()[index].page,
+                    Page::TYPE_BRANCH => p.branch_elements()[index].page(),
                     _ => panic!("INVALID PAGE TYPE FOR INDEX_PAGE"),
                 }
             }
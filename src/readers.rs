@@ -0,0 +1,315 @@
+//! Tracks the open read-only transactions and snapshots without a lock,
+//! so starting and finishing a reader never waits on other readers or the writer.
+use alloc::boxed::Box;
+use core::hint::spin_loop;
+use core::mem::{size_of, transmute};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{fence, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+use crate::meta::Meta;
+
+// marks a slot that no reader is using
+const FREE: u64 = u64::MAX;
+const SLOTS_PER_CHUNK: usize = 64;
+
+struct Chunk {
+    slots: [AtomicU64; SLOTS_PER_CHUNK],
+    next: AtomicPtr<Chunk>,
+}
+
+impl Chunk {
+    fn new() -> Self {
+        Chunk {
+            slots: [const { AtomicU64::new(FREE) }; SLOTS_PER_CHUNK],
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+}
+
+/// A table with one slot per open reader, holding the ID of the transaction it reads.
+///
+/// Like LMDB's reader table, readers claim a free slot with a compare-and-swap,
+/// and the writer scans every slot to find the oldest reader. The table is a list of chunks
+/// that is only ever appended to, so it grows when every slot is taken, and slots never move.
+pub(crate) struct ReaderTable {
+    head: Chunk,
+    count: AtomicUsize,
+}
+
+impl ReaderTable {
+    pub(crate) fn new() -> Self {
+        ReaderTable {
+            head: Chunk::new(),
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    /// Registers a reader of the latest transaction, returning its meta and slot.
+    ///
+    /// `meta` reads the latest meta. The reader's slot is filled in before the meta is
+    /// read again to make sure no commit happened in between, so a writer that starts after the
+    /// commit that follows always sees the reader, and keeps the pages it can see.
+    pub(crate) fn register(&self, meta: impl Fn() -> Meta) -> (Meta, ReaderSlot) {
+        let mut current = meta();
+        let slot = self.claim(current.tx_id());
+        loop {
+            fence(Ordering::SeqCst);
+            let latest = meta();
+            if latest.tx_id() == current.tx_id() {
+                return (current, slot);
+            }
+            current = latest;
+            slot.get().store(current.tx_id(), Ordering::SeqCst);
+        }
+    }
+
+    /// Frees a slot returned by [`register`](#method.register).
+    pub(crate) fn release(&self, slot: &ReaderSlot) {
+        slot.get().store(FREE, Ordering::Release);
+        self.count.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// The number of registered readers
+    pub(crate) fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// The ID of the oldest transaction that is still being read, if any
+    pub(crate) fn oldest(&self) -> Option<u64> {
+        fence(Ordering::SeqCst);
+        self.chunks()
+            .flat_map(|chunk| chunk.slots.iter())
+            .map(|slot| slot.load(Ordering::SeqCst))
+            .filter(|tx_id| *tx_id != FREE)
+            .min()
+    }
+
+    fn claim(&self, tx_id: u64) -> ReaderSlot {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let mut chunk = &self.head;
+        loop {
+            for slot in chunk.slots.iter() {
+                if slot
+                    .compare_exchange(FREE, tx_id, Ordering::SeqCst, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    return ReaderSlot(NonNull::from(slot));
+                }
+            }
+            let mut next = chunk.next.load(Ordering::Acquire);
+            if next.is_null() {
+                // every slot is taken, so add a chunk, unless another reader beat us to it
+                let new = Box::into_raw(Box::new(Chunk::new()));
+                match chunk.next.compare_exchange(
+                    ptr::null_mut(),
+                    new,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => next = new,
+                    Err(existing) => {
+                        drop(unsafe { Box::from_raw(new) });
+                        next = existing;
+                    }
+                }
+            }
+            // Safety: chunks are only freed when the table is dropped
+            chunk = unsafe { &*next };
+        }
+    }
+
+    fn chunks(&self) -> impl Iterator<Item = &Chunk> {
+        let mut chunk = Some(&self.head);
+        core::iter::from_fn(move || {
+            let current = chunk?;
+            let next = current.next.load(Ordering::Acquire);
+            // Safety: chunks are only freed when the table is dropped
+            chunk = unsafe { next.as_ref() };
+            Some(current)
+        })
+    }
+}
+
+impl Drop for ReaderTable {
+    fn drop(&mut self) {
+        let mut next = *self.head.next.get_mut();
+        while !next.is_null() {
+            let mut chunk = unsafe { Box::from_raw(next) };
+            next = *chunk.next.get_mut();
+        }
+    }
+}
+
+const META_WORDS: usize = size_of::<Meta>() / size_of::<u64>();
+
+/// The meta of the latest commit, which readers can copy without taking a lock.
+///
+/// It's a seqlock: the writer bumps the sequence number to an odd value while it stores
+/// a new meta, and readers retry if the number was odd or changed while they copied it.
+/// Since the meta was validated when it was written, readers don't need to hash it again.
+pub(crate) struct LatestMeta {
+    seq: AtomicU64,
+    words: [AtomicU64; META_WORDS],
+}
+
+impl LatestMeta {
+    pub(crate) fn new() -> Self {
+        LatestMeta {
+            seq: AtomicU64::new(0),
+            words: [const { AtomicU64::new(0) }; META_WORDS],
+        }
+    }
+
+    pub(crate) fn load(&self) -> Meta {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq % 2 == 1 {
+                spin_loop();
+                continue;
+            }
+            let words: [u64; META_WORDS] =
+                core::array::from_fn(|i| self.words[i].load(Ordering::Relaxed));
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == seq {
+                // Safety: the meta is plain old data with no padding, and these words were copied from one
+                return unsafe { transmute::<[u64; META_WORDS], Meta>(words) };
+            }
+        }
+    }
+
+    /// Publishes a new meta.
+    ///
+    /// Only one thread can store at a time, which is the writer holding the file lock.
+    pub(crate) fn store(&self, meta: &Meta) {
+        // Safety: the meta is plain old data with no padding
+        let words = unsafe { transmute::<Meta, [u64; META_WORDS]>(meta.clone()) };
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        for (word, value) in self.words.iter().zip(words) {
+            word.store(value, Ordering::Relaxed);
+        }
+        self.seq.store(seq + 2, Ordering::Release);
+    }
+}
+
+/// A slot claimed by a reader, see [`ReaderTable::register`]
+///
+/// It points into the table of the `DB` it was registered with,
+/// so it must be released before that `DB` is dropped.
+pub(crate) struct ReaderSlot(NonNull<AtomicU64>);
+
+// Safety: the slot is an atomic, and the table it points into outlives it
+unsafe impl Send for ReaderSlot {}
+unsafe impl Sync for ReaderSlot {}
+
+impl ReaderSlot {
+    fn get(&self) -> &AtomicU64 {
+        // Safety: the table is kept alive by the reader that holds the slot
+        unsafe { self.0.as_ref() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::Result;
+    use crate::memfile::{FakeMap, FileOpenOptions};
+    use crate::testutil::RandomFile;
+    use crate::DB;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+
+    fn meta_with_id() -> Result<impl Fn(u64) -> Meta> {
+        let random_file = RandomFile::new();
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file)?;
+        let meta = db.inner.meta()?;
+        Ok(move |tx_id| {
+            let mut meta = meta.clone();
+            meta.set_tx_id(tx_id);
+            meta
+        })
+    }
+
+    #[test]
+    fn test_reader_table() -> Result<()> {
+        let meta = meta_with_id()?;
+        let table = ReaderTable::new();
+        assert_eq!(table.count(), 0);
+        assert_eq!(table.oldest(), None);
+
+        // enough readers to need more chunks
+        let slots: Vec<_> = (0..200).map(|i| table.register(|| meta(100 + i))).collect();
+        assert_eq!(table.count(), 200);
+        assert_eq!(table.chunks().count(), 4);
+        assert_eq!(table.oldest(), Some(100));
+
+        let mut slots = slots.into_iter();
+        for (slot, tx_id) in slots.by_ref().take(150).zip(101..) {
+            let (_, slot) = slot;
+            table.release(&slot);
+            assert_eq!(table.oldest(), Some(tx_id));
+        }
+        assert_eq!(table.count(), 50);
+        // freed slots are reused before the table grows
+        let (_, slot) = table.register(|| meta(5));
+        assert_eq!(table.oldest(), Some(5));
+        assert_eq!(table.chunks().count(), 4);
+        table.release(&slot);
+        for (_, slot) in slots {
+            table.release(&slot);
+        }
+        assert_eq!(table.count(), 0);
+        assert_eq!(table.oldest(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_register_retries() -> Result<()> {
+        let meta = meta_with_id()?;
+        let table = ReaderTable::new();
+        // a commit lands between the first and second read of the meta page
+        let reads = AtomicU64::new(0);
+        let (latest, slot) = table.register(|| {
+            let n = reads.fetch_add(1, Ordering::Relaxed);
+            meta(if n == 0 { 1 } else { 2 })
+        });
+        assert_eq!(latest.tx_id(), 2);
+        assert_eq!(reads.load(Ordering::Relaxed), 3);
+        assert_eq!(table.oldest(), Some(2));
+        table.release(&slot);
+        Ok(())
+    }
+
+    #[test]
+    fn test_latest_meta() -> Result<()> {
+        let meta = meta_with_id()?;
+        let latest = Arc::new(LatestMeta::new());
+        latest.store(&meta(1));
+        assert_eq!(latest.load().tx_id(), 1);
+        assert_eq!(latest.load().hash, meta(1).hash);
+
+        // readers never see half of one meta and half of another
+        let writer = {
+            let latest = latest.clone();
+            std::thread::spawn(move || {
+                for tx_id in 2..10_000 {
+                    let mut meta = meta(tx_id);
+                    meta.set_num_pages(tx_id);
+                    latest.store(&meta);
+                }
+            })
+        };
+        let mut last = 0;
+        while last < 9_999 {
+            let meta = latest.load();
+            assert!(meta.tx_id() >= last);
+            if meta.tx_id() > 1 {
+                assert_eq!(meta.num_pages(), meta.tx_id());
+            }
+            last = meta.tx_id();
+        }
+        writer.join().unwrap();
+        Ok(())
+    }
+}
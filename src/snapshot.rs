@@ -0,0 +1,532 @@
+//! Read-only views of the database that can be shared between threads, see [`DB::snapshot`].
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+
+use crate::{
+    bucket::BucketMeta,
+    bytes::ToBytes,
+    cursor::{KVPairs, ToKVPairs},
+    data::{BucketName, Data, KVPair},
+    db::DB,
+    errors::{Error, Result},
+    meta::Meta,
+    node::Leaf,
+    page::{PageID, Pages},
+    page_node::PageNode,
+    readers::ReaderSlot,
+};
+
+/// A read-only view of the database that can be moved to, or shared between, other threads.
+///
+/// A snapshot sees the database exactly as it was when it was created by [`DB::snapshot`],
+/// just like a read-only [`Tx`](crate::Tx). Unlike a transaction, it doesn't borrow the [`DB`],
+/// so it can be stored in a struct or put in an [`Arc`](alloc::sync::Arc)
+/// and read from several threads at once.
+///
+/// Like any read-only transaction, a snapshot keeps the pages it can see from being reused,
+/// so the database file grows while it is kept around. Drop it as soon as you're done with it.
+///
+/// # Examples
+///
+/// ```no_run
+/// use jammdb::DB;
+/// # use jammdb::Error;
+///
+/// # fn main() -> Result<(), Error> {
+/// use std::sync::Arc;
+/// use jammdb::memfile::{FakeMap, FileOpenOptions};
+/// let db = DB::open::<FileOpenOptions,_>(Arc::new(FakeMap),"my.db")?;
+/// let snapshot = Arc::new(db.snapshot()?);
+///
+/// let workers: Vec<_> = (0..4)
+///     .map(|_| {
+///         let snapshot = snapshot.clone();
+///         std::thread::spawn(move || {
+///             let bucket = snapshot.get_bucket("my-bucket")?;
+///             Ok(bucket.kv_pairs().count())
+///         })
+///     })
+///     .collect();
+/// for worker in workers {
+///     let count: Result<usize, Error> = worker.join().unwrap();
+///     println!("{} keys", count?);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct Snapshot {
+    db: DB,
+    meta: Meta,
+    pages: Pages,
+    slot: ReaderSlot,
+}
+
+impl Snapshot {
+    pub(crate) fn new(db: &DB) -> Result<Snapshot> {
+        if db.inner.closed.load(Ordering::Acquire) {
+            return Err(Error::DatabaseClosed);
+        }
+        let (meta, slot) = db.inner.readers.register(|| db.inner.latest_meta.load());
+        let data = db.inner.data.lock().clone();
+        let pages = Pages::new(data, db.inner.pagesize).verify_checksums(meta.checksums());
+        Ok(Snapshot {
+            db: db.clone(),
+            meta,
+            pages,
+            slot,
+        })
+    }
+
+    /// Returns the ID of the transaction whose changes this snapshot sees.
+    pub fn tx_id(&self) -> u64 {
+        self.meta.tx_id()
+    }
+
+    /// Returns a reference to the root level bucket with the given name.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`BucketMissing`](enum.Error.html#variant.BucketMissing) error if the bucket does not exist,
+    /// or an [`IncompatibleValue`](enum.Error.html#variant.IncompatibleValue) error if the key exists but is not a bucket.
+    pub fn get_bucket<'s, T: ToBytes<'s>>(&'s self, name: T) -> Result<SnapshotBucket<'s>> {
+        self.root().get_bucket(name)
+    }
+
+    /// Iterator over the root level buckets
+    ///
+    /// Like a [`SnapshotCursor`], it stops early if a page can't be read.
+    pub fn buckets(&self) -> impl Iterator<Item = (BucketName<'_, '_>, SnapshotBucket<'_>)> {
+        self.root().buckets()
+    }
+
+    fn root(&self) -> SnapshotBucket<'_> {
+        SnapshotBucket {
+            snapshot: self,
+            meta: self.meta.root,
+        }
+    }
+
+    fn page_node(&self, id: PageID) -> Result<PageNode<'_>> {
+        Ok(PageNode::Page(self.pages.page(id)?))
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.db.inner.readers.release(&self.slot);
+    }
+}
+
+/// A bucket in a [`Snapshot`]
+///
+/// It has the same methods for reading data as a [`Bucket`](crate::Bucket).
+#[derive(Clone, Copy)]
+pub struct SnapshotBucket<'s> {
+    snapshot: &'s Snapshot,
+    meta: BucketMeta,
+}
+
+impl<'s> SnapshotBucket<'s> {
+    /// Gets the data for the given key.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`Corruption`](enum.Error.html#variant.Corruption) error
+    /// if a page on the way to the key fails its checksum.
+    pub fn get<T: AsRef<[u8]>>(&self, key: T) -> Result<Option<Data<'s, 's>>> {
+        let mut cursor = self.cursor();
+        match cursor.seek(key)? {
+            true => cursor.current(),
+            false => Ok(None),
+        }
+    }
+
+    /// Gets the key / value pair for the given key, if the key is not a bucket.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`Corruption`](enum.Error.html#variant.Corruption) error
+    /// if a page on the way to the key fails its checksum.
+    pub fn get_kv<T: AsRef<[u8]>>(&self, key: T) -> Result<Option<KVPair<'s, 's>>> {
+        match self.get(key)? {
+            Some(Data::KeyValue(kv)) => Ok(Some(kv)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Gets a nested bucket.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`BucketMissing`](enum.Error.html#variant.BucketMissing) error if the bucket does not exist,
+    /// or an [`IncompatibleValue`](enum.Error.html#variant.IncompatibleValue) error if the key exists but is not a bucket.
+    pub fn get_bucket<T: ToBytes<'s>>(&self, name: T) -> Result<SnapshotBucket<'s>> {
+        let name = name.to_bytes();
+        let mut cursor = self.cursor();
+        if !cursor.seek(&name)? {
+            return Err(Error::BucketMissing);
+        }
+        match cursor.leaf()? {
+            Some(Leaf::Bucket(_, meta)) => Ok(SnapshotBucket {
+                snapshot: self.snapshot,
+                meta,
+            }),
+            Some(Leaf::Kv(..)) => Err(Error::IncompatibleValue),
+            None => Err(Error::BucketMissing),
+        }
+    }
+
+    /// Returns the bucket's counter, see [`Bucket::next_int`](crate::Bucket::next_int).
+    pub fn next_int(&self) -> u64 {
+        self.meta.next_int()
+    }
+
+    /// Creates a cursor over the data in the bucket.
+    pub fn cursor(&self) -> SnapshotCursor<'s> {
+        SnapshotCursor {
+            bucket: *self,
+            stack: Vec::new(),
+            next_called: false,
+            error: None,
+        }
+    }
+
+    /// Iterator over the key / value pairs in the bucket.
+    pub fn kv_pairs(&self) -> KVPairs<SnapshotCursor<'s>> {
+        self.cursor().to_kv_pairs()
+    }
+
+    /// Iterator over the nested buckets.
+    ///
+    /// Like a [`SnapshotCursor`], it stops early if a page can't be read.
+    pub fn buckets(&self) -> impl Iterator<Item = (BucketName<'s, 's>, SnapshotBucket<'s>)> {
+        let snapshot = self.snapshot;
+        let mut cursor = self.cursor();
+        core::iter::from_fn(move || loop {
+            cursor.next()?;
+            let leaf = cursor.leaf();
+            if let Some(Leaf::Bucket(name, meta)) = cursor.stop_on_error(leaf) {
+                return Some((BucketName::new(name), SnapshotBucket { snapshot, meta }));
+            }
+        })
+    }
+}
+
+/// An iterator over a bucket in a [`Snapshot`]
+///
+/// Works the same way as a [`Cursor`](crate::Cursor), including stopping early
+/// and keeping the error if a page can't be read.
+pub struct SnapshotCursor<'s> {
+    bucket: SnapshotBucket<'s>,
+    // the pages on the way to the current element, with the index taken in each one
+    stack: Vec<(PageID, usize)>,
+    next_called: bool,
+    // the error that stopped the iterator, if any
+    error: Option<Error>,
+}
+
+impl<'s> SnapshotCursor<'s> {
+    /// Moves the cursor to the given key.
+    /// If the key does not exist, the cursor stops "just before"
+    /// where the key _would_ be.
+    ///
+    /// Returns whether or not the key exists in the bucket.
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`Corruption`](enum.Error.html#variant.Corruption) error
+    /// if a page on the way to the key fails its checksum.
+    pub fn seek<T: AsRef<[u8]>>(&mut self, key: T) -> Result<bool> {
+        self.next_called = false;
+        self.stack.clear();
+        let mut page_id = self.bucket.meta.root_page();
+        loop {
+            let page_node = match self.bucket.snapshot.page_node(page_id) {
+                Ok(page_node) => page_node,
+                Err(e) => {
+                    self.stack.clear();
+                    return Err(e);
+                }
+            };
+            let (index, exact) = page_node.index(key.as_ref());
+            self.stack.push((page_id, index));
+            if page_node.leaf() {
+                return Ok(exact);
+            }
+            page_id = page_node.index_page(index);
+            if page_id == 0 {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Returns the data at the cursor's current position.
+    /// You can use this to get data after doing a [`seek`](#method.seek).
+    pub fn current(&self) -> Result<Option<Data<'s, 's>>> {
+        Ok(self.leaf()?.map(|leaf| leaf.into()))
+    }
+
+    /// Moves the cursor to the next element and returns it,
+    /// like [`next`](#method.next) but returning any error instead of stopping.
+    pub fn try_next(&mut self) -> Result<Option<Data<'s, 's>>> {
+        if self.stack.is_empty() {
+            self.seek_first()?;
+        } else if self.next_called {
+            loop {
+                let (page_id, index) = self.stack.last_mut().unwrap();
+                let page_node = self.bucket.snapshot.page_node(*page_id)?;
+                if *index + 1 >= page_node.len() {
+                    if self.stack.len() == 1 {
+                        return Ok(None);
+                    }
+                    self.stack.pop();
+                    continue;
+                }
+                *index += 1;
+                break;
+            }
+            self.seek_first()?;
+        }
+        self.next_called = true;
+        self.current()
+    }
+
+    /// Returns the error that stopped the iterator early, if there was one.
+    pub fn error(&self) -> Option<&Error> {
+        self.error.as_ref()
+    }
+
+    fn leaf(&self) -> Result<Option<Leaf<'s>>> {
+        let Some((page_id, index)) = self.stack.last() else {
+            return Ok(None);
+        };
+        let page_node = self.bucket.snapshot.page_node(*page_id)?;
+        if !page_node.leaf() {
+            return Ok(None);
+        }
+        Ok(page_node.val(*index))
+    }
+
+    fn seek_first(&mut self) -> Result<()> {
+        if self.stack.is_empty() {
+            self.stack.push((self.bucket.meta.root_page(), 0));
+        }
+        loop {
+            let (page_id, index) = *self.stack.last().unwrap();
+            let page_node = self.bucket.snapshot.page_node(page_id)?;
+            if page_node.leaf() || page_node.len() == 0 {
+                return Ok(());
+            }
+            self.stack.push((page_node.index_page(index), 0));
+        }
+    }
+
+    // stops the iterator at the first error, keeping it for `error`
+    fn stop_on_error<T>(&mut self, result: Result<Option<T>>) -> Option<T> {
+        match result {
+            Ok(next) => next,
+            Err(e) => {
+                self.error = Some(e);
+                None
+            }
+        }
+    }
+}
+
+impl<'s> Iterator for SnapshotCursor<'s> {
+    type Item = Data<'s, 's>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.error.is_some() {
+            return None;
+        }
+        let next = self.try_next();
+        self.stop_on_error(next)
+    }
+}
+
+impl<'s> ToKVPairs<'s, 's> for SnapshotCursor<'s> {
+    fn to_kv_pairs(self) -> KVPairs<Self> {
+        KVPairs { i: self }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memfile::{FakeMap, FileOpenOptions};
+    use crate::testutil::RandomFile;
+    use crate::OpenOptions;
+    use alloc::sync::Arc;
+    use core2::io::SeekFrom;
+    use std::vec;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_snapshot() -> Result<()> {
+        assert_send_sync::<DB>();
+        assert_send_sync::<Snapshot>();
+        assert_send_sync::<SnapshotBucket>();
+        assert_send_sync::<SnapshotCursor>();
+
+        let random_file = RandomFile::new();
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file)?;
+        db.update(|tx| {
+            let b = tx.create_bucket("abc")?;
+            // enough keys to need branch pages
+            for i in 0..1000_u64 {
+                b.put(i.to_be_bytes(), i.to_string())?;
+            }
+            b.create_bucket("nested")?.put("key", "value")?;
+            tx.create_bucket("def")?;
+            Ok(())
+        })?;
+        let snapshot = db.snapshot()?;
+        assert_eq!(db.reader_count(), 1);
+        assert_eq!(db.oldest_reader_tx(), Some(snapshot.tx_id()));
+        db.update(|tx| {
+            tx.get_bucket("abc")?.put("new", "value")?;
+            tx.delete_bucket("def")
+        })?;
+
+        // the snapshot doesn't see later changes
+        let b = snapshot.get_bucket("abc")?;
+        assert!(b.get("new")?.is_none());
+        assert_eq!(b.get_kv(500_u64.to_be_bytes())?.unwrap().value(), b"500");
+        assert!(b.get_kv("nested")?.is_none());
+        assert_eq!(
+            b.get_bucket(1_u64.to_be_bytes()).err(),
+            Some(Error::IncompatibleValue)
+        );
+        assert_eq!(b.get_bucket("missing").err(), Some(Error::BucketMissing));
+        assert_eq!(
+            b.get_bucket("nested")?.get_kv("key")?.unwrap().value(),
+            b"value"
+        );
+        let names: Vec<_> = snapshot
+            .buckets()
+            .map(|(name, _)| name.name().to_vec())
+            .collect();
+        assert_eq!(names, vec![b"abc".to_vec(), b"def".to_vec()]);
+
+        // it iterates over the same data as a transaction at the same point would
+        let tx = db.tx(false)?;
+        let tx_bucket = tx.get_bucket("abc")?;
+        let expected: Vec<_> = tx_bucket
+            .cursor()
+            .filter(|data| data.key() != b"new")
+            .map(|data| data.key().to_vec())
+            .collect();
+        let keys: Vec<_> = b.cursor().map(|data| data.key().to_vec()).collect();
+        assert_eq!(keys, expected);
+        assert_eq!(b.kv_pairs().count(), 1000);
+        assert_eq!(b.buckets().count(), 1);
+
+        let mut cursor = b.cursor();
+        assert!(cursor.seek(998_u64.to_be_bytes())?);
+        assert_eq!(cursor.next().unwrap().key(), 998_u64.to_be_bytes());
+        assert_eq!(cursor.next().unwrap().key(), 999_u64.to_be_bytes());
+        assert_eq!(cursor.next().unwrap().key(), b"nested");
+        assert!(cursor.next().is_none());
+        drop(tx);
+
+        // closing the database doesn't take the data away from the snapshot
+        db.clone().close()?;
+        assert_eq!(db.snapshot().err(), Some(Error::DatabaseClosed));
+        assert_eq!(b.kv_pairs().count(), 1000);
+        drop(snapshot);
+        assert_eq!(db.reader_count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_threads() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file)?;
+        db.update(|tx| {
+            let b = tx.create_bucket("abc")?;
+            for i in 0..1000_u64 {
+                b.put(i.to_be_bytes(), i.to_string())?;
+            }
+            Ok(())
+        })?;
+        let snapshot = Arc::new(db.snapshot()?);
+        let workers: Vec<_> = (0..4_u64)
+            .map(|n| {
+                let snapshot = snapshot.clone();
+                std::thread::spawn(move || {
+                    let b = snapshot.get_bucket("abc").unwrap();
+                    for i in (n..1000).step_by(4) {
+                        let value = std::format!("{i}");
+                        assert_eq!(
+                            b.get_kv(i.to_be_bytes()).unwrap().unwrap().value(),
+                            value.as_bytes()
+                        );
+                    }
+                    b.kv_pairs().count()
+                })
+            })
+            .collect();
+        // writers carry on while the snapshot is read
+        let writer = {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                db.update(|tx| {
+                    tx.get_bucket("abc")?
+                        .delete(0_u64.to_be_bytes())
+                        .map(|_| ())
+                })
+            })
+        };
+        for worker in workers {
+            assert_eq!(worker.join().unwrap(), 1000);
+        }
+        writer.join().unwrap()?;
+        assert_eq!(snapshot.get_bucket("abc")?.kv_pairs().count(), 1000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_corrupt_page() -> Result<()> {
+        let random_file = RandomFile::new();
+        let page_id = {
+            let db = OpenOptions::new()
+                .pagesize(1024)
+                .open::<_, FileOpenOptions>(Arc::new(FakeMap), &random_file)?;
+            db.update(|tx| {
+                let b = tx.create_bucket("abc")?;
+                for i in 0..100_u64 {
+                    b.put(i.to_be_bytes(), i.to_string())?;
+                }
+                b.create_bucket("nested")?;
+                Ok(())
+            })?;
+            let tx = db.tx(false)?;
+            let page_id = tx.get_bucket("abc")?.inner.borrow().meta.root_page();
+            // flip a bit in the stored checksum of the bucket's root page
+            let mut file = db.file();
+            file.seek(SeekFrom::Start(page_id * 1024 + 12))?;
+            file.write_all(&[0xFF])?;
+            file.flush()?;
+            page_id
+        };
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file)?;
+        let snapshot = db.snapshot()?;
+        let b = snapshot.get_bucket("abc")?;
+        let corruption = Some(Error::Corruption { page_id });
+        assert_eq!(b.get(1_u64.to_be_bytes()).err(), corruption);
+        assert_eq!(b.get_kv(1_u64.to_be_bytes()).err(), corruption);
+        assert_eq!(b.get_bucket("nested").err(), corruption);
+        assert_eq!(b.cursor().seek(1_u64.to_be_bytes()).err(), corruption);
+        assert_eq!(b.cursor().try_next().err(), corruption);
+
+        // iterators stop at the error and keep it
+        let mut cursor = b.cursor();
+        assert_eq!(cursor.by_ref().count(), 0);
+        assert_eq!(cursor.error(), corruption.as_ref());
+        assert!(cursor.next().is_none());
+        assert_eq!(b.buckets().count(), 0);
+        Ok(())
+    }
+}
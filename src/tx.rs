@@ -2,27 +2,73 @@ use crate::fs::File;
 use alloc::rc::Rc;
 use alloc::vec::Vec;
 use alloc::{format, vec};
-use core::{cell::RefCell, marker::PhantomData};
+use core::{cell::RefCell, marker::PhantomData, sync::atomic::Ordering};
 use core2::io::SeekFrom;
 use hashbrown::HashSet;
-use spin::{MutexGuard, RwLockReadGuard};
+use spin::MutexGuard;
 
 use crate::{
-    bucket::{Bucket, BucketMeta, InnerBucket},
+    bucket::{Bucket, BucketMeta, BucketSnapshot, InnerBucket},
     bytes::ToBytes,
     cursor::ToBuckets,
-    db::{DB, MIN_ALLOC_SIZE},
+    db::{Durability, TxTimeout, DB, MIN_ALLOC_SIZE},
     errors::{Error, Result},
-    freelist::TxFreelist,
+    freelist::{Freelist, FreelistSnapshot, TxFreelist},
     meta::Meta,
     node::Node,
     page::{Page, PageID, Pages},
+    readers::ReaderSlot,
     BucketName,
 };
 
 pub(crate) enum TxLock<'tx> {
     Rw(MutexGuard<'tx, File>),
-    Ro(RwLockReadGuard<'tx, ()>),
+    Ro(ReaderSlot),
+}
+
+// How long to wait for the locks a transaction needs
+pub(crate) enum Wait {
+    Block,
+    Try,
+    Timeout(TxTimeout),
+}
+
+impl Wait {
+    fn acquire<T>(
+        &self,
+        mut try_lock: impl FnMut() -> Option<T>,
+        lock: impl FnOnce() -> T,
+    ) -> Result<T> {
+        match self {
+            Wait::Block => Ok(lock()),
+            Wait::Try => try_lock().ok_or(Error::WouldBlock),
+            Wait::Timeout(TxTimeout::Spins(spins)) => {
+                for _ in 0..*spins {
+                    if let Some(guard) = try_lock() {
+                        return Ok(guard);
+                    }
+                    core::hint::spin_loop();
+                }
+                try_lock().ok_or(Error::Timeout)
+            }
+            #[cfg(feature = "std")]
+            Wait::Timeout(TxTimeout::Duration(timeout)) => {
+                let deadline = match std::time::Instant::now().checked_add(*timeout) {
+                    Some(deadline) => deadline,
+                    None => return Ok(lock()),
+                };
+                loop {
+                    if let Some(guard) = try_lock() {
+                        return Ok(guard);
+                    }
+                    if std::time::Instant::now() >= deadline {
+                        return Err(Error::Timeout);
+                    }
+                    std::thread::yield_now();
+                }
+            }
+        }
+    }
 }
 
 impl<'tx> TxLock<'tx> {
@@ -50,6 +96,7 @@ impl<'tx> TxLock<'tx> {
 ///
 /// Trying to use the methods that require writable transactions from a read-only transaction will result in an error. If you make edits in a writable transaction,
 /// and you want to save them, you must call the [`commit`](#method.commit) method, otherwise when the transaction is dropped all your changes will be lost.
+/// To throw the changes away on purpose, call [`rollback`](#method.rollback).
 ///
 /// # Examples
 ///
@@ -80,9 +127,9 @@ impl<'tx> TxLock<'tx> {
 /// b2.put("new-key", "new-value")?;
 ///
 /// // the read-only transaction will not have this new key
-/// assert_eq!(b1.get("new-key"), None);
+/// assert_eq!(b1.get("new-key")?, None);
 /// // but it will be able to see data that already existed!
-/// assert!(b1.get("existing-key").is_some());
+/// assert!(b1.get("existing-key")?.is_some());
 ///
 /// # Ok(())
 /// # }
@@ -90,7 +137,8 @@ impl<'tx> TxLock<'tx> {
 ///
 ///
 /// <sup>1</sup> There can only be a single writeable transaction at a time, so trying to open
-/// two writable transactions on the same thread will deadlock.
+/// two writable transactions on the same thread will deadlock, or with the `std` feature,
+/// return an [`Error::Deadlock`](enum.Error.html#variant.Deadlock).
 ///
 /// <sup>2</sup> Keep in mind that long running read-only transactions will prevent the database from
 /// reclaiming old pages and your database may increase in disk size quickly if you're writing lots of data,
@@ -107,37 +155,57 @@ pub(crate) struct TxInner<'tx> {
     pub(crate) freelist: Rc<RefCell<TxFreelist>>,
     pages: Pages,
     num_freelist_pages: u64,
+    // set once the transaction is committed or rolled back on purpose
+    finished: bool,
 }
 
 impl<'tx> Tx<'tx> {
-    pub(crate) fn new(db: &'tx DB, writable: bool) -> Result<Tx<'tx>> {
-        let lock = match writable {
-            true => TxLock::Rw(db.inner.file.lock()),
-            false => TxLock::Ro(db.inner.mmap_lock.read()),
+    pub(crate) fn new(db: &'tx DB, writable: bool, wait: Wait) -> Result<Tx<'tx>> {
+        if writable && db.inner.read_only {
+            return Err(Error::ReadOnlyDB);
+        }
+        if writable {
+            db.inner.check_deadlock()?;
+        }
+        let file = match writable {
+            true => Some(wait.acquire(|| db.inner.file.try_lock(), || db.inner.file.lock())?),
+            // readers never wait, the mapping they start with stays valid until they're done
+            false => None,
         };
-        let mut freelist = db.inner.freelist.lock().clone();
-        let mut meta = db.inner.meta()?;
-        debug_assert!(meta.valid());
-        {
-            let mut open_ro_txs = db.inner.open_ro_txs.lock();
-            if writable {
-                meta.tx_id += 1;
-                if open_ro_txs.len() > 0 {
-                    freelist.release(open_ro_txs[0]);
-                } else {
-                    freelist.release(meta.tx_id);
+        if db.inner.closed.load(Ordering::Acquire) {
+            return Err(Error::DatabaseClosed);
+        }
+        let (lock, meta, freelist) = match file {
+            Some(file) => {
+                // only the writer needs its own copy of the freelist
+                let mut freelist = db.inner.freelist.lock().clone();
+                let mut meta = db.inner.latest_meta.load();
+                debug_assert!(meta.valid());
+                meta.set_tx_id(meta.tx_id() + 1);
+                // Without a meta sync, the last commit's meta page may not be on disk yet,
+                // so the pages it freed are still used by the meta page a crash would fall back to.
+                let mut oldest = match db.inner.durability {
+                    Durability::NoMetaSync => meta.tx_id() - 1,
+                    _ => meta.tx_id(),
+                };
+                if let Some(reader) = db.inner.readers.oldest() {
+                    oldest = oldest.min(reader);
                 }
-            } else {
-                open_ro_txs.push(meta.tx_id);
-                open_ro_txs.sort_unstable();
+                freelist.release(oldest);
+                (TxLock::Rw(file), meta, freelist)
             }
-        }
+            None => {
+                let (meta, slot) = db.inner.readers.register(|| db.inner.latest_meta.load());
+                debug_assert!(meta.valid());
+                (TxLock::Ro(slot), meta, Freelist::new())
+            }
+        };
         let freelist = Rc::new(RefCell::new(TxFreelist::new(meta.clone(), freelist)));
 
         let data = db.inner.data.lock().clone();
-        let pages = Pages::new(data, db.inner.pagesize);
+        let pages = Pages::new(data, db.inner.pagesize).verify_checksums(meta.checksums());
 
-        let num_freelist_pages = pages.page(meta.freelist_page).overflow + 1;
+        let num_freelist_pages = pages.page(meta.freelist_page())?.overflow() + 1;
         let root = InnerBucket::from_meta(meta.root, pages.clone());
         let root = Rc::new(RefCell::new(root));
         let inner = TxInner {
@@ -148,7 +216,12 @@ impl<'tx> Tx<'tx> {
             freelist,
             num_freelist_pages,
             pages,
+            finished: false,
         };
+        #[cfg(feature = "std")]
+        if writable {
+            *db.inner.writer.lock() = Some(std::thread::current().id());
+        }
         Ok(Tx {
             inner: RefCell::new(inner),
         })
@@ -254,15 +327,20 @@ impl<'tx> Tx<'tx> {
 
     /// Writes the changes made in the writeable transaction to the underlying file.
     ///
+    /// Whether the changes are durable once this returns depends on the database's
+    /// [`Durability`](crate::Durability). With anything but [`Durability::Full`](crate::Durability::Full),
+    /// use [`DB::sync`] to make sure they survive an operating system crash.
+    ///
     /// # Errors
     ///
-    /// Will return an [`IOError`](enum.Error.html#variant.IOError) error if there are any io errors while writing to disk,
+    /// Will return an [`Io`](enum.Error.html#variant.Io) error if there are any io errors while writing to disk,
     /// or a [`ReadOnlyTx`](enum.Error.html#variant.ReadOnlyTx) error if this is called on a read-only transaction.
     pub fn commit(self) -> Result<()> {
         if !self.writable() {
             return Err(Error::ReadOnlyTx);
         }
         let mut tx = self.inner.borrow_mut();
+        tx.finished = true;
         let freelist = tx.freelist.clone();
         let mut freelist = freelist.borrow_mut();
         let meta = {
@@ -274,38 +352,128 @@ impl<'tx> Tx<'tx> {
         tx.write_data(&mut freelist)
     }
 
+    /// Discards every change made in the transaction.
+    ///
+    /// This is the same as dropping the transaction, but makes it clear the changes
+    /// are thrown away on purpose, and they are not reported to the
+    /// [`on_uncommitted_tx`](struct.OpenOptions.html#method.on_uncommitted_tx) callback.
+    /// Rolling back a read-only transaction just closes it.
+    pub fn rollback(self) -> Result<()> {
+        self.inner.borrow_mut().finished = true;
+        Ok(())
+    }
+
+    /// Marks the current state of a writable transaction, so the changes made after it
+    /// can be undone with [`rollback_to`](struct.Savepoint.html#method.rollback_to)
+    /// without throwing away the rest of the transaction.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jammdb::DB;
+    /// # use jammdb::Error;
+    ///
+    /// # fn main() -> Result<(), Error> {
+    /// # use std::sync::Arc;
+    /// use jammdb::memfile::{FakeMap, FileOpenOptions};
+    /// let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), "my.db")?;
+    /// let tx = db.tx(true)?;
+    /// let bucket = tx.create_bucket("my-bucket")?;
+    /// bucket.put("kept", "value")?;
+    ///
+    /// let savepoint = tx.savepoint()?;
+    /// bucket.put("undone", "value")?;
+    /// savepoint.rollback_to();
+    ///
+    /// assert!(bucket.get("kept")?.is_some());
+    /// assert!(bucket.get("undone")?.is_none());
+    /// tx.commit()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Will return a [`ReadOnlyTx`](enum.Error.html#variant.ReadOnlyTx) error if this is called on a read-only transaction.
+    pub fn savepoint<'a>(&'a self) -> Result<Savepoint<'a, 'tx>> {
+        let tx = self.inner.borrow();
+        if !tx.lock.writable() {
+            return Err(Error::ReadOnlyTx);
+        }
+        let root = tx.root.borrow().snapshot();
+        let freelist = tx.freelist.borrow().snapshot();
+        Ok(Savepoint {
+            tx: self,
+            root,
+            freelist,
+        })
+    }
+
     pub(crate) fn check(&self) -> Result<()> {
         self.inner.borrow().check()
     }
 }
 
+/// A point in a writable transaction that it can be rolled back to.
+///
+/// Created by [`Tx::savepoint`](struct.Tx.html#method.savepoint).
+/// Rolling back restores every bucket that existed at the savepoint, along with any pages
+/// freed or allocated since then, but leaves the transaction open so it can still be committed.
+///
+/// Buckets created after the savepoint no longer exist once it is rolled back to,
+/// so using a [`Bucket`](struct.Bucket.html) you got for one of them will panic, just like a deleted bucket.
+/// Cursors should not be used across a rollback, get a new one from the bucket instead.
+pub struct Savepoint<'a, 'tx> {
+    tx: &'a Tx<'tx>,
+    root: BucketSnapshot<'tx>,
+    freelist: FreelistSnapshot,
+}
+
+impl<'a, 'tx> Savepoint<'a, 'tx> {
+    /// Undoes every change made in the transaction since the savepoint was taken.
+    ///
+    /// To be able to roll back to the same point again, take a new savepoint afterwards.
+    pub fn rollback_to(self) {
+        let tx = self.tx.inner.borrow();
+        tx.freelist.borrow_mut().restore(self.freelist);
+        tx.root.borrow_mut().restore(self.root);
+    }
+
+    /// Keeps the changes made since the savepoint was taken, and discards the savepoint.
+    ///
+    /// This is the same as dropping it.
+    pub fn release(self) {}
+}
+
 impl<'tx> TxInner<'tx> {
     fn write_data(&mut self, freelist: &mut TxFreelist) -> Result<()> {
         if let TxLock::Rw(file) = &mut self.lock {
             // Write the freelist to a new page
             {
-                freelist.free(self.meta.freelist_page, self.num_freelist_pages);
+                freelist.free(self.meta.freelist_page(), self.num_freelist_pages);
                 let freelist_size = freelist.inner.size();
                 let page = freelist.allocate(freelist_size);
-                self.meta.freelist_page = page.id;
+                self.meta.set_freelist_page(page.id());
                 let free_page_ids = freelist.inner.pages();
                 page.page_type = Page::TYPE_FREELIST;
-                page.count = free_page_ids.len() as u64;
-                page.freelist_mut()
-                    .copy_from_slice(free_page_ids.as_slice());
+                page.set_count(free_page_ids.len() as u64);
+                for (id, page_id) in page.freelist_mut().iter_mut().zip(free_page_ids) {
+                    id.set(page_id);
+                }
             }
 
             // Update our num_pages from the freelist now that we've allocated everything
-            self.meta.num_pages = freelist.meta.num_pages;
+            self.meta.set_num_pages(freelist.meta.num_pages());
 
             // Grow the file, if needed
-            let required_size = self.meta.num_pages * self.db.inner.pagesize;
+            let required_size = self.meta.num_pages() * self.db.inner.pagesize;
             let current_size = file.metadata()?.len();
             if current_size < required_size {
                 let size_diff = required_size - current_size;
                 let alloc_size = ((size_diff / MIN_ALLOC_SIZE) + 1) * MIN_ALLOC_SIZE;
                 let data = self.db.inner.resize(file, current_size + alloc_size)?;
-                self.pages = Pages::new(data, self.db.inner.pagesize);
+                self.pages = Pages::new(data, self.db.inner.pagesize)
+                    .verify_checksums(self.meta.checksums());
             }
 
             // write the data to the file
@@ -313,9 +481,19 @@ impl<'tx> TxInner<'tx> {
                 // freelist.pages is a BTreeMap so we're writing the pages in order to minmize
                 // the random seeks.
                 for (page_id, (ptr, size)) in freelist.pages.iter() {
+                    if self.meta.checksums() {
+                        #[allow(clippy::cast_ptr_alignment)]
+                        let page = unsafe { &mut *(ptr.as_ptr() as *mut Page) };
+                        let checksum = page.compute_checksum(*size as u64).ok_or_else(|| {
+                            Error::InvalidDB(format!("Page {page_id} overflows its buffer"))
+                        })?;
+                        page.set_checksum(checksum);
+                    }
                     let buf = unsafe { core::slice::from_raw_parts(ptr.as_ptr(), *size) };
                     file.seek(SeekFrom::Start(self.db.inner.pagesize * page_id))?;
                     file.write_all(buf)?;
+                    let num_pages = (*size as u64).div_ceil(self.db.inner.pagesize);
+                    self.pages.data.invalidate(*page_id, num_pages);
                 }
             }
         }
@@ -323,35 +501,51 @@ impl<'tx> TxInner<'tx> {
             self.check()?;
         }
         if let TxLock::Rw(file) = &mut self.lock {
+            let durability = self.db.inner.durability;
+            // The new pages have to be on disk before the meta page that points to them,
+            // otherwise a crash could leave a valid meta page pointing at garbage.
+            file.flush()?;
+            if durability != Durability::NoSync {
+                file.sync_all()?;
+            }
+
             // write meta page to file
-            {
+            let written = {
                 let mut buf = vec![0; self.db.inner.pagesize as usize];
 
                 #[allow(clippy::cast_ptr_alignment)]
                 let mut page = unsafe { &mut *(&mut buf[0] as *mut u8 as *mut Page) };
-                let meta_page_id = u64::from(self.meta.meta_page == 0);
-                page.id = meta_page_id;
+                let meta_page_id = u64::from(self.meta.meta_page() == 0);
+                page.set_id(meta_page_id);
                 page.page_type = Page::TYPE_META;
                 let m = page.meta_mut();
-                m.meta_page = meta_page_id as u32;
-                m.magic = self.meta.magic;
-                m.version = self.meta.version;
-                m.pagesize = self.meta.pagesize;
+                m.set_meta_page(meta_page_id as u32);
+                m.set_magic(self.meta.magic());
+                m.set_version(self.meta.version());
+                m.set_flags(self.meta.flags());
+                m.set_pagesize(self.meta.pagesize());
                 m.root = self.meta.root;
-                m.num_pages = self.meta.num_pages;
-                m.freelist_page = self.meta.freelist_page;
-                m.tx_id = self.meta.tx_id;
+                m.set_num_pages(self.meta.num_pages());
+                m.set_freelist_page(self.meta.freelist_page());
+                m.set_tx_id(self.meta.tx_id());
                 m.hash = m.hash_self();
+                let written = m.clone();
 
                 file.seek(SeekFrom::Start(self.db.inner.pagesize * meta_page_id))?;
                 file.write_all(buf.as_slice())?;
-            }
+                self.pages.data.invalidate(meta_page_id, 1);
+                written
+            };
 
             file.flush()?;
-            file.sync_all()?;
+            // With `NoMetaSync`, the sync before the next meta page is written makes this one durable
+            if durability == Durability::Full {
+                file.sync_all()?;
+            }
 
             let mut lock = self.db.inner.freelist.lock();
             *lock = freelist.inner.clone();
+            self.db.inner.latest_meta.store(&written);
             Ok(())
         } else {
             unreachable!()
@@ -359,126 +553,148 @@ impl<'tx> TxInner<'tx> {
     }
 
     fn check(&self) -> Result<()> {
-        let mut unused_pages: HashSet<PageID> = (2..self.meta.num_pages).collect();
-        let mut page_stack = Vec::new();
-        page_stack.push(self.meta.root.root_page);
-        page_stack.push(self.meta.freelist_page);
-        while !page_stack.is_empty() {
-            let page_id = page_stack.pop().unwrap();
-            // Make sure this page hasn't already been used
+        check_pages(&self.pages, &self.meta, |_, _| Ok(()))
+    }
+}
+
+// Walks every page reachable from the root bucket and the freelist, checking that each page is
+// used exactly once, that keys are sorted and that no page is left unreachable.
+// `visit` is called with the id of every page in use except for free pages.
+pub(crate) fn check_pages(
+    pages: &Pages,
+    meta: &Meta,
+    mut visit: impl FnMut(PageID, &Page) -> Result<()>,
+) -> Result<()> {
+    let mut unused_pages: HashSet<PageID> = (2..meta.num_pages()).collect();
+    let mut page_stack = Vec::new();
+    page_stack.push(meta.root.root_page());
+    page_stack.push(meta.freelist_page());
+    while !page_stack.is_empty() {
+        let page_id = page_stack.pop().unwrap();
+        // Make sure this page hasn't already been used
+        if !unused_pages.remove(&page_id) {
+            return Err(Error::InvalidDB(format!(
+                "Page {page_id} missing from unused_pages",
+            )));
+        }
+        let page = pages.page(page_id)?;
+        visit(page_id, page)?;
+        // Make sure none of the overflow pages have been used
+        for i in 0..page.overflow() {
+            let page_id = page_id + i + 1;
             if !unused_pages.remove(&page_id) {
                 return Err(Error::InvalidDB(format!(
-                    "Page {page_id} missing from unused_pages",
+                    "Overflow Page {page_id} from missing from unused_pages",
                 )));
             }
-            let page = self.pages.page(page_id);
-            // Make sure none of the overflow pages have been used
-            for i in 0..page.overflow {
-                let page_id = page_id + i + 1;
-                if !unused_pages.remove(&page_id) {
-                    return Err(Error::InvalidDB(format!(
-                        "Overflow Page {page_id} from missing from unused_pages",
-                    )));
-                }
-            }
-            // Check the page type and explore all possible pages
-            match page.page_type {
-                Page::TYPE_BRANCH => {
-                    let mut last: Option<&[u8]> = None;
-                    for b in page.branch_elements().iter() {
-                        // Make sure we visit every branch page
-                        page_stack.push(b.page);
-                        // and that the keys are in order
-                        if let Some(last) = last {
-                            if last >= b.key() {
-                                return Err(Error::InvalidDB(format!(
-                                    "Branch page {page_id} contains unsorted elements"
-                                )));
-                            }
+        }
+        // Check the page type and explore all possible pages
+        match page.page_type {
+            Page::TYPE_BRANCH => {
+                let mut last: Option<&[u8]> = None;
+                for b in page.branch_elements().iter() {
+                    // Make sure we visit every branch page
+                    page_stack.push(b.page());
+                    // and that the keys are in order
+                    if let Some(last) = last {
+                        if last >= b.key() {
+                            return Err(Error::InvalidDB(format!(
+                                "Branch page {page_id} contains unsorted elements"
+                            )));
                         }
-                        last = Some(b.key());
                     }
+                    last = Some(b.key());
                 }
-                Page::TYPE_LEAF => {
-                    let mut last: Option<&[u8]> = None;
-                    for (i, leaf) in page.leaf_elements().iter().enumerate() {
-                        match leaf.node_type {
-                            Node::TYPE_BUCKET => {
-                                let meta: BucketMeta = leaf.value().into();
-                                // Push all nested bucket pages onto the queue for exploration
-                                page_stack.push(meta.root_page);
-                            }
-                            // Ignore data nodes since they don't point to more pages
-                            Node::TYPE_DATA => (),
-                            // If somehow it isn't a bucket or data, that's really bad...
-                            _ => {
-                                return Err(Error::InvalidDB(format!(
-                                    "Page {} index {} has an invalid leaf node type {}",
-                                    page_id, i, leaf.node_type,
-                                )))
-                            }
+            }
+            Page::TYPE_LEAF => {
+                let mut last: Option<&[u8]> = None;
+                for (i, leaf) in page.leaf_elements().iter().enumerate() {
+                    match leaf.node_type {
+                        Node::TYPE_BUCKET => {
+                            let meta: BucketMeta = leaf.value().into();
+                            // Push all nested bucket pages onto the queue for exploration
+                            page_stack.push(meta.root_page());
                         }
-                        // Make sure all leaf elements are in order
-                        if let Some(last) = last {
-                            if last >= leaf.key() {
-                                // let keys: Vec<&[u8]> =
-                                //     page.leaf_elements().iter().map(|l| l.key()).collect();
-                                // let key = leaf.key();
-                                return Err(Error::InvalidDB(format!(
-                                    "Leaf page {page_id} contains unsorted elements"
-                                )));
-                            }
+                        // Ignore data nodes since they don't point to more pages
+                        Node::TYPE_DATA => (),
+                        // If somehow it isn't a bucket or data, that's really bad...
+                        _ => {
+                            return Err(Error::InvalidDB(format!(
+                                "Page {} index {} has an invalid leaf node type {}",
+                                page_id, i, leaf.node_type,
+                            )))
                         }
-                        last = Some(leaf.key());
-                    }
-                }
-                Page::TYPE_FREELIST => {
-                    // Make sure our metadata is pointing at the correct freelist page
-                    // and we didn't somehow find our way to another one.
-                    if page_id != self.meta.freelist_page {
-                        return Err(Error::InvalidDB(format!(
-                            "Found Invalid Freelist Page {page_id}"
-                        )));
                     }
-                    // "visit" all freelist pages (we don't actually care what data is in these pages)
-                    for page_id in page.freelist() {
-                        if !unused_pages.remove(page_id) {
+                    // Make sure all leaf elements are in order
+                    if let Some(last) = last {
+                        if last >= leaf.key() {
+                            // let keys: Vec<&[u8]> =
+                            //     page.leaf_elements().iter().map(|l| l.key()).collect();
+                            // let key = leaf.key();
                             return Err(Error::InvalidDB(format!(
-                                "Page {page_id} from freelist missing from unused_pages",
+                                "Leaf page {page_id} contains unsorted elements"
                             )));
                         }
                     }
+                    last = Some(leaf.key());
                 }
-                // There are no other valid page types, so getting here is really bad 😅
-                _ => {
+            }
+            Page::TYPE_FREELIST => {
+                // Make sure our metadata is pointing at the correct freelist page
+                // and we didn't somehow find our way to another one.
+                if page_id != meta.freelist_page() {
                     return Err(Error::InvalidDB(format!(
-                        "Invalid page type {} for page {}",
-                        page.page_type, page_id,
-                    )))
+                        "Found Invalid Freelist Page {page_id}"
+                    )));
+                }
+                // "visit" all freelist pages (we don't actually care what data is in these pages)
+                for page_id in page.freelist().iter().map(|id| id.get()) {
+                    if !unused_pages.remove(&page_id) {
+                        return Err(Error::InvalidDB(format!(
+                            "Page {page_id} from freelist missing from unused_pages",
+                        )));
+                    }
                 }
             }
+            // There are no other valid page types, so getting here is really bad 😅
+            _ => {
+                return Err(Error::InvalidDB(format!(
+                    "Invalid page type {} for page {}",
+                    page.page_type, page_id,
+                )))
+            }
         }
+    }
 
-        // Once we've explored all of the pages we can reach from the root bucket and freelist,
-        // If there are any pages left then we have an invalid database.
-        if !unused_pages.is_empty() {
-            return Err(Error::InvalidDB(format!(
-                "Unreachable pages {unused_pages:?}",
-            )));
-        }
-        Ok(())
+    // Once we've explored all of the pages we can reach from the root bucket and freelist,
+    // If there are any pages left then we have an invalid database.
+    if !unused_pages.is_empty() {
+        return Err(Error::InvalidDB(format!(
+            "Unreachable pages {unused_pages:?}",
+        )));
     }
+    Ok(())
 }
 
 impl<'tx> Drop for TxInner<'tx> {
     fn drop(&mut self) {
-        if !self.lock.writable() {
-            let mut open_txs = self.db.inner.open_ro_txs.lock();
-            let index = match open_txs.binary_search(&self.meta.tx_id) {
-                Ok(i) => i,
-                _ => return, // this shouldn't happen, but isn't the end of the world if it does
-            };
-            open_txs.remove(index);
+        if self.lock.writable() {
+            #[cfg(feature = "std")]
+            {
+                *self.db.inner.writer.lock() = None;
+            }
+            if let (false, Some(report)) = (self.finished, &self.db.inner.on_uncommitted_tx) {
+                // the root may still be borrowed if we're unwinding from a panic inside a bucket
+                let dirty = match self.root.try_borrow_mut() {
+                    Ok(mut root) => root.is_dirty(),
+                    Err(_) => true,
+                };
+                if dirty {
+                    report(self.meta.tx_id());
+                }
+            }
+        } else if let TxLock::Ro(slot) = &self.lock {
+            self.db.inner.readers.release(slot);
         }
     }
 }
@@ -514,6 +730,175 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_rollback() -> Result<()> {
+        let random_file = RandomFile::new();
+        let reported = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let db = {
+            let reported = reported.clone();
+            OpenOptions::new()
+                .on_uncommitted_tx(move |tx_id| reported.lock().unwrap().push(tx_id))
+                .open::<_, FileOpenOptions>(Arc::new(FakeMap), &random_file)?
+        };
+        {
+            let tx = db.tx(true)?;
+            tx.create_bucket("abc")?.put("key", "value")?;
+            tx.rollback()?;
+        }
+        {
+            let tx = db.tx(true)?;
+            assert_eq!(tx.get_bucket("abc").err(), Some(Error::BucketMissing));
+            tx.create_bucket("abc")?;
+            tx.commit()?;
+        }
+        // dropping a transaction without changes is fine
+        drop(db.tx(true)?);
+        db.tx(false)?.rollback()?;
+        assert_eq!(
+            db.update(|tx| {
+                tx.get_bucket("abc")?.put("key", "value")?;
+                tx.get_bucket("missing").map(|_| ())
+            }),
+            Err(Error::BucketMissing)
+        );
+        assert!(reported.lock().unwrap().is_empty());
+
+        let tx = db.tx(true)?;
+        let tx_id = tx.inner.borrow().meta.tx_id();
+        tx.get_bucket("abc")?.put("key", "value")?;
+        drop(tx);
+        assert_eq!(*reported.lock().unwrap(), vec![tx_id]);
+
+        let tx = db.tx(false)?;
+        assert!(tx.get_bucket("abc")?.get("key")?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_savepoint() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file)?;
+        {
+            let tx = db.tx(true)?;
+            let b = tx.create_bucket("abc")?;
+            for i in 0..100_u64 {
+                b.put(i.to_be_bytes(), "value")?;
+            }
+            b.create_bucket("nested")?.put("key", "value")?;
+            tx.commit()?;
+        }
+        {
+            let tx = db.tx(false)?;
+            assert_eq!(tx.savepoint().err(), Some(Error::ReadOnlyTx));
+        }
+        let tx = db.tx(true)?;
+        let b = tx.get_bucket("abc")?;
+        b.put("before", "value")?;
+        let (num_pages, free_pages) = {
+            let freelist = tx.inner.borrow().freelist.clone();
+            let freelist = freelist.borrow();
+            (freelist.meta.num_pages(), freelist.inner.pages())
+        };
+
+        let savepoint = tx.savepoint()?;
+        b.put("after", "value")?;
+        b.put(0_u64.to_be_bytes(), "changed")?;
+        for i in 50..100_u64 {
+            b.delete(i.to_be_bytes())?;
+        }
+        b.delete_bucket("nested")?;
+        let new_bucket = tx.create_bucket("def")?;
+        new_bucket.put("key", "value")?;
+        tx.inner
+            .borrow()
+            .freelist
+            .borrow_mut()
+            .allocate(size_of::<Page>() as u64);
+        savepoint.rollback_to();
+
+        {
+            let freelist = tx.inner.borrow().freelist.clone();
+            let freelist = freelist.borrow();
+            assert_eq!(freelist.meta.num_pages(), num_pages);
+            assert_eq!(freelist.inner.pages(), free_pages);
+            assert!(freelist.pages.is_empty());
+        }
+        // handles from before the savepoint still work
+        assert!(b.get("before")?.is_some());
+        assert!(b.get("after")?.is_none());
+        assert_eq!(b.get_kv(0_u64.to_be_bytes())?.unwrap().value(), b"value");
+        for i in 0..100_u64 {
+            assert!(b.get(i.to_be_bytes())?.is_some());
+        }
+        assert!(b.get_bucket("nested")?.get("key")?.is_some());
+        assert_eq!(tx.get_bucket("def").err(), Some(Error::BucketMissing));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _ = new_bucket.get("key");
+        }));
+        assert!(result.is_err());
+
+        // released savepoints keep their changes
+        let savepoint = tx.savepoint()?;
+        b.put("released", "value")?;
+        savepoint.release();
+        tx.commit()?;
+
+        let tx = db.tx(false)?;
+        tx.check()?;
+        let b = tx.get_bucket("abc")?;
+        assert!(b.get("before")?.is_some());
+        assert!(b.get("after")?.is_none());
+        assert!(b.get("released")?.is_some());
+        assert!(b.get_bucket("nested")?.get("key")?.is_some());
+        assert_eq!(b.kv_pairs().count(), 102);
+        assert_eq!(tx.get_bucket("def").err(), Some(Error::BucketMissing));
+        Ok(())
+    }
+
+    #[test]
+    fn test_savepoint_opened_buckets() -> Result<()> {
+        let random_file = RandomFile::new();
+        let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file)?;
+        db.update(|tx| {
+            let b = tx.create_bucket("x")?;
+            b.put("k", "value")?;
+            b.create_bucket("nested")?.put("key", "value")?;
+            Ok(())
+        })?;
+        let tx = db.tx(true)?;
+        let savepoint = tx.savepoint()?;
+        // buckets that already exist are only opened after the savepoint
+        let b = tx.get_bucket("x")?;
+        b.put("k", "changed")?;
+        b.put("after", "value")?;
+        let nested = b.get_bucket("nested")?;
+        nested.put("after", "value")?;
+        savepoint.rollback_to();
+
+        // they are back to how they were, and can still be used
+        assert_eq!(b.get_kv("k")?.unwrap().value(), b"value");
+        assert!(b.get("after")?.is_none());
+        assert_eq!(nested.get_kv("key")?.unwrap().value(), b"value");
+        assert!(nested.get("after")?.is_none());
+        b.put("k", "rolled back")?;
+        nested.put("key", "rolled back")?;
+        assert_eq!(
+            tx.get_bucket("x")?.get_kv("k")?.unwrap().value(),
+            b"rolled back"
+        );
+        tx.commit()?;
+
+        let tx = db.tx(false)?;
+        tx.check()?;
+        let b = tx.get_bucket("x")?;
+        assert_eq!(b.get_kv("k")?.unwrap().value(), b"rolled back");
+        assert!(b.get("after")?.is_none());
+        let nested = b.get_bucket("nested")?;
+        assert_eq!(nested.get_kv("key")?.unwrap().value(), b"rolled back");
+        assert!(nested.get("after")?.is_none());
+        Ok(())
+    }
+
     #[test]
     fn test_concurrent_txs() -> Result<()> {
         let random_file = RandomFile::new();
@@ -529,11 +914,8 @@ mod tests {
             let tx = tx.inner.borrow_mut();
             assert_eq!(tx.pages.data.len(), 1024 * 10);
             assert!(!tx.lock.writable());
-            {
-                let open_ro_txs = tx.db.inner.open_ro_txs.lock();
-                assert_eq!(open_ro_txs.len(), 1);
-                assert_eq!(open_ro_txs[0], tx.meta.tx_id);
-            }
+            assert_eq!(db.reader_count(), 1);
+            assert_eq!(db.oldest_reader_tx(), Some(tx.meta.tx_id()));
             {
                 // create a writable transaction while the read-only transaction is still open
                 let tx = db.tx(true)?;
@@ -541,7 +923,7 @@ mod tests {
                 {
                     {
                         let inner = tx.inner.borrow_mut();
-                        assert_eq!(inner.meta.tx_id, 1);
+                        assert_eq!(inner.meta.tx_id(), 1);
                         let freelist = inner.freelist.borrow();
                         assert_eq!(freelist.inner.pages(), vec![]);
                     }
@@ -558,7 +940,7 @@ mod tests {
                     {
                         let inner = tx.inner.borrow_mut();
                         let freelist = inner.freelist.borrow();
-                        assert_eq!(inner.meta.tx_id, 2);
+                        assert_eq!(inner.meta.tx_id(), 2);
                         assert_eq!(freelist.inner.pages(), vec![2, 3]);
                     }
                     let b = tx.get_bucket("abc")?;
@@ -576,33 +958,33 @@ mod tests {
             let mut freelist = inner.freelist.borrow_mut();
             assert_eq!(freelist.inner.pages(), vec![2, 3, 4, 5, 6]);
             // allocate some pages from the freelist
-            assert_eq!(freelist.meta.num_pages, 10);
+            assert_eq!(freelist.meta.num_pages(), 10);
             let page = freelist.allocate(size_of::<Page>() as u64);
-            assert!(page.id == 2);
-            assert!(page.overflow == 0);
+            assert!(page.id() == 2);
+            assert!(page.overflow() == 0);
 
             let page = freelist.allocate(size_of::<Page>() as u64);
-            assert!(page.id == 3);
-            assert!(page.overflow == 0);
+            assert!(page.id() == 3);
+            assert!(page.overflow() == 0);
 
             let page = freelist.allocate(size_of::<Page>() as u64);
-            assert!(page.id == 4);
-            assert!(page.overflow == 0);
+            assert!(page.id() == 4);
+            assert!(page.overflow() == 0);
 
             let page = freelist.allocate(size_of::<Page>() as u64);
-            assert!(page.id == 5);
-            assert!(page.overflow == 0);
+            assert!(page.id() == 5);
+            assert!(page.overflow() == 0);
 
             let page = freelist.allocate(size_of::<Page>() as u64);
-            assert!(page.id == 6);
-            assert!(page.overflow == 0);
+            assert!(page.id() == 6);
+            assert!(page.overflow() == 0);
 
             // freelist should be empty so make sure the page is new
-            assert_eq!(freelist.meta.num_pages, 10);
+            assert_eq!(freelist.meta.num_pages(), 10);
             let page = freelist.allocate(size_of::<Page>() as u64);
-            assert!(page.id == 10);
-            assert!(page.overflow == 0);
-            assert_eq!(freelist.meta.num_pages, 11);
+            assert!(page.id() == 10);
+            assert!(page.overflow() == 0);
+            assert_eq!(freelist.meta.num_pages(), 11);
             assert_eq!(freelist.inner.pages(), vec![]);
         }
         Ok(())
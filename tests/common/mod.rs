@@ -45,6 +45,9 @@ impl PathLike for RandomFile {
         let x = &self.path;
         x.exists()
     }
+    fn to_path_string(&self) -> String {
+        self.path.clone()
+    }
 }
 
 impl PathLike for &RandomFile {
@@ -52,9 +55,14 @@ impl PathLike for &RandomFile {
         let x = &self.path;
         x.exists()
     }
+    fn to_path_string(&self) -> String {
+        self.path.clone()
+    }
 }
 
 impl Drop for RandomFile {
     #[allow(unused_must_use)]
-    fn drop(&mut self) {}
+    fn drop(&mut self) {
+        jammdb::memfile::MemFs::global().remove(&self.path.as_str());
+    }
 }
@@ -289,7 +289,7 @@ impl Instructions {
             }
             let path = filename.as_ref().unwrap();
             println!("Recoding instructions to {}", path);
-            let file = FileOpenOptions::new().open(&path)?;
+            let file = FileOpenOptions::new().create(true).open(&path)?;
             Some(file)
         } else {
             None
@@ -0,0 +1,256 @@
+// Needs the `fault-injection` feature: cargo test --features fault-injection --test crash
+use core2::io::{Seek, SeekFrom};
+use jammdb::faulty::{Faults, FaultyFile, FaultyMap, WriteFault};
+use jammdb::memfile::{FakeMap, MemFs};
+use jammdb::{Durability, Error, File, OpenOptions, DB};
+use std::sync::Arc;
+
+const PAGESIZE: u64 = 1024;
+const DB_NAME: &str = "crash.db";
+
+type Contents = Vec<(Vec<u8>, Vec<u8>)>;
+
+// The committed state before the transaction that gets interrupted
+fn state_a(db: &DB) -> Result<(), Error> {
+    let tx = db.tx(true)?;
+    let b = tx.create_bucket("abc")?;
+    for i in 0..200_u64 {
+        b.put(i.to_be_bytes(), format!("a{i}"))?;
+    }
+    tx.commit()
+}
+
+// The transaction that gets interrupted, touching enough pages to need several writes
+fn state_b(db: &DB) -> Result<(), Error> {
+    let tx = db.tx(true)?;
+    let b = tx.get_bucket("abc")?;
+    for i in 0..400_u64 {
+        b.put(i.to_be_bytes(), format!("b{i}").repeat(10))?;
+    }
+    for i in 0..50_u64 {
+        b.delete(i.to_be_bytes())?;
+    }
+    tx.create_bucket("def")?.put("key", "value")?;
+    tx.commit()
+}
+
+// A commit after `state_b`, reusing the pages freed by it
+fn state_c(db: &DB) -> Result<(), Error> {
+    let tx = db.tx(true)?;
+    let b = tx.get_bucket("abc")?;
+    for i in 50..400_u64 {
+        b.put(i.to_be_bytes(), format!("c{i}").repeat(10))?;
+    }
+    tx.commit()
+}
+
+fn contents(db: &DB) -> Result<Contents, Error> {
+    let tx = db.tx(false)?;
+    let b = tx.get_bucket("abc")?;
+    let mut contents: Contents = b
+        .kv_pairs()
+        .map(|kv| (kv.key().to_vec(), kv.value().to_vec()))
+        .collect();
+    if let Ok(b) = tx.get_bucket("def") {
+        contents.extend(
+            b.kv_pairs()
+                .map(|kv| (kv.key().to_vec(), kv.value().to_vec())),
+        );
+    }
+    Ok(contents)
+}
+
+fn copy_fs(fs: &MemFs) -> Result<MemFs, Error> {
+    let images = MemFs::new();
+    let mut image = images.create(&"image")?;
+    fs.open(&DB_NAME)?.export(&mut image)?;
+    image.seek(SeekFrom::Start(0))?;
+    let copy = MemFs::new();
+    copy.import_file(&DB_NAME, &mut image)?;
+    Ok(copy)
+}
+
+struct Harness {
+    base: MemFs,
+    a: Contents,
+    b: Contents,
+}
+
+impl Harness {
+    fn new() -> Result<Harness, Error> {
+        let base = MemFs::new();
+        let db = OpenOptions::new()
+            .pagesize(PAGESIZE)
+            .num_pages(4)
+            .open_with(Arc::new(FakeMap), base.options(), DB_NAME)?;
+        state_a(&db)?;
+        let a = contents(&db)?;
+        drop(db);
+
+        let fs = copy_fs(&base)?;
+        let db = OpenOptions::new().pagesize(PAGESIZE).open_with(
+            Arc::new(FakeMap),
+            fs.options(),
+            DB_NAME,
+        )?;
+        state_b(&db)?;
+        let b = contents(&db)?;
+        assert_ne!(a, b);
+        Ok(Harness { base, a, b })
+    }
+
+    // Runs `run` against a copy of the database opened with `durability` and faults injected,
+    // "crashes" by dropping the database, then reopens it and checks it is still consistent.
+    // Returns what `run` returned, and the contents that survived.
+    fn crash_with(
+        &self,
+        durability: Durability,
+        run: impl FnOnce(&DB, &Faults) -> bool,
+    ) -> Result<(bool, Contents), Error> {
+        let fs = copy_fs(&self.base)?;
+        let faults = Faults::new();
+        let result = {
+            let file = File::new(Box::new(fs.open(&DB_NAME)?));
+            let file = File::new(Box::new(FaultyFile::new(file, faults.clone())));
+            let db = OpenOptions::new()
+                .pagesize(PAGESIZE)
+                .durability(durability)
+                .open_file(Arc::new(FaultyMap::new(Arc::new(FakeMap))), file)?;
+            run(&db, &faults)
+        };
+
+        let db = OpenOptions::new().pagesize(PAGESIZE).open_with(
+            Arc::new(FakeMap),
+            fs.options(),
+            DB_NAME,
+        )?;
+        db.check()?;
+        Ok((result, contents(&db)?))
+    }
+
+    // Runs the second transaction with `faults` injected and crashes.
+    // Returns whether the commit reported success, and whether the new state survived.
+    fn crash(&self, setup: impl FnOnce(&Faults)) -> Result<(bool, bool), Error> {
+        let (committed, contents) = self.crash_with(Durability::Full, |db, faults| {
+            setup(faults);
+            state_b(db).is_ok()
+        })?;
+        assert!(
+            contents == self.a || contents == self.b,
+            "database is in neither the old nor the new state"
+        );
+        Ok((committed, contents == self.b))
+    }
+}
+
+// bytes written and syncs made by the second transaction when nothing goes wrong
+fn total_written(harness: &Harness) -> Result<(u64, u64), Error> {
+    let mut counters = Faults::new();
+    let (committed, new_state) = harness.crash(|faults| counters = faults.clone())?;
+    assert!(committed && new_state);
+    Ok((counters.bytes_written(), counters.syncs()))
+}
+
+#[test]
+fn crash_during_writes() -> Result<(), Error> {
+    let harness = Harness::new()?;
+    let (total, _) = total_written(&harness)?;
+    assert!(total > 0);
+    let step = (total / 64).max(1);
+    for fault in [WriteFault::Fail, WriteFault::Drop, WriteFault::Tear] {
+        for budget in (0..total).step_by(step as usize).chain([total - 1]) {
+            let (committed, new_state) = harness.crash(|faults| {
+                faults.write_budget(budget, fault);
+            })?;
+            if fault == WriteFault::Fail {
+                assert!(!committed);
+            }
+            // the new state can only show up if the commit reported success
+            assert!(!new_state || committed);
+        }
+        // with enough budget the commit goes through
+        let (committed, new_state) = harness.crash(|faults| {
+            faults.write_budget(total, fault);
+        })?;
+        assert!(committed && new_state);
+    }
+    Ok(())
+}
+
+#[test]
+fn crash_with_unsynced_writes() -> Result<(), Error> {
+    let harness = Harness::new()?;
+    let (_, syncs) = total_written(&harness)?;
+    assert!(syncs > 0);
+    for seed in 0..16 {
+        for synced in 0..syncs {
+            let (committed, _) = harness.crash(|faults| {
+                faults.reorder_unsynced(seed).fail_sync_after(synced);
+            })?;
+            assert!(!committed);
+        }
+        // once every sync succeeds, the commit must survive the crash
+        let (committed, new_state) = harness.crash(|faults| {
+            faults.reorder_unsynced(seed);
+        })?;
+        assert!(committed && new_state);
+    }
+    Ok(())
+}
+
+#[test]
+fn crash_without_meta_sync() -> Result<(), Error> {
+    let harness = Harness::new()?;
+    let (_, c) = harness.crash_with(Durability::Full, |db, _| {
+        state_b(db).is_ok() && state_c(db).is_ok()
+    })?;
+    // a single sync per commit, before the meta page is written
+    let mut counters = Faults::new();
+    harness.crash_with(Durability::NoMetaSync, |db, faults| {
+        counters = faults.clone();
+        state_b(db).is_ok() && state_c(db).is_ok()
+    })?;
+    assert_eq!(counters.syncs(), 2);
+    for seed in 0..16 {
+        for synced in 0..=2 {
+            let (_, contents) = harness.crash_with(Durability::NoMetaSync, |db, faults| {
+                faults.reorder_unsynced(seed).fail_sync_after(synced);
+                state_b(db).is_ok() && state_c(db).is_ok()
+            })?;
+            // the last commit may be lost, but the database is never corrupted
+            assert!(contents == harness.a || contents == harness.b || contents == c);
+            if synced == 2 {
+                assert!(contents == harness.b || contents == c);
+            }
+        }
+        let (synced, contents) = harness.crash_with(Durability::NoMetaSync, |db, faults| {
+            faults.reorder_unsynced(seed);
+            state_b(db).is_ok() && state_c(db).is_ok() && db.sync().is_ok()
+        })?;
+        assert!(synced);
+        assert!(contents == c);
+    }
+    Ok(())
+}
+
+#[test]
+fn crash_without_sync() -> Result<(), Error> {
+    let harness = Harness::new()?;
+    let mut counters = Faults::new();
+    let (committed, contents) = harness.crash_with(Durability::NoSync, |db, faults| {
+        counters = faults.clone();
+        state_b(db).is_ok()
+    })?;
+    assert!(committed);
+    assert!(contents == harness.b);
+    assert_eq!(counters.syncs(), 0);
+    for seed in 0..16 {
+        let (synced, contents) = harness.crash_with(Durability::NoSync, |db, faults| {
+            faults.reorder_unsynced(seed);
+            state_b(db).is_ok() && db.sync().is_ok()
+        })?;
+        assert!(synced);
+        assert!(contents == harness.b);
+    }
+    Ok(())
+}
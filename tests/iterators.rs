@@ -195,7 +195,7 @@ fn check_cursor_starts(fruits: &Vec<&str>, b: &Bucket) {
 fn check_cursor(seek_to: &str, expected_fruits: &[&str], b: &Bucket, start_index: usize) {
     let mut cur_index = 0;
     let mut cursor = b.cursor();
-    let exists = cursor.seek(seek_to);
+    let exists = cursor.seek(seek_to).unwrap();
     if expected_fruits[0] == seek_to {
         assert!(exists);
     }
@@ -240,7 +240,7 @@ fn root_buckets() -> Result<(), Error> {
         let tx = db.tx(false)?;
         for (i, (data, bucket)) in tx.buckets().enumerate() {
             let name = std::str::from_utf8(data.name()).unwrap();
-            let kv = bucket.get_kv("data").unwrap();
+            let kv = bucket.get_kv("data")?.unwrap();
             let value = std::str::from_utf8(kv.value()).unwrap();
             if i == 0 {
                 assert_eq!(name, "abc");
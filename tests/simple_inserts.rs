@@ -72,7 +72,7 @@ fn test_insert(mut values: Vec<u64>) -> Result<(), Error> {
         check_data(&b, values.len() as u64, 1);
         assert_eq!(b.next_int(), values.len() as u64);
         let missing_key = (values.len() + 1) as u64;
-        assert!(b.get(missing_key.to_be_bytes()).is_none());
+        assert!(b.get(missing_key.to_be_bytes())?.is_none());
     }
     let db = DB::open::<FileOpenOptions, _>(Arc::new(FakeMap), &random_file.path)?;
     db.check()